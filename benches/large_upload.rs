@@ -0,0 +1,58 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Times the parsing of a body holding one large upload, and the search for its delimiter
+//! with `memchr`, as the parser searches, against the byte-by-byte search it made before.
+//! Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use hyper::header::Headers;
+use memchr::memmem;
+use mime_multipart::{read_multipart_body_with_options, Node, ReadOptions};
+
+const SIZE: usize = 32 * 1024 * 1024;
+const ITERATIONS: u32 = 5;
+
+fn body() -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::with_capacity(SIZE + 200);
+    body.extend(b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"large.bin\"\r\n\
+                  \r\n");
+    // Line terminators and dashes throughout, as in text, to give the search near misses
+    body.extend((0..SIZE).map(|i| b"abcdefghijklmnopqrstuvwxyz0123456789 -\r\n"[i % 40]));
+    body.extend(b"\r\n--AaB03x--\r\n");
+    body
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let body = body();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let options = ReadOptions { in_memory_only: true, ..ReadOptions::with_buffer_capacity(65536) };
+
+    let parse = time(|| {
+        let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+        assert!(matches!(nodes[0], Node::Part(ref part) if part.body.len() == SIZE));
+    });
+    let delimiter = b"\r\n--AaB03x";
+    let search = time(|| assert_eq!(memmem::find(&body, delimiter), Some(body.len() - 14)));
+    let naive = time(|| {
+        let at = body.windows(delimiter.len()).position(|w| w == delimiter);
+        assert_eq!(at, Some(body.len() - 14));
+    });
+    println!("{} byte upload: {:?} per parse", SIZE, parse);
+    println!("search for the delimiter: {:?} with memchr, {:?} byte by byte", search, naive);
+}
@@ -0,0 +1,57 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Times the parsing of a body of many small parts, with and without Content-Length
+//! headers, at several buffer capacities.  Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use hyper::header::Headers;
+use mime_multipart::{read_multipart_body_with_options, ReadOptions};
+
+const PARTS: usize = 5000;
+const ITERATIONS: u32 = 20;
+
+fn body(with_lengths: bool) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    for i in 0..PARTS {
+        let content = format!("value {} {}", i, "x".repeat(i % 200));
+        body.extend(b"--AaB03x\r\n");
+        body.extend(format!("Content-Disposition: form-data; name=\"field{}\"\r\n", i).bytes());
+        if with_lengths {
+            body.extend(format!("Content-Length: {}\r\n", content.len()).bytes());
+        }
+        body.extend(b"\r\n");
+        body.extend(content.bytes());
+        body.extend(b"\r\n");
+    }
+    body.extend(b"--AaB03x--\r\n");
+    body
+}
+
+fn time(body: &[u8], headers: &Headers, options: &ReadOptions) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let nodes = read_multipart_body_with_options(&mut &body[..], headers, options).unwrap();
+        assert_eq!(nodes.len(), PARTS);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    for &with_lengths in &[false, true] {
+        let body = body(with_lengths);
+        for &capacity in &[512, 4096, 65536] {
+            let options = ReadOptions::with_buffer_capacity(capacity);
+            println!("{} parts, {} bytes, Content-Length {}, buffer {}: {:?} per parse",
+                     PARTS, body.len(), if with_lengths { "given" } else { "absent" },
+                     capacity, time(&body, &headers, &options));
+        }
+    }
+}
@@ -0,0 +1,70 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Times the parsing of a body read from a file, where every read is a syscall, at
+//! several buffer capacities and through a `BufReader`.  With a capacity of one byte, each
+//! read is no larger than the delimiter being searched for.  Run with `cargo bench`.
+
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+
+use hyper::header::Headers;
+use mime_multipart::{read_multipart_body_with_options, ReadOptions};
+
+const PARTS: usize = 200;
+const ITERATIONS: u32 = 5;
+
+fn body() -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    for i in 0..PARTS {
+        body.extend(b"--AaB03x\r\n");
+        body.extend(format!("Content-Disposition: form-data; name=\"field{}\"\r\n", i).bytes());
+        body.extend(b"\r\n");
+        body.extend("x".repeat(i * 20).bytes());
+        body.extend(b"\r\n");
+    }
+    body.extend(b"--AaB03x--\r\n");
+    body
+}
+
+// Time `f`, parsing the body from the start of `file` each time.
+fn time<F: FnMut(&mut File)>(file: &mut File, mut f: F) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        file.seek(SeekFrom::Start(0)).unwrap();
+        f(file);
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let body = body();
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(&body).unwrap();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    for &capacity in &[1, 512, 4096, 65536] {
+        let options = ReadOptions::with_buffer_capacity(capacity);
+        let elapsed = time(&mut file, |file| {
+            let nodes = read_multipart_body_with_options(file, &headers, &options).unwrap();
+            assert_eq!(nodes.len(), PARTS);
+        });
+        println!("{} parts, {} bytes, buffer {}: {:?} per parse",
+                 PARTS, body.len(), capacity, elapsed);
+    }
+
+    let options = ReadOptions::default();
+    let elapsed = time(&mut file, |file| {
+        let mut reader = BufReader::new(file);
+        let nodes = read_multipart_body_with_options(&mut reader, &headers, &options).unwrap();
+        assert_eq!(nodes.len(), PARTS);
+    });
+    println!("{} parts, {} bytes, default buffer behind a BufReader: {:?} per parse",
+             PARTS, body.len(), elapsed);
+}
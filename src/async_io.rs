@@ -0,0 +1,273 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of `multipart/*` bodies from a tokio `AsyncRead`.  Enabled by the `tokio`
+//! feature.
+//!
+//! The body is parsed by a `Pusher`, as it is handed over, so it is read just as the
+//! blocking parsers read it; only the reading and the writing of parts is done here.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use hyper::header::Headers;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use super::hashing::HashingWriter;
+use super::push::{Event, Pusher};
+use super::{check_length, finish_part, get_multipart_boundary_from_headers, is_multipart,
+            streams_to_file, Error, FilePart, LimitKind, Node, ReadOptions, TeeWriter};
+
+/// Parse a MIME `multipart/*` body from an `AsyncRead`able stream into a `Vec` of `Node`s,
+/// as `read_multipart_body()` does for a blocking stream.  File parts are written to
+/// temporary files with `tokio::fs`.
+///
+/// The `boundary` is given as it appears in the Content-Type header, without the leading
+/// `--`.  The default `ReadOptions` are used, but for `always_use_files`.
+pub async fn read_multipart_body_async<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    boundary: &[u8],
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions { always_use_files, ..ReadOptions::default() };
+    read(reader, boundary, &options, None).await
+}
+
+/// As `read_multipart_body_async()`, but with every setting taken from `options`, as for
+/// `read_multipart_body_with_options()`.
+pub async fn read_multipart_body_async_with_options<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    boundary: &[u8],
+    options: &ReadOptions)
+    -> Result<Vec<Node>, Error>
+{
+    read(reader, boundary, options, None).await
+}
+
+/// As `read_multipart_body_async()`, but giving up with an `Error::Io` of kind
+/// `Interrupted` once `cancel` is set, as it might be when a request times out.  Any file
+/// parts already written, and the one being written, are deleted.
+///
+/// The flag is checked before and after each read from `reader`, so setting it does not
+/// interrupt a read which is waiting for a stalled client.  To abandon one of those, drop
+/// the future (as `tokio::time::timeout()` does), which cleans up in the same way.
+pub async fn read_multipart_body_async_cancellable<R: AsyncRead + Unpin + Send>(
+    reader: R,
+    boundary: &[u8],
+    always_use_files: bool,
+    cancel: Arc<AtomicBool>)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions { always_use_files, ..ReadOptions::default() };
+    read(reader, boundary, &options, Some(cancel)).await
+}
+
+async fn read<R: AsyncRead + Unpin + Send>(
+    mut reader: R,
+    boundary: &[u8],
+    options: &ReadOptions,
+    cancel: Option<Arc<AtomicBool>>)
+    -> Result<Vec<Node>, Error>
+{
+    let mut body = Level::new(Pusher::with_options(boundary, options), 0);
+    let mut context = Context { options, parts: 0 };
+    let mut chunk: Vec<u8> = vec![0; options.buffer_capacity().max(1)];
+    while ! body.done {
+        check_cancelled(&cancel)?;
+        let result = reader.read(&mut chunk).await;
+        check_cancelled(&cancel)?;
+        let read = result?;
+        if read == 0 {
+            break;
+        }
+        body.push(&chunk[..read], &mut context).await?;
+    }
+    body.finish()
+}
+
+fn check_cancelled(cancel: &Option<Arc<AtomicBool>>) -> io::Result<()> {
+    match *cancel {
+        Some(ref cancel) if cancel.load(Ordering::SeqCst) => {
+            Err(io::Error::new(io::ErrorKind::Interrupted, "parsing was cancelled"))
+        },
+        _ => Ok(()),
+    }
+}
+
+struct Context<'a> {
+    options: &'a ReadOptions,
+    parts: usize,
+}
+
+// A multipart being parsed, either the body itself or one nested within it
+struct Level {
+    pusher: Pusher,
+    depth: usize,
+    nodes: Vec<Node>,
+    // The part whose body is being read
+    part: Option<Current>,
+    // Whether the closing delimiter has been reached
+    done: bool,
+}
+
+enum Current {
+    Memory(Headers, Vec<u8>),
+    File(Box<FileBody>),
+    Multipart(Headers, Box<Level>),
+}
+
+// A part being written to a file, with the hash and the copy of it which `inner()` keeps
+// as it writes one
+struct FileBody {
+    file: tokio::fs::File,
+    filepart: FilePart,
+    size: usize,
+    copy: HashingWriter<TeeWriter<io::Sink>>,
+}
+
+impl FileBody {
+    async fn create(headers: Headers, options: &ReadOptions) -> Result<FileBody, Error> {
+        let filepart = FilePart::create_with_config(headers, &options.file_parts)?;
+        let file = tokio::fs::File::create(&filepart.path).await?;
+        let copy = HashingWriter::new(TeeWriter::new(io::sink(), options.tee_file_parts),
+                                      options);
+        Ok(FileBody { file, filepart, size: 0, copy })
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.write_all(data).await?;
+        self.copy.write_all(data)?;
+        self.size += data.len();
+        Ok(())
+    }
+
+    async fn finish(mut self, options: &ReadOptions) -> Result<FilePart, Error> {
+        self.file.flush().await?;
+        check_length(&self.filepart.headers, self.size, options)?;
+        let (tee, hash) = self.copy.into_parts();
+        self.filepart.size = Some(self.size);
+        self.filepart.hash = hash;
+        self.filepart.bytes = tee.bytes;
+        Ok(self.filepart)
+    }
+}
+
+impl Level {
+    fn new(pusher: Pusher, depth: usize) -> Level {
+        Level { pusher, depth, nodes: Vec::new(), part: None, done: false }
+    }
+
+    // Boxed, as it recurses for nested multiparts
+    fn push<'a>(&'a mut self, data: &'a [u8], context: &'a mut Context<'_>)
+                -> Pin<Box<dyn Future<Output = Result<(), Error>> + Send + 'a>>
+    {
+        Box::pin(async move {
+            for event in self.pusher.push(data)? {
+                match event {
+                    Event::PartStart(headers) => {
+                        let (depth, index) = (self.depth, self.nodes.len());
+                        self.part = Some(start_part(headers, depth, index, context).await?);
+                    },
+                    Event::PartData(data) => match self.part {
+                        Some(Current::Memory(ref headers, ref mut body)) => {
+                            if spills(body.len() + data.len(), context.options) {
+                                // Move the part to a file, as `SpillWriter` does
+                                let mut file = FileBody::create(headers.clone(),
+                                                                context.options).await?;
+                                file.write(body).await?;
+                                file.write(&data).await?;
+                                self.part = Some(Current::File(Box::new(file)));
+                                continue;
+                            }
+                            if body.len() + data.len() > context.options.limits.max_part_size {
+                                return Err(Error::LimitExceeded(LimitKind::PartSize));
+                            }
+                            body.extend(data);
+                        },
+                        Some(Current::File(ref mut file)) => file.write(&data).await?,
+                        Some(Current::Multipart(_, ref mut level)) => {
+                            level.push(&data, &mut *context).await?;
+                        },
+                        None => unreachable!("part data outside of a part"),
+                    },
+                    Event::PartEnd => {
+                        let node = match self.part.take() {
+                            Some(Current::Memory(headers, body)) => {
+                                Node::Part(finish_part(headers, body, self.nodes.len(),
+                                                       context.options)?)
+                            },
+                            Some(Current::File(file)) => {
+                                Node::File(file.finish(context.options).await?)
+                            },
+                            Some(Current::Multipart(headers, level)) => {
+                                Node::Multipart((headers, level.finish()?))
+                            },
+                            None => unreachable!("the end of a part outside of a part"),
+                        };
+                        self.nodes.push(node);
+                    },
+                    Event::Done => self.done = true,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Check that the whole multipart was read, returning its parts.  If the body ended
+    // within a nested multipart, it is that which is reported.
+    fn finish(mut self) -> Result<Vec<Node>, Error> {
+        if let Some(Current::Multipart(_, level)) = self.part.take() {
+            level.finish()?;
+        }
+        self.pusher.finish()?;
+        Ok(self.nodes)
+    }
+}
+
+// Decide from its headers where the `index`th part of a multipart at `depth` is to go, as
+// `inner()` does.
+async fn start_part(headers: Headers, depth: usize, index: usize, context: &mut Context<'_>)
+                    -> Result<Current, Error>
+{
+    let options = context.options;
+    context.parts += 1;
+    if context.parts > options.limits.max_parts {
+        return Err(Error::LimitExceeded(LimitKind::Parts));
+    }
+    if options.require_disposition && depth == 0 &&
+        headers.get_raw("Content-Disposition").is_none()
+    {
+        return Err(Error::MissingContentDisposition(index));
+    }
+
+    if is_multipart(&headers) {
+        if depth + 1 > options.limits.max_depth {
+            return Err(Error::LimitExceeded(LimitKind::Depth));
+        }
+        let boundary = get_multipart_boundary_from_headers(&headers)?;
+        let level = Level::new(Pusher::with_options(&boundary, options), depth + 1);
+        return Ok(Current::Multipart(headers, Box::new(level)));
+    }
+
+    if streams_to_file(&headers, options) {
+        Ok(Current::File(Box::new(FileBody::create(headers, options).await?)))
+    } else {
+        Ok(Current::Memory(headers, Vec::new()))
+    }
+}
+
+// Whether a part kept in memory is to be moved to a file once it is `size` bytes long
+fn spills(size: usize, options: &ReadOptions) -> bool {
+    match options.spill_threshold {
+        Some(threshold) => ! options.in_memory_only && size > threshold,
+        None => false,
+    }
+}
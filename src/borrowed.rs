@@ -0,0 +1,157 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of a `multipart/*` body which is already in memory, into parts which borrow
+//! their bodies from it rather than copying them.
+
+use hyper::header::{ContentType, Headers};
+use mime::Mime;
+
+use super::boundary::BoundaryFinder;
+use super::stream::find_bytes;
+use super::{get_multipart_boundary, is_multipart, parse_part_headers, truncated, Error,
+            LimitKind, Limits, Node, Part};
+
+/// A part parsed by `parse_multipart_borrowed()`, whose body is a slice of the buffer it
+/// was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BorrowedPart<'a> {
+    pub headers: Headers,
+    pub body: &'a [u8],
+}
+
+/// A node parsed by `parse_multipart_borrowed()`: a part, or a nested multipart
+/// containing more of them.  There are no file parts, as nothing is written to disk.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BorrowedNode<'a> {
+    /// A part, borrowing its body
+    Part(BorrowedPart<'a>),
+    /// A nested multipart, with its headers and its parts
+    Multipart((Headers, Vec<BorrowedNode<'a>>)),
+}
+
+impl<'a> BorrowedNode<'a> {
+    /// Copy into an owned `Node`, as `read_multipart_body()` would have returned with
+    /// every part kept in memory.
+    pub fn to_node(&self) -> Node {
+        match *self {
+            BorrowedNode::Part(ref part) => Node::Part(Part {
+                headers: part.headers.clone(),
+                body: part.body.to_vec(),
+            }),
+            BorrowedNode::Multipart((ref headers, ref nodes)) => {
+                Node::Multipart((headers.clone(), nodes.iter().map(|n| n.to_node()).collect()))
+            },
+        }
+    }
+}
+
+/// Parse `body`, a `multipart/*` body with the given Content-Type header value, without
+/// copying any part's body: each is a slice of `body`.  Bodies are as received, without
+/// transfer decoding.  The default `Limits` are applied.
+///
+/// Returns `Error::NotMultipart` if `content_type` is not a `multipart/*` type.
+pub fn parse_multipart_borrowed<'a>(content_type: &str, body: &'a [u8])
+                                    -> Result<Vec<BorrowedNode<'a>>, Error>
+{
+    let mime: Mime = content_type.trim().parse().map_err(|_| Error::NotMultipart)?;
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime));
+    let limits = Limits::default();
+    let mut parts = 0;
+    parse(body, &get_multipart_boundary(&headers)?, &limits, &mut parts, 0)
+}
+
+// Parse the parts of `body` delimited by `boundary` (including its leading `--`), as the
+// streaming parser does.
+fn parse<'a>(body: &'a [u8], boundary: &[u8], limits: &Limits, parts: &mut usize,
+             depth: usize) -> Result<Vec<BorrowedNode<'a>>, Error>
+{
+    let mut nodes: Vec<BorrowedNode<'a>> = Vec::new();
+
+    // Read past the initial boundary
+    let mut pos = match BoundaryFinder::with_delimiter(boundary).find(body) {
+        Some(found) => found.position + boundary.len(),
+        None => return Err(Error::EofBeforeFirstBoundary),
+    };
+
+    // Use their first line terminator to determine whether to use CRLF or LF.
+    let lt: &[u8] = if body[pos..].starts_with(b"--") {
+        return Ok(nodes);
+    } else if body[pos..].starts_with(b"\r\n") {
+        b"\r\n"
+    } else if body[pos..].starts_with(b"\n") {
+        b"\n"
+    } else {
+        return Err(Error::NoCrLfAfterBoundary);
+    };
+    let ltlt = [lt, lt].concat();
+    let lt_boundary = [lt, boundary].concat();
+    let delimiter = BoundaryFinder::with_delimiter(&lt_boundary);
+
+    loop {
+        // If the next two characters are '--', parsing is finished.
+        if body[pos..].starts_with(b"--") {
+            return Ok(nodes);
+        }
+
+        // Read the line terminator after the boundary.  If the body ends here, all that is
+        // missing is the closing delimiter.
+        match find_bytes(&body[pos..], lt) {
+            Some(at) => pos += at + lt.len(),
+            None if body[pos..].iter().all(|b| b" \t\r\n".contains(b)) => {
+                return Err(Error::MissingClosingBoundary);
+            },
+            None => return Err(Error::NoCrLfAfterBoundary),
+        }
+        if pos == body.len() {
+            return Err(Error::MissingClosingBoundary);
+        }
+
+        // Read the headers (which end in 2 line terminators)
+        let at = match find_bytes(&body[pos..], &ltlt) {
+            Some(at) if at > limits.max_header_size => {
+                return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+            },
+            Some(at) => at,
+            None if body.len() - pos > limits.max_header_size => {
+                return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+            },
+            None => return Err(Error::EofInPartHeaders),
+        };
+        let end = pos + at + ltlt.len();
+        let part_headers = parse_part_headers(&body[pos..end], limits.max_headers, false)?;
+        pos = end;
+
+        *parts += 1;
+        if *parts > limits.max_parts {
+            return Err(Error::LimitExceeded(LimitKind::Parts));
+        }
+
+        // Find the end of the part's body
+        let length = match delimiter.find(&body[pos..]) {
+            Some(found) => found.position,
+            None => return Err(truncated(&lt_boundary, body.len() - pos)),
+        };
+        let content = &body[pos..pos + length];
+        pos += length + lt_boundary.len();
+
+        if is_multipart(&part_headers) {
+            if depth + 1 > limits.max_depth {
+                return Err(Error::LimitExceeded(LimitKind::Depth));
+            }
+            let nested_boundary = get_multipart_boundary(&part_headers)?;
+            let inner_nodes = parse(content, &nested_boundary, limits, parts, depth + 1)?;
+            nodes.push(BorrowedNode::Multipart((part_headers, inner_nodes)));
+        } else {
+            if content.len() > limits.max_part_size {
+                return Err(Error::LimitExceeded(LimitKind::PartSize));
+            }
+            nodes.push(BorrowedNode::Part(BorrowedPart { headers: part_headers, body: content }));
+        }
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs::File;
+use std::io::Read;
+
+use memchr::memmem;
+
+use super::disposition::split_unquoted;
+use super::stream::find_bytes;
+use super::{check_headers, get_multipart_boundary_from_headers, Error, Node};
+
+/// Check that `boundary` (given without the leading `--`) is made up only of the
+/// characters RFC 2046 allows, and does not end in a space.  It must be 1 to 70
+/// characters long: RFC 2046 allows no longer, and some parsers reject longer ones.
+pub fn validate_boundary(boundary: &[u8]) -> Result<(), Error> {
+    if boundary.is_empty() || boundary.len() > 70 || boundary.last() == Some(&b' ') {
+        return Err(Error::InvalidBoundary);
+    }
+    let legal = |b: &u8| b.is_ascii_alphanumeric() || b"'()+_,-./:=? ".contains(b);
+    if !boundary.iter().all(legal) {
+        return Err(Error::InvalidBoundary);
+    }
+    Ok(())
+}
+
+// Find the boundary parameter in a raw Content-Type value.  Returns `None` if there is no
+// boundary parameter.
+pub(crate) fn boundary_param(content_type: &str) -> Option<Vec<u8>> {
+    param(content_type, "boundary")
+}
+
+// Find the parameter `key` (matched case-insensitively) in a raw Content-Type value.  A
+// quoted value has its quotes removed and any quoted-pairs (such as `\"`) unescaped.
+pub(crate) fn param(content_type: &str, key: &str) -> Option<Vec<u8>> {
+    let mut sections = split_unquoted(content_type, b';').into_iter().skip(1);
+    let value = sections.find_map(|section| {
+        let mut pieces = section.splitn(2, '=');
+        let name = pieces.next()?.trim();
+        if name.eq_ignore_ascii_case(key) { pieces.next() } else { None }
+    })?.trim();
+    let quoted = match value.strip_prefix('"') {
+        Some(quoted) => quoted,
+        None => return Some(value.as_bytes().to_vec()),
+    };
+    let mut unquoted = Vec::with_capacity(quoted.len());
+    let mut bytes = quoted.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' => break,
+            b'\\' => unquoted.extend(bytes.next()),
+            b => unquoted.push(b),
+        }
+    }
+    Some(unquoted)
+}
+
+/// Where `BoundaryFinder` found a delimiter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BoundaryMatch {
+    /// The offset of the delimiter's leading `--`: within the slice for
+    /// `BoundaryFinder::find()`, or from the start of everything pushed for
+    /// `BoundaryFinder::push()`.
+    pub position: usize,
+    /// Whether this is the closing delimiter, followed immediately by `--`.
+    pub closing: bool,
+}
+
+/// Finds the delimiters for a boundary, either in a slice or in data arriving in chunks
+/// of any size.  This is the search every parser in this crate makes.
+///
+/// A delimiter is the boundary with a leading `--`.  The line terminator which precedes
+/// it is part of the delimiter too (RFC 2046 section 5.1.1), but as its form is not known
+/// until the first delimiter line has been read, it is left for the caller to strip.
+#[derive(Clone, Debug)]
+pub struct BoundaryFinder {
+    delimiter: Vec<u8>,
+    finder: memmem::Finder<'static>,
+    // Pushed bytes which might still hold (the start of) a delimiter
+    held: Vec<u8>,
+    // The offset within everything pushed of the start of `held`
+    base: usize,
+}
+
+// What `BoundaryFinder::scan()` found in data which may continue past its end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scan {
+    // The delimiter starts at this offset
+    Found(usize),
+    // There is no delimiter, and this many bytes from the start cannot be part of one; the
+    // rest may be the start of one, depending on what follows
+    Clear(usize),
+}
+
+impl BoundaryFinder {
+    /// Create a finder for `boundary`, given without the leading `--`.
+    pub fn new(boundary: &[u8]) -> BoundaryFinder {
+        BoundaryFinder::with_delimiter(&[b"--", boundary].concat())
+    }
+
+    // A finder for `delimiter` exactly as given, such as a boundary with its leading `--`
+    // and the line terminator before that.
+    pub(crate) fn with_delimiter(delimiter: &[u8]) -> BoundaryFinder {
+        BoundaryFinder {
+            delimiter: delimiter.to_vec(),
+            finder: memmem::Finder::new(delimiter).into_owned(),
+            held: Vec::new(),
+            base: 0,
+        }
+    }
+
+    // The delimiter searched for.
+    pub(crate) fn delimiter(&self) -> &[u8] {
+        &self.delimiter
+    }
+
+    // Search `data`, which may be followed by more, for the delimiter: where it starts, or
+    // if it is not there how much cannot be the start of one split across the end of
+    // `data`, and so can be passed on.
+    pub(crate) fn scan(&self, data: &[u8]) -> Scan {
+        match self.finder.find(data) {
+            Some(at) => Scan::Found(at),
+            None => Scan::Clear(data.len() - partial_suffix(data, &self.delimiter)),
+        }
+    }
+
+    /// Find the first delimiter within `haystack`, which is taken to be complete.
+    pub fn find(&self, haystack: &[u8]) -> Option<BoundaryMatch> {
+        self.finder.find(haystack).map(|position| BoundaryMatch {
+            position,
+            closing: haystack[position + self.delimiter.len()..].starts_with(b"--"),
+        })
+    }
+
+    /// Feed the next chunk of data, returning the delimiters which can now be told apart,
+    /// in order.  A delimiter split across chunks is returned once the rest of it has been
+    /// pushed, and one at the end of a chunk once the two bytes after it which may make it
+    /// the closing delimiter have been.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<BoundaryMatch> {
+        self.held.extend(chunk);
+        let mut matches = Vec::new();
+        let mut from = 0;
+        loop {
+            let at = match self.scan(&self.held[from..]) {
+                Scan::Found(at) => from + at,
+                Scan::Clear(clear) => {
+                    from += clear;
+                    break;
+                },
+            };
+            let end = at + self.delimiter.len();
+            if self.held.len() < end + 2 {
+                // Wait to see whether it is the closing delimiter
+                from = at;
+                break;
+            }
+            matches.push(BoundaryMatch {
+                position: self.base + at,
+                closing: &self.held[end..end + 2] == b"--",
+            });
+            from = end;
+        }
+        self.held.drain(..from);
+        self.base += from;
+        matches
+    }
+
+    /// Declare that nothing more will be pushed, returning any delimiter at the very end
+    /// which `push()` was holding back.
+    pub fn finish(&mut self) -> Option<BoundaryMatch> {
+        let found = self.find(&self.held).map(|m| BoundaryMatch {
+            position: self.base + m.position,
+            closing: m.closing,
+        });
+        self.base += self.held.len();
+        self.held.clear();
+        found
+    }
+}
+
+// Check that none of the nodes (at any depth) contain a delimiter for `boundary` or
+// for any multipart enclosing them, nor a header with a line break in it, so that writing
+// them out cannot produce a body which parses differently.
+pub(crate) fn check_nodes(boundary: &[u8], nodes: &[Node]) -> Result<(), Error> {
+    validate_boundary(boundary)?;
+    let mut delimiter = b"--".to_vec();
+    delimiter.extend(boundary);
+    check_nodes_inner(nodes, &mut vec![delimiter])
+}
+
+fn check_nodes_inner(nodes: &[Node], delimiters: &mut Vec<Vec<u8>>) -> Result<(), Error> {
+    for node in nodes {
+        check_headers(node.headers())?;
+        match *node {
+            Node::Part(ref part) => {
+                if delimiters.iter().any(|d| contains(&part.body, d)) {
+                    return Err(Error::BoundaryCollision);
+                }
+            },
+            Node::File(ref filepart) => {
+                check_file(&mut File::open(&filepart.path)?, delimiters)?;
+            },
+            Node::Multipart((ref headers, ref subnodes)) => {
+                let boundary = get_multipart_boundary_from_headers(headers)?;
+                validate_boundary(&boundary)?;
+                let mut delimiter = b"--".to_vec();
+                delimiter.extend(boundary);
+                delimiters.push(delimiter);
+                check_nodes_inner(subnodes, delimiters)?;
+                delimiters.pop();
+            },
+        }
+    }
+    Ok(())
+}
+
+// Scan a file for any of the delimiters without reading it all into memory.
+pub(crate) fn check_file<R: Read>(file: &mut R, delimiters: &[Vec<u8>]) -> Result<(), Error> {
+    let overlap = delimiters.iter().map(|d| d.len()).max().unwrap_or(1) - 1;
+    let mut buf: Vec<u8> = vec![0; 8192 + overlap];
+    let mut len = 0;
+    loop {
+        let read = file.read(&mut buf[len..])?;
+        if read == 0 {
+            return Ok(());
+        }
+        len += read;
+        if delimiters.iter().any(|d| contains(&buf[..len], d)) {
+            return Err(Error::BoundaryCollision);
+        }
+        // Keep the tail, in case a delimiter straddles the next read
+        let keep = len.min(overlap);
+        buf.copy_within(len - keep..len, 0);
+        len = keep;
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    find_bytes(haystack, needle).is_some()
+}
+
+// The length of the longest suffix of `haystack` which is a proper prefix of `needle`.
+fn partial_suffix(haystack: &[u8], needle: &[u8]) -> usize {
+    let max = haystack.len().min(needle.len() - 1);
+    (1..=max).rev()
+        .find(|&n| haystack[haystack.len() - n..] == needle[..n])
+        .unwrap_or(0)
+}
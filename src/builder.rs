@@ -0,0 +1,163 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::io::Write;
+use std::path::Path;
+
+use hyper::header::{ContentType, Headers};
+use mime::Mime;
+
+use super::{write_multipart_generate, Error, FilePart, Node, Part};
+
+/// Builds the `Node`s of a `multipart/form-data` body from simple field values,
+/// filling in the Content-Disposition and Content-Type headers.
+///
+/// Field names and filenames are quoted, with any CR or LF in them percent-encoded (as
+/// browsers do), so that they cannot break out of the Content-Disposition header.
+#[derive(Debug, Default)]
+pub struct MultipartBuilder {
+    nodes: Vec<Node>,
+}
+
+impl MultipartBuilder {
+    pub fn new() -> MultipartBuilder {
+        MultipartBuilder { nodes: Vec::new() }
+    }
+
+    /// Add a text field.
+    pub fn add_text(mut self, name: &str, value: &str) -> MultipartBuilder {
+        self.nodes.push(Node::Part(Part {
+            headers: disposition_headers(name, None, None),
+            body: value.as_bytes().to_vec(),
+        }));
+        self
+    }
+
+    /// Add a file field, which is streamed from `path` when the body is written.  The
+    /// filename sent is the final component of `path`.
+    pub fn add_file(mut self, name: &str, path: &Path) -> MultipartBuilder {
+        let filename = path.file_name().map(|f| f.to_string_lossy().into_owned());
+        let content_type: Mime = "application/octet-stream".parse().unwrap();
+        let headers = disposition_headers(name, filename.as_deref(), Some(content_type));
+        self.nodes.push(Node::File(FilePart::new(headers, path)));
+        self
+    }
+
+    /// Add a file field from bytes in memory.
+    pub fn add_bytes(mut self, name: &str, filename: &str, content_type: Mime, data: Vec<u8>)
+                     -> MultipartBuilder
+    {
+        self.nodes.push(Node::Part(Part {
+            headers: disposition_headers(name, Some(filename), Some(content_type)),
+            body: data,
+        }));
+        self
+    }
+
+    /// The nodes built, ready to pass to `write_multipart()`.
+    pub fn build(self) -> Vec<Node> {
+        self.nodes
+    }
+
+    /// Write the body to `stream` using a newly generated boundary.  Returns the
+    /// boundary (for the caller's Content-Type header) and the number of bytes written.
+    pub fn write_to<W: Write>(self, stream: &mut W) -> Result<(Vec<u8>, usize), Error> {
+        write_multipart_generate(stream, &self.nodes)
+    }
+}
+
+// The content type of a file, guessed from its extension.  Files whose extension is
+// missing or not known are `application/octet-stream`.
+pub(crate) fn guess_content_type(path: &Path) -> Mime {
+    let extension = path.extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let content_type = match extension.as_str() {
+        "txt" => "text/plain",
+        "htm" | "html" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "js" => "application/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "webp" => "image/webp",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    };
+    content_type.parse().unwrap()
+}
+
+pub(crate) fn disposition_headers(name: &str, filename: Option<&str>, content_type: Option<Mime>)
+                                  -> Headers
+{
+    let mut value = format!("form-data; name={}", quoted_string(name));
+    if let Some(filename) = filename {
+        if filename.is_ascii() {
+            value.push_str(&format!("; filename={}", quoted_string(filename)));
+        } else {
+            // RFC 6266 section 4.3: an ASCII fallback for recipients which do not
+            // understand the extended parameter, followed by the extended parameter.
+            let fallback: String = filename.chars()
+                .map(|c| if c.is_ascii() { c } else { '_' })
+                .collect();
+            value.push_str(&format!("; filename={}; filename*=UTF-8''{}",
+                                    quoted_string(&fallback), percent_encode(filename)));
+        }
+    }
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![value.into_bytes()]);
+    if let Some(content_type) = content_type {
+        headers.set(ContentType(content_type));
+    }
+    headers
+}
+
+// Quote a parameter value, escaping backslashes and double quotes (RFC 2616 section 2.2).
+// Non-ASCII characters are passed through as UTF-8, as RFC 7578 section 5.1 recommends
+// for form field names.  CR and LF cannot be escaped in a quoted-string, so they are
+// percent-encoded as browsers do; otherwise a name could end the header early.
+fn quoted_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '\r' => output.push_str("%0D"),
+            '\n' => output.push_str("%0A"),
+            '"' | '\\' => {
+                output.push('\\');
+                output.push(c);
+            },
+            _ => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
+
+// Percent-encode a value for an RFC 5987 ext-value, leaving only attr-chars unencoded.
+fn percent_encode(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() * 3);
+    for &b in value.as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' |
+            b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => output.push(b as char),
+            _ => output.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    output
+}
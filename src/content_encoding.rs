@@ -0,0 +1,58 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Decompression of part bodies according to their `Content-Encoding` header.  Enabled by
+//! the `flate2` feature.
+//!
+//! This is not `Content-Transfer-Encoding`, which makes a body safe to transport (and is
+//! handled by `transfer_encoding`): a content coding compresses the content itself, and
+//! is undone after any transfer encoding.
+
+use std::io::{self, Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use hyper::header::Headers;
+
+use super::{Error, LimitKind};
+
+/// Decompress `body` according to the `Content-Encoding` header found in `headers`,
+/// undoing each listed coding in reverse order.  Bodies without the header, or with the
+/// `identity` coding, are returned unchanged.  Decompressing to more than `limit` bytes
+/// returns `Error::LimitExceeded`.
+pub(crate) fn decode(headers: &Headers, mut body: Vec<u8>, limit: usize)
+                     -> Result<Vec<u8>, Error>
+{
+    let codings = match headers.get_raw("Content-Encoding") {
+        Some(raw) if !raw.is_empty() => String::from_utf8_lossy(&raw[0]).to_lowercase(),
+        _ => return Ok(body),
+    };
+    for coding in codings.rsplit(',').map(str::trim) {
+        body = match coding {
+            "gzip" | "x-gzip" => inflate(GzDecoder::new(&body[..]), limit, coding)?,
+            // Meant to be zlib-wrapped (RFC 9110 section 8.4.1.2), though some clients send
+            // a raw deflate stream
+            "deflate" => match inflate(ZlibDecoder::new(&body[..]), limit, coding) {
+                Err(Error::Decoding(_)) => inflate(DeflateDecoder::new(&body[..]), limit, coding)?,
+                other => other?,
+            },
+            "identity" | "" => body,
+            _ => return Err(Error::Decoding(
+                format!("content-encoding {:?} is not supported", coding).into())),
+        };
+    }
+    Ok(body)
+}
+
+fn inflate<R: Read>(decoder: R, limit: usize, coding: &str) -> Result<Vec<u8>, Error> {
+    let mut output: Vec<u8> = Vec::new();
+    let read = decoder.take(limit as u64 + 1).read_to_end(&mut output)
+        .map_err(|e: io::Error| Error::Decoding(format!("bad {} body: {}", coding, e).into()))?;
+    if read > limit {
+        return Err(Error::LimitExceeded(LimitKind::PartSize));
+    }
+    Ok(output)
+}
@@ -0,0 +1,227 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of Content-Disposition header values (RFC 6266), working from the raw
+//! header rather than hyper's typed header so that quoting and the extended parameter
+//! syntax of RFC 5987 can be handled precisely.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use hyper::header::{Charset, Headers};
+
+use super::{charset_decode, Error};
+
+/// The type of a Content-Disposition header, which says how the part is to be handled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DispositionType {
+    /// `form-data`: a field of a submitted form (RFC 7578)
+    FormData,
+    /// `attachment`: to be saved rather than displayed (RFC 6266, RFC 2183)
+    Attachment,
+    /// `inline`: to be displayed as part of the message (RFC 2183)
+    Inline,
+    /// Any other type, lowercased
+    Other(String),
+}
+impl<'a> From<&'a str> for DispositionType {
+    fn from(token: &'a str) -> DispositionType {
+        // Disposition types are case-insensitive
+        match &*token.trim().to_lowercase() {
+            "form-data" => DispositionType::FormData,
+            "attachment" => DispositionType::Attachment,
+            "inline" => DispositionType::Inline,
+            other => DispositionType::Other(other.to_owned()),
+        }
+    }
+}
+
+// The disposition type of the first Content-Disposition header found in `headers`.
+pub(crate) fn disposition_type(headers: &Headers) -> Option<DispositionType> {
+    parse_headers(headers).map(|(disposition, _)| DispositionType::from(&*disposition))
+}
+
+/// A parsed Content-Disposition header, as returned by `Part::content_disposition()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContentDisposition {
+    /// The disposition type, such as `form-data` or `attachment`, as it was given
+    pub disposition: String,
+    /// The field name, from the `name*` parameter if there is one, otherwise `name`
+    pub name: Option<String>,
+    /// The filename, from the `filename*` parameter if there is one, otherwise `filename`
+    pub filename: Option<String>,
+    /// Every parameter (including those above) by its key exactly as it was given, with
+    /// quotes removed (and `\"` and `\\` unescaped within them) but extended values left
+    /// encoded.  Where a key is repeated, the first value is kept.
+    pub params: HashMap<String, String>,
+}
+impl ContentDisposition {
+    /// Parse the first Content-Disposition header in `headers`.  Returns
+    /// `Error::Hyper(hyper::Error::Header)` if there is none, and `Error::Decoding` if an
+    /// extended `filename*` parameter cannot be decoded.
+    pub fn from_headers(headers: &Headers) -> Result<ContentDisposition, Error> {
+        let (disposition, params) = parse_headers(headers)
+            .ok_or(Error::Hyper(::hyper::Error::Header))?;
+        let find = |key: &str| params.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.clone());
+        let name = find("name*").and_then(|v| decode_ext_value(&v).ok()).or_else(|| find("name"));
+        let filename = match find("filename*") {
+            Some(v) => Some(decode_ext_value(&v).map_err(Error::Decoding)?),
+            None => find("filename"),
+        };
+        let mut map: HashMap<String, String> = HashMap::with_capacity(params.len());
+        for (key, value) in params {
+            map.entry(key).or_insert(value);
+        }
+        Ok(ContentDisposition { disposition, name, filename, params: map })
+    }
+}
+
+// Split a Content-Disposition value into its disposition type and its parameters, in
+// the order they appear.  Semicolons within quoted values do not split parameters.
+pub(crate) fn parse(value: &str) -> (String, Vec<(String, String)>) {
+    let mut sections = split_unquoted(value, b';').into_iter();
+    let disposition = sections.next().unwrap_or("").trim().to_owned();
+    let params = sections.filter_map(|section| {
+        let mut pieces = section.splitn(2, '=');
+        let key = pieces.next()?.trim();
+        let value = pieces.next()?.trim();
+        if key.is_empty() {
+            return None;
+        }
+        Some((key.to_owned(), unquote(value)))
+    }).collect();
+    (disposition, params)
+}
+
+// Parse the first Content-Disposition header found in `headers`.
+pub(crate) fn parse_headers(headers: &Headers) -> Option<(String, Vec<(String, String)>)> {
+    let raw = headers.get_raw("Content-Disposition")?;
+    let first = raw.first()?;
+    Some(parse(&String::from_utf8_lossy(first)))
+}
+
+// The filename given in `headers`.  An extended `filename*` parameter takes precedence
+// over a plain `filename` parameter (RFC 6266 section 4.3).
+pub(crate) fn filename(headers: &Headers) -> Result<Option<String>, Error> {
+    let params = match parse_headers(headers) {
+        Some((_, params)) => params,
+        None => return Ok(None),
+    };
+    if let Some((_, value)) = params.iter().find(|(k, _)| k.eq_ignore_ascii_case("filename*")) {
+        return decode_ext_value(value).map(Some).map_err(Error::Decoding);
+    }
+    Ok(params.into_iter()
+       .find(|(k, _)| k.eq_ignore_ascii_case("filename"))
+       .map(|(_, v)| v))
+}
+
+// The field name given in `headers`.  An extended `name*` parameter takes precedence over
+// a plain `name` parameter, as for filenames.  Raw non-ASCII bytes in the header are read
+// as `charset`, the form's charset (or UTF-8 if it cannot hold them).
+pub(crate) fn name(headers: &Headers, charset: &Charset) -> Option<String> {
+    let raw = headers.get_raw("Content-Disposition")?.first()?;
+    let value = charset_decode(charset, raw)
+        .unwrap_or_else(|_| String::from_utf8_lossy(raw).into_owned());
+    let (_, params) = parse(&value);
+    if let Some((_, value)) = params.iter().find(|(k, _)| k.eq_ignore_ascii_case("name*")) {
+        if let Ok(name) = decode_ext_value(value) {
+            return Some(name);
+        }
+    }
+    params.into_iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("name"))
+        .map(|(_, v)| v)
+}
+
+// Decode an RFC 5987 ext-value: charset'language'percent-encoded-value
+pub(crate) fn decode_ext_value(value: &str) -> Result<String, Cow<'static, str>> {
+    let mut pieces = value.splitn(3, '\'');
+    let (charset, _language, encoded) = match (pieces.next(), pieces.next(), pieces.next()) {
+        (Some(c), Some(l), Some(e)) => (c, l, e),
+        _ => return Err("malformed extended parameter value".into()),
+    };
+    let charset = match Charset::from_str(charset) {
+        Ok(charset) => charset,
+        Err(_) => return Err(format!("unknown charset {:?}", charset).into()),
+    };
+    let bytes = percent_decode(encoded)?;
+    charset_decode(&charset, &bytes)
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, Cow<'static, str>> {
+    let bytes = value.as_bytes();
+    let mut output: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let octet = bytes.get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| ::std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            match octet {
+                Some(octet) => output.push(octet),
+                None => return Err(format!("invalid percent-encoding at byte {}", i).into()),
+            }
+            i += 3;
+        } else {
+            output.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(output)
+}
+
+// Split on `delimiter` wherever it is not within a quoted string.
+pub(crate) fn split_unquoted(value: &str, delimiter: u8) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, &b) in value.as_bytes().iter().enumerate() {
+        if escaped {
+            escaped = false;
+        } else if quoted && b == b'\\' {
+            escaped = true;
+        } else if b == b'"' {
+            quoted = !quoted;
+        } else if !quoted && b == delimiter {
+            pieces.push(&value[start..i]);
+            start = i + 1;
+        }
+    }
+    pieces.push(&value[start..]);
+    pieces
+}
+
+// Remove the surrounding quotes from a quoted parameter value, and unescape it.
+//
+// Within a quoted string a backslash escapes the character which follows it (RFC 2616
+// section 2.2), but some clients (older versions of Internet Explorer among them) send
+// the full path of a file with its backslashes unescaped, as `"C:\Users\me\file.txt"`.
+// So only `\"` and `\\` are unescaped, as those are all that a quoted string needs
+// escaped; a backslash before anything else is kept, taken to be a path separator.
+fn unquote(value: &str) -> String {
+    if !(value.len() >= 2 && value.starts_with('"') && value.ends_with('"')) {
+        return value.to_owned();
+    }
+    let inner = &value[1..value.len() - 1];
+    let mut unquoted = String::with_capacity(inner.len());
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(&next)) if next == '"' || next == '\\' => {
+                unquoted.push(next);
+                chars.next();
+            },
+            _ => unquoted.push(c),
+        }
+    }
+    unquoted
+}
@@ -14,7 +14,24 @@ use std::string::FromUtf8Error;
 use httparse;
 use hyper;
 
+use super::LimitKind;
+
 /// An error type for the `mime-multipart` crate.
+///
+/// New variants may be added in minor releases, so a `match` on an `Error` must end with
+/// a catch-all arm:
+///
+/// ```
+/// # use mime_multipart::Error;
+/// fn is_client_error(err: &Error) -> bool {
+///     match *err {
+///         Error::Io(_) => false,
+///         _ => true,
+///     }
+/// }
+/// # assert!(is_client_error(&Error::NotMultipart));
+/// ```
+#[non_exhaustive]
 pub enum Error {
     /// The Hyper request did not have a Content-Type header.
     NoRequestContentType,
@@ -25,11 +42,40 @@ pub enum Error {
     /// A multipart section contained only partial headers.
     PartialHeaders,
     EofInMainHeaders,
+    /// The body ended before its first delimiter, as an empty body or one which has
+    /// already been read does.
     EofBeforeFirstBoundary,
     NoCrLfAfterBoundary,
     EofInPartHeaders,
     EofInFile,
     EofInPart,
+    /// The body ended within a part, before the delimiter closing it was found.  This is
+    /// returned by the parser in place of `EofInFile` and `EofInPart`.
+    Truncated {
+        /// The delimiter which was expected, including the line terminator preceding it.
+        expected_boundary: Vec<u8>,
+        /// How many bytes of the part's body were read before the body ended.  A part within
+        /// a nested multipart ends at the delimiter of any multipart enclosing it, as that
+        /// part does, so this counts only the bytes preceding that delimiter.
+        bytes_read: usize,
+    },
+    /// The body ended just after a delimiter, without the closing delimiter
+    /// (`--boundary--`).  Unlike `Truncated`, every part was complete: the client only
+    /// failed to close the body.
+    MissingClosingBoundary,
+    /// A part's body was not the length its Content-Length header declared.  This is only
+    /// checked if `ReadOptions::check_part_lengths` is set.
+    LengthMismatch {
+        /// The length given by the Content-Length header
+        declared: u64,
+        /// The length of the body which was read
+        actual: u64,
+    },
+    /// The part at this index among the parts of the body had no Content-Disposition
+    /// header.  This is only checked if `ReadOptions::require_disposition` is set.
+    MissingContentDisposition(usize),
+    /// A form had more than one field of this name, where only one was expected.
+    DuplicateField(String),
     /// An HTTP parsing error from a multipart section.
     Httparse(httparse::Error),
     /// An I/O error.
@@ -40,6 +86,15 @@ pub enum Error {
     Utf8(FromUtf8Error),
     /// An error occurred during character decoding
     Decoding(Cow<'static, str>),
+    /// One of the parser `Limits` was exceeded.
+    LimitExceeded(LimitKind),
+    /// A boundary contained characters which RFC 2046 does not allow.
+    InvalidBoundary,
+    /// A part to be written contained the boundary of a multipart enclosing it.
+    BoundaryCollision,
+    /// A header to be written, of this name, had a CR or LF in its name or value, which
+    /// would end the header early and let what follows be read as further headers.
+    InvalidHeaderValue(String),
 }
 
 impl From<io::Error> for Error {
@@ -66,6 +121,18 @@ impl From<FromUtf8Error> for Error {
     }
 }
 
+/// For `?` in code returning `io::Result`.  `Error::Io` gives back the error it holds, with
+/// its original kind; every other error becomes one of kind `InvalidData`, with the `Error`
+/// as its inner error.
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -79,7 +146,22 @@ impl Display for Error {
                 format!("Utf8: {}", e).fmt(f),
             Error::Decoding(ref e) =>
                 format!("Decoding: {}", e).fmt(f),
-            _ => format!("{}", self).fmt(f),
+            Error::LimitExceeded(ref kind) =>
+                format!("LimitExceeded: {}", kind).fmt(f),
+            Error::Truncated { ref expected_boundary, bytes_read } =>
+                format!("Truncated: the body ended after {} bytes of a part, expecting {:?}",
+                        bytes_read, String::from_utf8_lossy(expected_boundary)).fmt(f),
+            Error::LengthMismatch { declared, actual } =>
+                format!("LengthMismatch: a part declared {} bytes, but had {}",
+                        declared, actual).fmt(f),
+            Error::MissingContentDisposition(index) =>
+                format!("MissingContentDisposition: part {} had no Content-Disposition header",
+                        index).fmt(f),
+            Error::DuplicateField(ref name) =>
+                format!("DuplicateField: {:?}", name).fmt(f),
+            Error::InvalidHeaderValue(ref name) =>
+                format!("InvalidHeaderValue: {:?}", name).fmt(f),
+            _ => f.write_str(self.description_str()),
         }
     }
 }
@@ -94,8 +176,8 @@ impl fmt::Debug for Error {
     }
 }
 
-impl StdError for Error {
-    fn description(&self) -> &str{
+impl Error {
+    fn description_str(&self) -> &'static str {
         match *self {
             Error::NoRequestContentType => "The Hyper request did not have a Content-Type header.",
             Error::NotMultipart =>
@@ -116,12 +198,44 @@ impl StdError for Error {
                 "The request body ended prematurely while streaming a file part.",
             Error::EofInPart =>
                 "The request body ended prematurely while reading a multipart part.",
+            Error::Truncated { .. } =>
+                "The request body ended before the delimiter closing a part.",
+            Error::MissingClosingBoundary =>
+                "The request body ended without the closing delimiter.",
+            Error::LengthMismatch { .. } =>
+                "A part's length differed from its Content-Length header.",
+            Error::MissingContentDisposition(_) =>
+                "A part had no Content-Disposition header.",
+            Error::DuplicateField(_) => "A form field was given more than once.",
             Error::Httparse(_) =>
                 "A parse error occurred while parsing the headers of a multipart section.",
             Error::Io(_) => "An I/O error occurred.",
             Error::Hyper(_) => "A Hyper error occurred.",
             Error::Utf8(_) => "A UTF-8 error occurred.",
             Error::Decoding(_) => "A decoding error occurred.",
+            Error::LimitExceeded(_) => "A parser limit was exceeded.",
+            Error::InvalidBoundary =>
+                "The boundary contained characters not allowed by RFC 2046.",
+            Error::BoundaryCollision =>
+                "A part contained the boundary of a multipart enclosing it.",
+            Error::InvalidHeaderValue(_) =>
+                "A header to be written contained a CR or LF.",
+        }
+    }
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        self.description_str()
+    }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Error::Httparse(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::Hyper(ref e) => Some(e),
+            Error::Utf8(ref e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,149 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Access to the parts of a parsed `multipart/form-data` body by field name.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use hyper::header::{Charset, Headers};
+use mime::Mime;
+
+use super::{get_content_disposition_name, Error, FilePart, Node};
+
+/// Group `nodes` by the `name` parameter of their Content-Disposition header.
+///
+/// A form may send several parts with the same name, so each name maps to all of its
+/// nodes, in the order they appeared in the body (which is the order the parser returns
+/// them in).  Nodes with no name are grouped under the empty string.
+pub fn into_field_map(nodes: Vec<Node>) -> HashMap<String, Vec<Node>> {
+    let mut map: HashMap<String, Vec<Node>> = HashMap::new();
+    for node in nodes {
+        let name = get_content_disposition_name(node.headers()).unwrap_or_default();
+        map.entry(name).or_default().push(node);
+    }
+    map
+}
+
+/// A form field, as returned by `into_fields()`: the part's metadata alongside its content.
+#[derive(Debug, PartialEq)]
+pub struct Field {
+    /// The field name, from the Content-Disposition `name` parameter
+    pub name: Option<String>,
+    /// The filename, from the Content-Disposition `filename*` or `filename` parameter
+    pub filename: Option<String>,
+    /// The Content-Type of the part
+    pub content_type: Option<Mime>,
+    /// All of the part's headers
+    pub headers: Headers,
+    /// The content of the part
+    pub data: FieldData,
+}
+
+/// Where the content of a `Field` is.
+#[derive(Debug, PartialEq)]
+pub enum FieldData {
+    /// In memory
+    Bytes(Vec<u8>),
+    /// In a file, which is deleted when the `FilePart` drops as usual
+    File(FilePart),
+}
+
+/// Flatten `nodes` into a list of fields, each with its name, filename and content type
+/// already extracted, as other multipart crates present a form.
+///
+/// Nested multiparts are flattened into the list in place.  Their parts often have no
+/// name of their own, as where a form sends several files for one field as a
+/// `multipart/mixed` part (RFC 2388 section 4.5), so these take the name of the nearest
+/// enclosing multipart which has one.  A filename which cannot be decoded is left out.
+pub fn into_fields(nodes: Vec<Node>) -> Vec<Field> {
+    let mut fields: Vec<Field> = Vec::with_capacity(nodes.len());
+    flatten(nodes, None, &mut fields);
+    fields
+}
+
+fn flatten(nodes: Vec<Node>, enclosing: Option<&str>, fields: &mut Vec<Field>) {
+    for node in nodes {
+        let name = get_content_disposition_name(node.headers())
+            .or_else(|| enclosing.map(|name| name.to_owned()));
+        match node {
+            Node::Part(part) => fields.push(Field {
+                name,
+                filename: part.filename().ok().flatten(),
+                content_type: part.content_type(),
+                headers: part.headers,
+                data: FieldData::Bytes(part.body),
+            }),
+            Node::File(filepart) => fields.push(Field {
+                name,
+                filename: filepart.filename().ok().flatten(),
+                content_type: filepart.content_type(),
+                headers: filepart.headers.clone(),
+                data: FieldData::File(filepart),
+            }),
+            Node::Multipart((_, subnodes)) => flatten(subnodes, name.as_deref(), fields),
+        }
+    }
+}
+
+/// The charset of a form, as given by the value of its `_charset_` field.
+///
+/// A browser fills in a hidden field named `_charset_` with the charset it encoded the
+/// form in (HTML section 4.10.22.7), which is also the charset of any raw non-ASCII field
+/// names, and of the fields' values; pass it to `Part::name_with_charset()` and
+/// `Part::text_with_default_charset()`.  Returns `None` if there is no such field, or its
+/// value is not a charset name.
+pub fn form_charset(nodes: &[Node]) -> Option<Charset> {
+    nodes.iter().find_map(|node| match *node {
+        Node::Part(ref part) if part.name().as_deref() == Some("_charset_") => {
+            let value = ::std::str::from_utf8(&part.body).ok()?.trim();
+            if value.is_empty() { None } else { Charset::from_str(value).ok() }
+        },
+        _ => None,
+    })
+}
+
+/// What `text_fields_with()` does with a field name given more than once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Keep the value of the last field of that name
+    LastWins,
+    /// Return `Error::DuplicateField`
+    Error,
+}
+
+/// Collect the text fields of a form into a map from field name to value, keeping the last
+/// value of any name given more than once.  See `text_fields_with()`.
+pub fn text_fields(nodes: &[Node]) -> Result<HashMap<String, String>, Error> {
+    text_fields_with(nodes, Duplicates::LastWins)
+}
+
+/// Collect the text fields of a form into a map from field name to value.
+///
+/// Only in-memory parts with a name are collected; file parts and nested multiparts are
+/// skipped.  Values are read as UTF-8, returning `Error::Utf8` for one which is not (see
+/// `Part::text()` for other charsets).
+pub fn text_fields_with(nodes: &[Node], duplicates: Duplicates)
+                        -> Result<HashMap<String, String>, Error>
+{
+    let mut map: HashMap<String, String> = HashMap::new();
+    for node in nodes {
+        let part = match *node {
+            Node::Part(ref part) => part,
+            _ => continue,
+        };
+        let name = match part.name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let value = String::from_utf8(part.body.clone())?;
+        if map.insert(name.clone(), value).is_some() && duplicates == Duplicates::Error {
+            return Err(Error::DuplicateField(name));
+        }
+    }
+    Ok(map)
+}
@@ -0,0 +1,72 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Hashing of file parts as they are written, for `ReadOptions::hash_file_parts`.  Without
+//! the `sha2` feature nothing is hashed.
+
+use std::io::{self, Write};
+
+#[cfg(feature = "sha2")]
+use sha2::{Digest, Sha256};
+
+use super::ReadOptions;
+
+// A writer which passes everything on to `inner`, hashing it on the way if the options
+// ask for file parts to be hashed.
+pub(crate) struct HashingWriter<W: Write> {
+    inner: W,
+    #[cfg(feature = "sha2")]
+    hasher: Option<Sha256>,
+}
+
+impl<W: Write> HashingWriter<W> {
+    #[cfg_attr(not(feature = "sha2"), allow(unused_variables))]
+    pub(crate) fn new(inner: W, options: &ReadOptions) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            #[cfg(feature = "sha2")]
+            hasher: if options.hash_file_parts { Some(Sha256::new()) } else { None },
+        }
+    }
+
+    // The SHA-256 digest of everything written, as lowercase hex, if it was hashed.
+    pub(crate) fn hex_digest(self) -> Option<String> {
+        self.into_parts().1
+    }
+
+    // The writer everything was passed on to, and the digest as from `hex_digest()`.
+    pub(crate) fn into_parts(self) -> (W, Option<String>) {
+        #[cfg(feature = "sha2")]
+        {
+            let digest = self.hasher.map(|hasher| {
+                hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect()
+            });
+            (self.inner, digest)
+        }
+        #[cfg(not(feature = "sha2"))]
+        {
+            (self.inner, None)
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        #[cfg(feature = "sha2")]
+        {
+            if let Some(ref mut hasher) = self.hasher {
+                hasher.update(&buf[..written]);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
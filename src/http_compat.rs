@@ -0,0 +1,56 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing of `multipart/*` bodies whose headers are held in the `http` crate's types, as
+//! used by current versions of hyper and most other frameworks.  Enabled by the `http`
+//! feature.
+
+use std::io::Read;
+
+use http::request::Parts;
+use http::HeaderMap;
+use hyper::header::Headers;
+
+use super::{read_multipart_body, Error, Node};
+
+/// Convert an `http::HeaderMap` into `hyper::header::Headers`, keeping every value of
+/// every header.
+pub fn headers_from_http(map: &HeaderMap) -> Headers {
+    let mut headers = Headers::new();
+    for name in map.keys() {
+        let values: Vec<Vec<u8>> = map.get_all(name).iter()
+            .map(|value| value.as_bytes().to_vec())
+            .collect();
+        headers.set_raw(name.as_str().to_owned(), values);
+    }
+    headers
+}
+
+/// Parse a MIME `multipart/*` body from `body`, taking the boundary from the request head
+/// in `parts`.
+///
+/// This is `read_multipart_body()` for requests split up by the `http` crate, as
+/// `http::Request::into_parts()` does.  See `read_multipart_body_http_headers()` if only
+/// the headers are at hand.
+pub fn read_multipart_http<S: Read>(
+    parts: &Parts,
+    body: &mut S,
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    read_multipart_body_http_headers(&parts.headers, body, always_use_files)
+}
+
+/// As `read_multipart_http()`, taking the boundary from an `http::HeaderMap`.
+pub fn read_multipart_body_http_headers<S: Read>(
+    headers: &HeaderMap,
+    body: &mut S,
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    read_multipart_body(body, &headers_from_http(headers), always_use_files, false)
+}
@@ -0,0 +1,105 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::slice;
+
+use super::{get_content_disposition_name, FilePart, Node, Part};
+
+/// A leaf of a `Node` tree: a part which is not itself a multipart.
+#[derive(Clone, Copy, Debug)]
+pub enum Leaf<'a> {
+    /// A part in memory
+    Part(&'a Part),
+    /// A part streamed to a file
+    File(&'a FilePart),
+}
+
+/// A depth-first iterator over the leaves of a `Node` tree, created by `leaves()` or
+/// `Node::leaves()`.
+///
+/// Each leaf is yielded along with its path: the Content-Disposition `name` of each
+/// enclosing multipart and of the leaf itself, outermost first.  Nodes without a name
+/// do not contribute to the path.
+pub struct Leaves<'a> {
+    // The nodes remaining at each level of nesting, with the path to that level
+    stack: Vec<(Vec<String>, slice::Iter<'a, Node>)>,
+}
+
+impl<'a> Leaves<'a> {
+    pub(crate) fn new(nodes: &'a [Node]) -> Leaves<'a> {
+        Leaves {
+            stack: vec![(Vec::new(), nodes.iter())],
+        }
+    }
+}
+
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (Vec<String>, Leaf<'a>);
+
+    fn next(&mut self) -> Option<(Vec<String>, Leaf<'a>)> {
+        loop {
+            let (node, mut path) = {
+                let (path, nodes) = self.stack.last_mut()?;
+                match nodes.next() {
+                    Some(node) => (node, path.clone()),
+                    None => {
+                        self.stack.pop();
+                        continue;
+                    },
+                }
+            };
+
+            let headers = match *node {
+                Node::Part(ref part) => &part.headers,
+                Node::File(ref filepart) => &filepart.headers,
+                Node::Multipart((ref headers, _)) => headers,
+            };
+            if let Some(name) = get_content_disposition_name(headers) {
+                path.push(name);
+            }
+
+            match *node {
+                Node::Part(ref part) => return Some((path, Leaf::Part(part))),
+                Node::File(ref filepart) => return Some((path, Leaf::File(filepart))),
+                Node::Multipart((_, ref subnodes)) => self.stack.push((path, subnodes.iter())),
+            }
+        }
+    }
+}
+
+/// Walk `nodes` depth-first, yielding every `Part` and `FilePart` regardless of how
+/// deeply they are nested within `Node::Multipart`s.
+pub fn leaves(nodes: &[Node]) -> Leaves<'_> {
+    Leaves::new(nodes)
+}
+
+/// The total size in bytes of every part within `nodes`, however deeply nested: the body of
+/// each in-memory `Part` and the `size` of each `FilePart`.  A file part whose size is not
+/// recorded (as for one created with `FilePart::new()`) is measured on disk, and counts
+/// for nothing if it cannot be.
+pub fn total_size(nodes: &[Node]) -> u64 {
+    leaves(nodes).map(|(_, leaf)| match leaf {
+        Leaf::Part(part) => part.body.len() as u64,
+        Leaf::File(filepart) => match filepart.size {
+            Some(size) => size as u64,
+            None => ::std::fs::metadata(&filepart.path).map(|m| m.len()).unwrap_or(0),
+        },
+    }).sum()
+}
+
+/// Whether any part within `nodes`, however deeply nested, was streamed to a file.
+pub fn has_files(nodes: &[Node]) -> bool {
+    leaves(nodes).any(|(_, leaf)| matches!(leaf, Leaf::File(_)))
+}
+
+/// Every `FilePart` within `nodes`, however deeply nested, in the order they appear.
+pub fn file_parts(nodes: &[Node]) -> Vec<&FilePart> {
+    leaves(nodes).filter_map(|(_, leaf)| match leaf {
+        Leaf::File(filepart) => Some(filepart),
+        Leaf::Part(_) => None,
+    }).collect()
+}
@@ -5,26 +5,74 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+#[cfg(feature = "tokio")]
+pub mod async_io;
+pub mod borrowed;
+pub mod boundary;
+pub mod builder;
+#[cfg(feature = "flate2")]
+mod content_encoding;
+pub mod disposition;
 pub mod error;
+pub mod fields;
+mod hashing;
+#[cfg(feature = "http")]
+pub mod http_compat;
+pub mod leaves;
+pub mod limits;
+mod lookahead;
+pub mod options;
+pub mod persist;
+pub mod push;
+pub mod related;
+pub mod route;
+pub mod stream;
+pub mod summary;
+mod transfer_encoding;
+pub mod writer;
 
 #[cfg(test)]
 mod mock;
 #[cfg(test)]
 mod tests;
 
+#[cfg(feature = "tokio")]
+pub use async_io::{read_multipart_body_async, read_multipart_body_async_cancellable,
+                   read_multipart_body_async_with_options};
+pub use borrowed::{parse_multipart_borrowed, BorrowedNode, BorrowedPart};
+pub use boundary::{validate_boundary, BoundaryFinder, BoundaryMatch};
+pub use builder::MultipartBuilder;
+pub use disposition::{ContentDisposition, DispositionType};
 pub use error::Error;
+pub use fields::{form_charset, into_field_map, into_fields, text_fields, text_fields_with,
+                 Duplicates, Field, FieldData};
+#[cfg(feature = "http")]
+pub use http_compat::{headers_from_http, read_multipart_body_http_headers,
+                      read_multipart_http};
+pub use leaves::{file_parts, has_files, leaves, total_size, Leaf, Leaves};
+pub use limits::{LimitKind, Limits};
+pub use options::{FilePartConfig, LineEnding, ReadOptions, WriteOptions};
+pub use persist::persist_all;
+pub use push::{Event, Pusher};
+pub use related::find_by_content_id;
+pub use route::{read_multipart_routed, read_multipart_routed_with_options, PartSink, RoutedNode};
+pub use stream::{validate_multipart, MultipartParts, MultipartStats, PartReader, PartStream};
+pub use summary::{summarize, MultipartSummary, PartSummary};
+pub use writer::{MultipartWriter, XMixedReplaceWriter};
 
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::path::{Path, PathBuf};
 use std::borrow::Cow;
 use std::ops::Drop;
+use std::str::FromStr;
 use encoding::{all, Encoding, DecoderTrap};
-use hyper::header::{ContentType, Headers, ContentDisposition, DispositionParam,
-                    DispositionType, Charset};
+use hyper::header::{ContentLength, ContentType, Headers, DispositionParam, Charset};
 use textnonce::TextNonce;
 use mime::{Attr, Mime, TopLevel, Value};
-use buf_read_ext::BufReadExt;
+use hashing::HashingWriter;
+use lookahead::LookaheadReader;
+use stream::find_bytes;
 
 /// A multipart part which is not a file (stored in memory)
 #[derive(Clone, Debug, PartialEq)]
@@ -33,16 +81,99 @@ pub struct Part {
     pub body: Vec<u8>,
 }
 impl Part {
-    /// Mime content-type specified in the header
+    /// Mime content-type specified in the header.  Of a parsed part with more than one
+    /// Content-Type header, only the first is kept.
     pub fn content_type(&self) -> Option<Mime> {
-        let ct: Option<&ContentType> = self.headers.get();
-        ct.map(|ref ct| ct.0.clone())
+        content_type(&self.headers)
+    }
+
+    /// The value of the parameter `key` (matched case-insensitively) of the Content-Type
+    /// header, such as `name` or `smime-type`, with any quoting removed.  Returns `None` if
+    /// there is no such header or parameter.
+    pub fn content_type_param(&self, key: &str) -> Option<String> {
+        content_type_param(&self.headers, key)
+    }
+
+    /// Field name given by the `name` parameter of the content-disposition header, decoded
+    /// from the extended `name*` form if that was used.  Returns `None` if there is no such
+    /// header or parameter.
+    pub fn name(&self) -> Option<String> {
+        get_content_disposition_name(&self.headers)
+    }
+
+    /// As `name()`, but reading a name sent as raw non-ASCII bytes in `charset`.  HTML forms
+    /// send names this way in the form's charset, which is given by a `_charset_` field if
+    /// the form has one; see `form_charset()`.
+    pub fn name_with_charset(&self, charset: &Charset) -> Option<String> {
+        disposition::name(&self.headers, charset)
+    }
+
+    /// Filename given by the content-disposition header, decoded from the extended
+    /// `filename*` form if that was used.  Returns `Ok<None>` if there was no
+    /// content-disposition header supplied, or it had no filename.
+    pub fn filename(&self) -> Result<Option<String>, Error> {
+        disposition::filename(&self.headers)
+    }
+
+    /// The range of the whole resource which this part holds, from its Content-Range
+    /// header, as the parts of a `multipart/byteranges` response do (RFC 7233 appendix A):
+    /// the first and last byte positions, inclusive, and the length of the whole resource
+    /// if it is known.  Returns `None` if there is no such header, or it is not of the
+    /// form `bytes first-last/length` (where `length` may be `*`).
+    pub fn content_range(&self) -> Option<(u64, u64, Option<u64>)> {
+        content_range(&self.headers)
+    }
+
+    /// The disposition type of the content-disposition header, or `None` if there is no
+    /// such header.
+    pub fn disposition_type(&self) -> Option<DispositionType> {
+        disposition::disposition_type(&self.headers)
+    }
+
+    /// Everything in the content-disposition header, parsed once.  Returns
+    /// `Error::Hyper(hyper::Error::Header)` if there is no such header, and
+    /// `Error::Decoding` if an extended `filename*` parameter cannot be decoded.
+    pub fn content_disposition(&self) -> Result<ContentDisposition, Error> {
+        ContentDisposition::from_headers(&self.headers)
+    }
+
+    /// The body as text, decoded from the charset given by the `charset` parameter of the
+    /// Content-Type header, or from UTF-8 if there is none.  Returns `Error::Decoding` if
+    /// the charset is not supported or the body is not valid in it.
+    pub fn text(&self) -> Result<String, Error> {
+        self.text_with_default_charset("UTF-8")
+    }
+
+    /// As `text()`, but decoding from `default` if the Content-Type gives no charset.
+    ///
+    /// The fields of an HTML form are sent without one, in the form's charset, which the
+    /// `_charset_` field gives if the form has one (HTML section 4.10.22.7); so pass the
+    /// value of `form_charset()`, or `"UTF-8"` if it is `None`.  Returns `Error::Decoding`
+    /// if `default` is needed and is not a supported charset.
+    pub fn text_with_default_charset(&self, default: &str) -> Result<String, Error> {
+        let charset = self.content_type()
+            .and_then(|mime| mime.get_param(Attr::Charset).map(|v| v.to_string()))
+            .unwrap_or_else(|| default.to_owned());
+        let charset = Charset::from_str(&charset)
+            .map_err(|_| Error::Decoding(format!("bad charset {:?}", charset).into()))?;
+        charset_decode(&charset, &self.body).map_err(Error::Decoding)
+    }
+
+    /// Take the body, without copying it, dropping the headers.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.body
     }
 }
 
 /// A file that is to be inserted into a `multipart/*` or alternatively an uploaded file that
 /// was received as part of `multipart/*` parsing.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// A clone refers to the same file, but never deletes it: only the original does, when it
+/// drops (see `delete_on_drop()`), so keep it alive for as long as any clone is in use.
+///
+/// File parts compare equal if their headers, paths and sizes are equal; the contents of
+/// the files are not compared.
+#[derive(Debug)]
 pub struct FilePart {
     /// The headers of the part
     pub headers: Headers,
@@ -51,6 +182,12 @@ pub struct FilePart {
     /// Optionally, the size of the file.  This is filled when multiparts are parsed, but is
     /// not necessary when they are generated.
     pub size: Option<usize>,
+    /// A copy of the file content in memory, if the parser was asked to keep one with
+    /// `ReadOptions::tee_file_parts`.
+    pub bytes: Option<Vec<u8>>,
+    /// The SHA-256 digest of the file content as lowercase hex, if the parser was asked to
+    /// compute one with `ReadOptions::hash_file_parts` (which requires the `sha2` feature).
+    pub hash: Option<String>,
     // The temporary directory the upload was put into, saved for the Drop trait
     tempdir: Option<PathBuf>,
 }
@@ -58,62 +195,235 @@ impl FilePart {
     pub fn new(headers: Headers, path: &Path) -> FilePart
     {
         FilePart {
-            headers: headers,
+            headers,
             path: path.to_owned(),
             size: None,
+            bytes: None,
+            hash: None,
             tempdir: None,
         }
     }
 
+    /// A file part for the form field `name`, to be written from the file at `path`.  The
+    /// file's metadata is checked, and `size` set from it; the Content-Disposition gives
+    /// the final component of `path` as the filename, and the Content-Type is guessed from
+    /// its extension (falling back to `application/octet-stream`).
+    ///
+    /// Returns `Error::Io` if there is no file at `path`.  The file is not deleted when the
+    /// part drops.
+    pub fn from_path<P: AsRef<Path>>(name: &str, path: P) -> Result<FilePart, Error> {
+        let path = path.as_ref();
+        let metadata = ::std::fs::metadata(path)?;
+        if ! metadata.is_file() {
+            return Err(Error::Io(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()))));
+        }
+        let filename = path.file_name().map(|f| f.to_string_lossy().into_owned());
+        let content_type = builder::guess_content_type(path);
+        let headers = builder::disposition_headers(name, filename.as_deref(), Some(content_type));
+        let mut filepart = FilePart::new(headers, path);
+        filepart.size = Some(metadata.len() as usize);
+        Ok(filepart)
+    }
+
     /// If you do not want the file on disk to be deleted when Self drops, call this
     /// function.  It will become your responsability to clean up.
     pub fn do_not_delete_on_drop(&mut self) {
         self.tempdir = None;
     }
 
+    /// Whether the file (and the temporary directory holding it) will be deleted when Self
+    /// drops.  This is true of the file parts created by the parser, and false of those
+    /// created with `new()`.
+    pub fn delete_on_drop(&self) -> bool {
+        self.tempdir.is_some()
+    }
+
+    /// Move the file to `path`, so that it is kept after Self drops.  Returns the file part
+    /// with its `path` updated, which will not delete the file when it drops.
+    ///
+    /// If the file cannot be renamed to `path` (as when it is on another filesystem), it
+    /// is copied there instead.  On failure the file is left where it was, and is deleted
+    /// as usual.
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> Result<FilePart, Error> {
+        let path = path.as_ref();
+        if ::std::fs::rename(&self.path, path).is_err() {
+            ::std::fs::copy(&self.path, path)?;
+            let _ = ::std::fs::remove_file(&self.path);
+        }
+        if let Some(tempdir) = self.tempdir.take() {
+            let _ = ::std::fs::remove_dir(tempdir);
+        }
+        self.path = path.to_owned();
+        Ok(self)
+    }
+
+    /// Take the path of the file, which is then not deleted: it is left where it is (in
+    /// its temporary directory, for a parsed part), and becomes your responsability to
+    /// clean up.  To move it somewhere permanent instead, see `persist()`.
+    pub fn into_path(mut self) -> PathBuf {
+        self.do_not_delete_on_drop();
+        ::std::mem::take(&mut self.path)
+    }
+
     /// Create a new temporary FilePart (when created this way, the file will be
     /// deleted once the FilePart object goes out of scope).
     pub fn create(headers: Headers) -> Result<FilePart, Error> {
+        FilePart::create_with_config(headers, &FilePartConfig::default())
+    }
+
+    /// As `create()`, but placing and naming the file as `config` directs.
+    pub fn create_with_config(headers: Headers, config: &FilePartConfig)
+                              -> Result<FilePart, Error>
+    {
         // Setup a file to capture the contents.
-        let mut path = tempfile::Builder::new().prefix("mime_multipart").tempdir()?.into_path();
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(&config.prefix);
+        let mut path = match config.directory {
+            Some(ref directory) => builder.tempdir_in(options::long_path(directory.clone()))?,
+            None => builder.tempdir()?,
+        }.keep();
         let tempdir = Some(path.clone());
-        path.push(TextNonce::sized_urlsafe(32).unwrap().into_string());
+        let preserved = if config.preserve_filename {
+            disposition::filename(&headers).ok().flatten()
+                .and_then(|f| options::sanitize_filename(&f))
+        } else {
+            None
+        };
+        path.push(preserved.unwrap_or_else(|| {
+            format!("{}{}", config.prefix, TextNonce::sized_urlsafe(32).unwrap().into_string())
+        }));
+        let path = options::long_path(path);
         Ok(FilePart {
-            headers: headers,
-            path: path,
+            headers,
+            path,
             size: None,
-            tempdir: tempdir,
+            bytes: None,
+            hash: None,
+            tempdir,
         })
     }
 
+    /// Open the file for reading.
+    pub fn open(&self) -> Result<File, Error> {
+        Ok(File::open(&self.path)?)
+    }
+
+    /// Read the whole file into memory.
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut contents = Vec::with_capacity(self.size.unwrap_or(0));
+        self.open()?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Guess the content type of the file from the magic number at its start, for when the
+    /// part has no Content-Type header.  Recognises PNG, JPEG, GIF, PDF and ZIP files, and
+    /// returns `None` for anything else or if the file cannot be read.  This does not look
+    /// at the headers; to prefer them, use
+    /// `filepart.content_type().or_else(|| filepart.sniff_content_type())`.
+    pub fn sniff_content_type(&self) -> Option<Mime> {
+        const MAGIC: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n", "image/png"),
+            (b"\xff\xd8\xff", "image/jpeg"),
+            (b"GIF87a", "image/gif"),
+            (b"GIF89a", "image/gif"),
+            (b"%PDF-", "application/pdf"),
+            (b"PK\x03\x04", "application/zip"),
+            (b"PK\x05\x06", "application/zip"),
+        ];
+        let mut head: Vec<u8> = Vec::with_capacity(8);
+        self.open().ok()?.take(8).read_to_end(&mut head).ok()?;
+        MAGIC.iter()
+            .find(|&&(magic, _)| head.starts_with(magic))
+            .and_then(|&(_, mime)| mime.parse().ok())
+    }
+
     /// Filename that was specified when the file was uploaded.  Returns `Ok<None>` if there
     /// was no content-disposition header supplied.
     pub fn filename(&self) -> Result<Option<String>, Error> {
-        let cd: Option<&ContentDisposition> = self.headers.get();
-        match cd {
-            Some(cd) => get_content_disposition_filename(cd),
-            None => Ok(None),
-        }
+        disposition::filename(&self.headers)
+    }
+
+    /// Field name given by the `name` parameter of the content-disposition header, decoded
+    /// from the extended `name*` form if that was used.  Returns `None` if there is no such
+    /// header or parameter.
+    pub fn name(&self) -> Option<String> {
+        get_content_disposition_name(&self.headers)
+    }
+
+    /// As `name()`, but reading a name sent as raw non-ASCII bytes in `charset`.  See
+    /// `Part::name_with_charset()`.
+    pub fn name_with_charset(&self, charset: &Charset) -> Option<String> {
+        disposition::name(&self.headers, charset)
     }
 
-    /// Mime content-type specified in the header
+    /// Mime content-type specified in the header.  Of a parsed part with more than one
+    /// Content-Type header, only the first is kept.
     pub fn content_type(&self) -> Option<Mime> {
-        let ct: Option<&ContentType> = self.headers.get();
-        ct.map(|ref ct| ct.0.clone())
+        content_type(&self.headers)
+    }
+
+    /// The value of the parameter `key` (matched case-insensitively) of the Content-Type
+    /// header, such as `name` or `smime-type`, with any quoting removed.  Returns `None` if
+    /// there is no such header or parameter.
+    pub fn content_type_param(&self, key: &str) -> Option<String> {
+        content_type_param(&self.headers, key)
+    }
+
+    /// The range of the whole resource which this part holds, from its Content-Range
+    /// header, as the parts of a `multipart/byteranges` response do (RFC 7233 appendix A):
+    /// the first and last byte positions, inclusive, and the length of the whole resource
+    /// if it is known.  Returns `None` if there is no such header, or it is not of the
+    /// form `bytes first-last/length` (where `length` may be `*`).
+    pub fn content_range(&self) -> Option<(u64, u64, Option<u64>)> {
+        content_range(&self.headers)
+    }
+
+    /// The disposition type of the content-disposition header, or `None` if there is no
+    /// such header.
+    pub fn disposition_type(&self) -> Option<DispositionType> {
+        disposition::disposition_type(&self.headers)
+    }
+
+    /// Everything in the content-disposition header, parsed once.  See
+    /// `Part::content_disposition()`.
+    pub fn content_disposition(&self) -> Result<ContentDisposition, Error> {
+        ContentDisposition::from_headers(&self.headers)
+    }
+}
+impl Clone for FilePart {
+    fn clone(&self) -> FilePart {
+        FilePart {
+            headers: self.headers.clone(),
+            path: self.path.clone(),
+            size: self.size,
+            bytes: self.bytes.clone(),
+            hash: self.hash.clone(),
+            // The original alone owns the file
+            tempdir: None,
+        }
+    }
+}
+impl PartialEq for FilePart {
+    fn eq(&self, other: &FilePart) -> bool {
+        self.headers == other.headers && self.path == other.path && self.size == other.size
     }
 }
 impl Drop for FilePart {
     fn drop(&mut self) {
-        if self.tempdir.is_some() {
+        if let Some(ref tempdir) = self.tempdir {
             let _ = ::std::fs::remove_file(&self.path);
-            let _ = ::std::fs::remove_dir(&self.tempdir.as_ref().unwrap());
+            let _ = ::std::fs::remove_dir(tempdir);
         }
     }
 }
 
 /// A multipart part which could be either a file, in memory, or another multipart
 /// container containing nested parts.
-#[derive(Clone, Debug)]
+///
+/// Headers compare equal regardless of their order.
+#[derive(Clone, Debug, PartialEq)]
 pub enum Node {
     /// A part in memory
     Part(Part),
@@ -122,242 +432,1399 @@ pub enum Node {
     /// A container of nested multipart parts
     Multipart((Headers, Vec<Node>)),
 }
+impl Node {
+    /// The headers of this node, whichever kind of node it is.
+    pub fn headers(&self) -> &Headers {
+        match *self {
+            Node::Part(ref part) => &part.headers,
+            Node::File(ref filepart) => &filepart.headers,
+            Node::Multipart((ref headers, _)) => headers,
+        }
+    }
+
+    /// Walk this node depth-first, yielding every `Part` and `FilePart` within it
+    /// regardless of how deeply they are nested.  See `leaves()`.
+    pub fn leaves(&self) -> Leaves<'_> {
+        Leaves::new(::std::slice::from_ref(self))
+    }
+}
+
+/// A whole `multipart/*` body, as returned by `read_multipart_full()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Multipart {
+    /// The parts of the body
+    pub nodes: Vec<Node>,
+    /// What preceded the first delimiter, up to the end of the line before it.  RFC 2046
+    /// says this is to be ignored, though it often holds a note for non-MIME readers.
+    pub preamble: Vec<u8>,
+    /// What followed the closing delimiter, after the end of its line.  This is also to be
+    /// ignored, so like the preamble it is discarded by the other parsing functions.
+    pub epilogue: Vec<u8>,
+}
+
+/// Where a part was found within the body it was parsed from, as returned by
+/// `read_multipart_body_with_meta()`.  Offsets are in bytes from the start of the body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartMeta {
+    /// The position of the part among all the parts of the body, nested ones included, in
+    /// the order they begin
+    pub index: usize,
+    /// Where the part's headers begin, just after the line of the delimiter before them
+    pub headers_offset: usize,
+    /// Where the part's body begins, just after the blank line ending its headers
+    pub body_offset: usize,
+    /// The length of the part's body as it was received, before any decoding, up to the
+    /// line terminator preceding the next delimiter.  In `lenient` mode, the CR of a CRLF
+    /// before that delimiter is counted within the body.
+    pub body_len: usize,
+}
+
+/// Parse a MIME `multipart/*` from a `Read`able stream into a `Vec` of `Node`s, streaming
+/// files to disk and keeping the rest in memory.  Recursive `multipart/*` parts will are
+/// parsed as well and returned within a `Node::Multipart` variant.
+///
+/// If `always_use_files` is true, all parts will be streamed to files.  If false, only parts
+/// with a `ContentDisposition` header set to `Attachment` or otherwise containing a `Filename`
+/// parameter will be streamed to files.
+///
+/// Part bodies are stored exactly as they were received.  To decode their
+/// `Content-Transfer-Encoding`, see `ReadOptions::decode_transfer_encoding`.
+///
+/// Nodes are returned in the order their parts appear in the body, including parts which
+/// share a field name; see `into_field_map()` to group them by name.
+///
+/// It is presumed that the headers are still in the stream.  If you have them separately,
+/// use `read_multipart_body()` instead.  If they are not those of a `multipart/*` body,
+/// the error is returned as soon as they have been read, without reading the body beyond
+/// what was buffered with them.
+///
+/// The stream is consumed: it is read at least to the closing delimiter, and bytes read
+/// ahead of that are discarded, so it cannot be parsed again.  A second attempt on the same
+/// stream returns `Error::EofInMainHeaders`.
+///
+/// The default `Limits` are applied; use `read_multipart_with_limits()` to choose others,
+/// or `read_multipart_with_options()` to control everything.
+pub fn read_multipart<S: Read>(
+    stream: &mut S,
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    read_multipart_with_limits(stream, always_use_files, &Limits::default())
+}
+
+/// As `read_multipart()`, but stopping with `Error::LimitExceeded` as soon as the body
+/// exceeds any of the given `limits`.
+pub fn read_multipart_with_limits<S: Read>(
+    stream: &mut S,
+    always_use_files: bool,
+    limits: &Limits)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions {
+        always_use_files,
+        limits: limits.clone(),
+        ..ReadOptions::default()
+    };
+    read_multipart_with_options(stream, &options)
+}
+
+/// As `read_multipart()`, but with every setting taken from `options`.
+pub fn read_multipart_with_options<S: Read>(
+    stream: &mut S,
+    options: &ReadOptions)
+    -> Result<Vec<Node>, Error>
+{
+    let mut reader = LookaheadReader::with_capacity(options.buffer_capacity(), stream);
+    let mut nodes: Vec<Node> = Vec::new();
+    let headers = read_main_headers(&mut reader)?;
+    let mut context = Context::new(options);
+    inner(&mut reader, &headers, &mut nodes, &mut context)?;
+    Ok(nodes)
+}
+
+/// As `read_multipart_with_options()`, but also returning what precedes the first
+/// delimiter of the body and what follows the closing delimiter.  The stream is read to
+/// its end.
+pub fn read_multipart_full<S: Read>(
+    stream: &mut S,
+    options: &ReadOptions)
+    -> Result<Multipart, Error>
+{
+    let mut reader = LookaheadReader::with_capacity(options.buffer_capacity(), stream);
+    let mut nodes: Vec<Node> = Vec::new();
+    let headers = read_main_headers(&mut reader)?;
+    let mut context = Context::new(options);
+    context.preamble = Some(Vec::new());
+    context.epilogue = Some(Vec::new());
+    inner(&mut reader, &headers, &mut nodes, &mut context)?;
+    Ok(Multipart {
+        nodes,
+        preamble: context.preamble.unwrap_or_default(),
+        epilogue: context.epilogue.unwrap_or_default(),
+    })
+}
+
+/// Parse a MIME `multipart/*` body from a captured HTTP message: a request or response
+/// line, then the headers, then the body.  The boundary is taken from the Content-Type
+/// header, and the body is parsed with the default options.
+///
+/// Returns `Error::EofInMainHeaders` if the stream ends within the headers.
+pub fn read_multipart_from_http<S: Read>(stream: &mut S) -> Result<Vec<Node>, Error> {
+    let mut reader = LookaheadReader::with_capacity(4096, stream);
+    let mut buf: Vec<u8> = Vec::new();
+    let (_, found) = reader.stream_until_token(b"\r\n\r\n", &mut buf)?;
+    if ! found { return Err(Error::EofInMainHeaders); }
+    buf.extend(b"\r\n\r\n".iter().cloned());
+
+    let mut header_memory = [httparse::EMPTY_HEADER; 64];
+    let headers = if buf.starts_with(b"HTTP/") {
+        let mut response = httparse::Response::new(&mut header_memory);
+        let status = response.parse(&buf);
+        start_line_headers(status, response.headers)?
+    } else {
+        let mut request = httparse::Request::new(&mut header_memory);
+        let status = request.parse(&buf);
+        start_line_headers(status, request.headers)?
+    };
+
+    let options = ReadOptions::default();
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut context = Context::new(&options);
+    inner(&mut reader, &headers, &mut nodes, &mut context)?;
+    Ok(nodes)
+}
+
+// The headers of a request or response once httparse has parsed it.
+fn start_line_headers(status: httparse::Result<usize>, raw_headers: &[httparse::Header])
+                      -> Result<Headers, Error>
+{
+    match status {
+        Ok(httparse::Status::Complete(_)) => Headers::from_raw(raw_headers).map_err(From::from),
+        // The whole block is read, up to and including the blank line, before it is parsed;
+        // so more data would not help, and this is an error rather than a reason to read on
+        Ok(httparse::Status::Partial) => Err(Error::PartialHeaders),
+        Err(err) => Err(From::from(err)),
+    }
+}
+
+// Read the headers at the start of a stream, up to and including the blank line.
+fn read_main_headers<R: Read>(reader: &mut LookaheadReader<R>) -> Result<Headers, Error> {
+    let mut buf: Vec<u8> = Vec::new();
+
+    let (_, found) = reader.stream_until_token(b"\r\n\r\n", &mut buf)?;
+    if ! found { return Err(Error::EofInMainHeaders); }
+
+    // Keep the CRLFCRLF as httparse will expect it
+    buf.extend(b"\r\n\r\n".iter().cloned());
+
+    // Parse the headers
+    let mut header_memory = [httparse::EMPTY_HEADER; 64];
+    match httparse::parse_headers(&buf, &mut header_memory) {
+        Ok(httparse::Status::Complete((_, raw_headers))) => {
+            Headers::from_raw(raw_headers).map_err(From::from)
+        },
+        Ok(httparse::Status::Partial) => Err(Error::PartialHeaders),
+        Err(err) => Err(From::from(err)),
+    }
+}
+
+/// Parse a MIME `multipart/*` from a `Read`able stream into a `Vec` of `Node`s, streaming
+/// files to disk and keeping the rest in memory.  Recursive `multipart/*` parts will are
+/// parsed as well and returned within a `Node::Multipart` variant.
+///
+/// If `always_use_files` is true, all parts will be streamed to files.  If false, only parts
+/// with a `ContentDisposition` header set to `Attachment` or otherwise containing a `Filename`
+/// parameter will be streamed to files.
+///
+/// If `decode_transfer_encoding` is true, in-memory parts with a `Content-Transfer-Encoding`
+/// header of `base64` or `quoted-printable` are decoded before being stored in `Part::body`.
+/// If false, the body bytes are stored exactly as they were received.
+///
+/// It is presumed that you have the `Headers` already and the stream starts at the body.
+/// If the headers are still in the stream, use `read_multipart()` instead.
+///
+/// The headers are checked before anything is read: if they have no Content-Type, one
+/// which is not `multipart/*`, or one without a boundary, `Error::NoRequestContentType`,
+/// `Error::NotMultipart` or `Error::BoundaryNotSpecified` is returned and the stream is
+/// left untouched, so a large body of some other type is not read.
+///
+/// The body is consumed: it is read at least to the closing delimiter, and bytes read ahead
+/// of that are discarded, so it cannot be parsed again.  A second attempt on the same body
+/// (such as a one-shot request body read twice) returns `Error::EofBeforeFirstBoundary`
+/// rather than an empty result.
+///
+/// The stream need not be buffered: it is read through a buffer of 4096 bytes, reused for
+/// the whole body, so an unbuffered socket or file is not read byte by byte.  Wrapping it
+/// in a `BufReader` saves no reads; use `ReadOptions::with_buffer_capacity()` for a larger
+/// buffer instead.
+///
+/// The default `Limits` are applied; use `read_multipart_body_with_limits()` to choose
+/// others, or `read_multipart_body_with_options()` to control everything.
+pub fn read_multipart_body<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    always_use_files: bool,
+    decode_transfer_encoding: bool)
+    -> Result<Vec<Node>, Error>
+{
+    read_multipart_body_with_limits(stream, headers, always_use_files, decode_transfer_encoding,
+                                    &Limits::default())
+}
+
+/// Parse a MIME `multipart/*` body from `body`, taking the boundary from `headers`, for
+/// frameworks which hand the headers and the body stream over separately rather than as a
+/// hyper `Request`.
+///
+/// This is `read_multipart_body()` for a body behind a trait object, without transfer
+/// decoding.
+pub fn read_multipart_parts(
+    headers: &Headers,
+    mut body: &mut dyn Read,
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    read_multipart_body(&mut body, headers, always_use_files, false)
+}
+
+/// As `read_multipart_body()`, but stopping with `Error::LimitExceeded` as soon as the body
+/// exceeds any of the given `limits`.
+pub fn read_multipart_body_with_limits<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    always_use_files: bool,
+    decode_transfer_encoding: bool,
+    limits: &Limits)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions {
+        always_use_files,
+        decode_transfer_encoding,
+        limits: limits.clone(),
+        ..ReadOptions::default()
+    };
+    read_multipart_body_with_options(stream, headers, &options)
+}
+
+/// As `read_multipart_body()`, but keeping each part which is not a file upload in memory
+/// only while it is no larger than `spill_bytes`.  A part which grows larger is moved to a
+/// temporary file as it is read, and returned as a `Node::File`.  Parts which are spilled
+/// this way are not transfer-decoded.
+pub fn read_multipart_body_threshold<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    spill_bytes: usize)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions { spill_threshold: Some(spill_bytes), ..ReadOptions::default() };
+    read_multipart_body_with_options(stream, headers, &options)
+}
+
+/// As `read_multipart_body()`, but accepting some malformations which clients are known to
+/// produce: spaces or tabs after a boundary delimiter, and lone LF line terminators
+/// anywhere (even mixed with CRLF).  See `ReadOptions::lenient`.
+pub fn read_multipart_body_lenient<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    let options = ReadOptions { always_use_files, lenient: true, ..ReadOptions::default() };
+    read_multipart_body_with_options(stream, headers, &options)
+}
+
+/// As `read_multipart_body()`, but with every setting taken from `options`.
+pub fn read_multipart_body_with_options<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    options: &ReadOptions)
+    -> Result<Vec<Node>, Error>
+{
+    let mut reader = body_reader(stream, headers, options)?;
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut context = Context::new(options);
+    inner(&mut reader, headers, &mut nodes, &mut context)?;
+    Ok(nodes)
+}
+
+/// As `read_multipart_body_with_options()`, but keeping the parts which were completely
+/// read when parsing fails, as when a client disconnects part way through an upload.
+///
+/// Returns the complete parts, with the error if parsing did not succeed (typically
+/// `Error::Truncated` for a truncated body).  The part being read when the error occurred
+/// is not among them; nor is a nested multipart, or any of its parts, unless the nested
+/// multipart was completely read.
+pub fn read_multipart_body_partial<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    options: &ReadOptions)
+    -> (Vec<Node>, Option<Error>)
+{
+    let mut reader = match body_reader(stream, headers, options) {
+        Ok(reader) => reader,
+        Err(e) => return (Vec::new(), Some(e)),
+    };
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut context = Context::new(options);
+    let error = inner(&mut reader, headers, &mut nodes, &mut context).err();
+    (nodes, error)
+}
+
+/// As `read_multipart_body_with_options()`, but also returning where each part was found
+/// in the body, for mapping the parts back to the raw request.  There is one `PartMeta`
+/// for every part, nested multiparts and the parts within them included, in the order
+/// the parts begin (so a nested multipart comes before its parts).
+pub fn read_multipart_body_with_meta<S: Read>(
+    stream: &mut S,
+    headers: &Headers,
+    options: &ReadOptions)
+    -> Result<(Vec<Node>, Vec<PartMeta>), Error>
+{
+    let mut reader = body_reader(stream, headers, options)?;
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut context = Context::new(options);
+    context.meta = Some(Vec::new());
+    inner(&mut reader, headers, &mut nodes, &mut context)?;
+    Ok((nodes, context.meta.unwrap_or_default()))
+}
+
+/// As `read_multipart_body()`, but calling `progress` with the total number of bytes read
+/// from `stream` so far, for showing the progress of a large upload.  It is called each
+/// time a buffer's worth is read, not for every byte.  The `boundary` is given without
+/// the leading `--`.
+pub fn read_multipart_body_with_progress<S: Read, F: FnMut(u64)>(
+    stream: &mut S,
+    boundary: &[u8],
+    always_use_files: bool,
+    progress: F)
+    -> Result<Vec<Node>, Error>
+{
+    let mut reader = ProgressReader { inner: stream, count: 0, progress };
+    read_multipart_body(&mut reader, &boundary_headers(boundary), always_use_files, false)
+}
+
+/// Parse only the first part of a MIME `multipart/*` body, for protocols which send a
+/// single part, with the default options.  The `boundary` is given without the leading
+/// `--`.  Returns `None` if the body has no parts.
+///
+/// Reading stops at the delimiter which ends the first part, so the rest of the body is
+/// not parsed, nor read beyond what the parser buffers (4096 bytes).  Nor is it checked:
+/// whether the body continues properly, or ends at all, is not known.  If the first part
+/// is a nested multipart, it is parsed whole.
+pub fn read_first_part<S: Read>(stream: &mut S, boundary: &[u8]) -> Result<Option<Node>, Error> {
+    let options = ReadOptions::default();
+    let mut reader = LookaheadReader::with_capacity(options.buffer_capacity(), stream);
+    let mut nodes: Vec<Node> = Vec::new();
+    let mut context = Context::new(&options);
+    context.first_only = true;
+    inner(&mut reader, &boundary_headers(boundary), &mut nodes, &mut context)?;
+    Ok(nodes.pop())
+}
+
+/// Parse a MIME `multipart/*` body which was sent compressed as a whole, with an HTTP
+/// `Content-Encoding: gzip` header.  The stream is decompressed as it is parsed, and
+/// parsed as by `read_multipart_body()` with the default options.  The `boundary` is
+/// given without the leading `--`.  Requires the `flate2` feature.
+///
+/// This is not the same as parts which are each compressed, with a `Content-Encoding`
+/// header of their own; for those, see `ReadOptions::decode_content_encoding`.  A stream
+/// which is not gzip-compressed returns `Error::Io`.
+#[cfg(feature = "flate2")]
+pub fn read_multipart_gzipped<S: Read>(stream: &mut S, boundary: &[u8])
+                                       -> Result<Vec<Node>, Error>
+{
+    let mut decoder = flate2::read::GzDecoder::new(stream);
+    read_multipart_body(&mut decoder, &boundary_headers(boundary), false, false)
+}
+
+// The reader a body is parsed through, once `headers` have been checked to give a
+// `multipart/*` Content-Type with a boundary, so that a body which is not multipart is
+// never read from.
+fn body_reader<'a, S: Read>(stream: &'a mut S, headers: &Headers, options: &ReadOptions)
+                            -> Result<LookaheadReader<&'a mut S>, Error>
+{
+    get_multipart_boundary_from_headers(headers)?;
+    Ok(LookaheadReader::with_capacity(options.buffer_capacity(), stream))
+}
+
+// Headers giving a `multipart/mixed` Content-Type with `boundary`.
+fn boundary_headers(boundary: &[u8]) -> Headers {
+    let mut headers = Headers::new();
+    let mut content_type = b"multipart/mixed; boundary=\"".to_vec();
+    content_type.extend(boundary);
+    content_type.push(b'"');
+    headers.set_raw("Content-Type", vec![content_type]);
+    headers
+}
+
+// A reader which reports the running total of bytes read through it.
+struct ProgressReader<'a, S: Read, F: FnMut(u64)> {
+    inner: &'a mut S,
+    count: u64,
+    progress: F,
+}
+impl<'a, S: Read, F: FnMut(u64)> Read for ProgressReader<'a, S, F> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read > 0 {
+            self.count += read as u64;
+            (self.progress)(self.count);
+        }
+        Ok(read)
+    }
+}
+
+/// Parse a MIME `multipart/*` body held in memory, given the value of its Content-Type
+/// header, for when the body did not come through hyper (or through HTTP at all).
+///
+/// Returns `Error::NotMultipart` if `content_type_header` is not a `multipart/*` type
+/// (or cannot be parsed as a MIME type), and `Error::BoundaryNotSpecified` if it has no
+/// boundary.  Parts are streamed to files as described for `read_multipart_body()`.
+pub fn parse_multipart_bytes(
+    content_type_header: &str,
+    body: &[u8],
+    always_use_files: bool)
+    -> Result<Vec<Node>, Error>
+{
+    let mime: Mime = content_type_header.trim().parse().map_err(|_| Error::NotMultipart)?;
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime));
+    read_multipart_body(&mut &body[..], &headers, always_use_files, false)
+}
+
+/// Parse `body` as a `multipart/*` body with the given Content-Type header value, keeping
+/// every part in memory and decoding transfer encodings.  This is meant as the target of a
+/// fuzzer, as it needs no hyper types and never touches the filesystem: it should return
+/// an error for bad input, and never panic.
+pub fn fuzz_parse(content_type: &str, body: &[u8]) -> Result<Vec<Node>, Error> {
+    let mime: Mime = content_type.trim().parse().map_err(|_| Error::NotMultipart)?;
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime));
+    let options = ReadOptions {
+        in_memory_only: true,
+        decode_transfer_encoding: true,
+        ..ReadOptions::default()
+    };
+    read_multipart_body_with_options(&mut &body[..], &headers, &options)
+}
+
+// Settings and running totals shared by every level of a (possibly nested) parse
+struct Context<'a> {
+    options: &'a ReadOptions,
+    // Parts seen so far, at any depth
+    parts: usize,
+    // Current multipart nesting depth
+    depth: usize,
+    // The delimiters (with their line terminators) of the multiparts enclosing the one
+    // being read, any of which ends it
+    enclosing: Vec<Vec<u8>>,
+    // If set, what precedes the first delimiter and what follows the closing delimiter of
+    // the outermost multipart are read into these
+    preamble: Option<Vec<u8>>,
+    epilogue: Option<Vec<u8>>,
+    // If set, where each part was found is recorded in this
+    meta: Option<Vec<PartMeta>>,
+    // Stop once the first part of the outermost multipart has been read
+    first_only: bool,
+}
+impl<'a> Context<'a> {
+    fn new(options: &'a ReadOptions) -> Context<'a> {
+        Context {
+            options,
+            parts: 0,
+            depth: 0,
+            enclosing: Vec::new(),
+            preamble: None,
+            epilogue: None,
+            meta: None,
+            first_only: false,
+        }
+    }
+}
+
+fn inner<R: Read>(
+    reader: &mut LookaheadReader<R>,
+    headers: &Headers,
+    nodes: &mut Vec<Node>,
+    context: &mut Context)
+    -> Result<(), Error>
+{
+    let mut buf: Vec<u8> = Vec::new();
+
+    let boundary = get_multipart_boundary(headers)?;
+
+    // Read past the initial boundary, keeping what precedes it only if it is wanted
+    match context.preamble {
+        Some(ref mut preamble) if context.depth == 0 => {
+            let (_, found) = reader.stream_until_token(&boundary, &mut buf)?;
+            if ! found { return Err(Error::EofBeforeFirstBoundary); }
+            // The line terminator before the boundary belongs to the delimiter
+            if buf.ends_with(b"\r\n") {
+                buf.truncate(buf.len() - 2);
+            } else if buf.ends_with(b"\n") {
+                buf.truncate(buf.len() - 1);
+            }
+            preamble.extend(&buf);
+        },
+        _ => {
+            let (_, found) = reader.stream_until_token(&boundary, &mut ::std::io::sink())?;
+            if ! found { return Err(Error::EofBeforeFirstBoundary); }
+        },
+    }
+
+    // A closing delimiter straight away is an empty multipart
+    if reader.peek(2)? == b"--" {
+        return read_closing(reader, context);
+    }
+
+    // In lenient mode, allow linear whitespace after the boundary
+    let strict = context.options.strict_crlf;
+    let lenient = context.options.lenient && ! strict;
+    if lenient {
+        skip_whitespace(reader)?;
+    }
+
+    // Define the boundary, including the line terminator preceding it.
+    // Use their first line terminator to determine whether to use CRLF or LF.  In lenient
+    // mode a lone LF is always accepted, and any CR before it dropped.
+    let (lt, ltlt, lt_boundary) = {
+        let peeker = reader.peek(2)?;
+        if lenient && (peeker.starts_with(b"\r\n") || peeker.starts_with(b"\n")) {
+            let mut output = Vec::with_capacity(1 + boundary.len());
+            output.push(b'\n');
+            output.extend(boundary.clone());
+            (vec![b'\n'], vec![b'\n', b'\n'], output)
+        }
+        else if peeker.len() > 1 && &peeker[..2]==b"\r\n" {
+            let mut output = Vec::with_capacity(2 + boundary.len());
+            output.push(b'\r');
+            output.push(b'\n');
+            output.extend(boundary.clone());
+            (vec![b'\r', b'\n'], vec![b'\r', b'\n', b'\r', b'\n'], output)
+        }
+        else if !strict && !peeker.is_empty() && peeker[0]==b'\n' {
+            let mut output = Vec::with_capacity(1 + boundary.len());
+            output.push(b'\n');
+            output.extend(boundary.clone());
+            (vec![b'\n'], vec![b'\n', b'\n'], output)
+        }
+        else {
+            return Err(Error::NoCrLfAfterBoundary);
+        }
+    };
+    let delimiter = BoundaryFinder::with_delimiter(&lt_boundary);
+
+    // The index within `context.meta` of the part before the delimiter just read
+    let mut previous: Option<usize> = None;
+
+    loop {
+        // The previous part's body ended just before the delimiter
+        if let (Some(index), Some(meta)) = (previous.take(), context.meta.as_mut()) {
+            let part = &mut meta[index];
+            part.body_len = reader.position() - lt_boundary.len() - part.body_offset;
+        }
+        if context.first_only && context.depth == 0 && ! nodes.is_empty() {
+            return Ok(());
+        }
+
+        // If the next two lookahead characters are '--', parsing is finished.
+        {
+            let peeker = reader.peek(2)?;
+            if peeker.len() >= 2 && &peeker[..2] == b"--" {
+                return read_closing(reader, context);
+            }
+        }
+
+        // Read the line terminator after the boundary.  What comes before it is buffered, so
+        // counts towards the size of the headers.  If the body ends here, all that is
+        // missing is the closing delimiter.
+        buf.truncate(0);
+        let mut limited = LimitedWriter::new(&mut buf, context.options.limits.max_header_size);
+        let (_, found) = match reader.stream_until_token(&lt, &mut limited) {
+            Err(_) if limited.exceeded => return Err(Error::LimitExceeded(LimitKind::HeaderSize)),
+            other => other?,
+        };
+        let whitespace = buf.iter().all(|&b| b == b' ' || b == b'\t');
+        if ! found {
+            return Err(if buf.iter().all(|b| b" \t\r\n".contains(b)) {
+                Error::MissingClosingBoundary
+            } else {
+                Error::NoCrLfAfterBoundary
+            });
+        }
+        if strict && ! whitespace {
+            return Err(Error::NoCrLfAfterBoundary);
+        }
+        if reader.peek(1)?.is_empty() {
+            return Err(Error::MissingClosingBoundary);
+        }
+
+        // Read the headers (which end in 2 line terminators)
+        buf.truncate(0); // start fresh
+        let headers_offset = reader.position();
+        let max_header_size = context.options.limits.max_header_size;
+        if lenient {
+            read_headers_lenient(reader, &mut buf, max_header_size)?;
+        } else {
+            let mut limited = LimitedWriter::new(&mut buf, max_header_size);
+            let (_, found) = match reader.stream_until_token(&ltlt, &mut limited) {
+                Err(_) if limited.exceeded => {
+                    return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+                },
+                other => other?,
+            };
+            if ! found { return Err(Error::EofInPartHeaders); }
+
+            // Keep the 2 line terminators as httparse will expect it
+            buf.extend(ltlt.iter().cloned());
+        }
+
+        // Parse the headers
+        let part_headers = parse_part_headers(&buf, context.options.limits.max_headers,
+                                              context.options.reject_conflicting_content_type)?;
+
+        context.parts += 1;
+        if context.parts > context.options.limits.max_parts {
+            return Err(Error::LimitExceeded(LimitKind::Parts));
+        }
+        if let Some(ref mut meta) = context.meta {
+            previous = Some(meta.len());
+            meta.push(PartMeta {
+                index: meta.len(),
+                headers_offset,
+                body_offset: reader.position(),
+                body_len: 0,
+            });
+        }
+
+        if context.options.require_disposition && context.depth == 0 &&
+            part_headers.get_raw("Content-Disposition").is_none()
+        {
+            return Err(Error::MissingContentDisposition(nodes.len()));
+        }
+
+        // Check for a nested multipart
+        if is_multipart(&part_headers) {
+            // Recurse:
+            if context.depth + 1 > context.options.limits.max_depth {
+                return Err(Error::LimitExceeded(LimitKind::Depth));
+            }
+            let mut inner_nodes: Vec<Node> = Vec::new();
+            let body_offset = reader.position();
+            context.depth += 1;
+            let enclosed = context.enclosing.len();
+            if lenient {
+                // Any CR before our delimiter would be dropped as part of its line terminator
+                context.enclosing.push([&b"\r"[..], &lt_boundary].concat());
+            }
+            context.enclosing.push(lt_boundary.clone());
+            inner(reader, &part_headers, &mut inner_nodes, context)?;
+            context.enclosing.truncate(enclosed);
+            context.depth -= 1;
+
+            // Skip past the nested multipart's closing delimiter (and anything following
+            // it) to our own next boundary.
+            let skipped_from = reader.position();
+            let (read, found) = stream_body(reader, &delimiter, &mut ::std::io::sink(), false,
+                                            &context.enclosing)?;
+            if ! found {
+                return Err(truncated(&lt_boundary, skipped_from + read - body_offset));
+            }
+
+            nodes.push(Node::Multipart((part_headers, inner_nodes)));
+            continue;
+        }
+
+        if streams_to_file(&part_headers, context.options) {
+            // Setup a file to capture the contents.
+            let mut filepart = FilePart::create_with_config(part_headers,
+                                                            &context.options.file_parts)?;
+            let mut file = File::create(filepart.path.clone())?;
+
+            // Stream out the file, keeping a copy in memory and hashing it if asked to.
+            let mut tee = TeeWriter::new(&mut file, context.options.tee_file_parts);
+            let mut hashing = HashingWriter::new(&mut tee, context.options);
+            let mut checked = BareDelimiterCheck::new(&mut hashing, &boundary, strict);
+            let result = stream_body(reader, &delimiter, &mut checked, lenient, &context.enclosing);
+            let bare = checked.found;
+            filepart.hash = hashing.hex_digest();
+            filepart.bytes = tee.bytes;
+            let (read, found) = match result {
+                Err(_) if bare => return Err(Error::NoCrLfAfterBoundary),
+                other => other?,
+            };
+            if ! found { return Err(truncated(&lt_boundary, read)); }
+            check_length(&filepart.headers, read, context.options)?;
+            filepart.size = Some(read);
+
+            // TODO: Handle Content-Transfer-Encoding.  RFC 7578 section 4.7 deprecated
+            // this, and the authors state "Currently, no deployed implementations that
+            // send such bodies have been discovered", so this is very low priority.
+
+            nodes.push(Node::File(filepart));
+        } else {
+            // Read straight into the part's own body rather than copying it out of `buf`,
+            // allocated up front if the part gives its Content-Length
+            let mut body = Vec::with_capacity(body_size_hint(&part_headers, context.options));
+            let spill_threshold = context.options.spill_threshold
+                .filter(|_| ! context.options.in_memory_only);
+            let (read, found) = match spill_threshold {
+                Some(threshold) => {
+                    // Keep the part in memory unless it grows beyond the threshold
+                    let mut spill = SpillWriter::new(&mut body, threshold, &part_headers,
+                                                     &context.options.file_parts,
+                                                     context.options.tee_file_parts);
+                    let mut hashing = HashingWriter::new(&mut spill, context.options);
+                    let mut checked = BareDelimiterCheck::new(&mut hashing, &boundary, strict);
+                    let result = stream_body(reader, &delimiter, &mut checked, lenient,
+                                             &context.enclosing);
+                    let bare = checked.found;
+                    let hash = hashing.hex_digest();
+                    let (read, found) = match result {
+                        Err(_) if bare => return Err(Error::NoCrLfAfterBoundary),
+                        Err(e) => return Err(spill.error.take().unwrap_or(Error::Io(e))),
+                        Ok(x) => x,
+                    };
+                    if let Some((mut filepart, tee)) = spill.spilled.take() {
+                        filepart.hash = hash;
+                        filepart.bytes = tee.bytes;
+                        if ! found { return Err(truncated(&lt_boundary, read)); }
+                        check_length(&filepart.headers, read, context.options)?;
+                        filepart.size = Some(read);
+                        nodes.push(Node::File(filepart));
+                        continue;
+                    }
+                    (read, found)
+                },
+                None => {
+                    let mut limited = LimitedWriter::new(&mut body,
+                                                         context.options.limits.max_part_size);
+                    let mut checked = BareDelimiterCheck::new(&mut limited, &boundary, strict);
+                    let result = stream_body(reader, &delimiter, &mut checked, lenient,
+                                             &context.enclosing);
+                    match result {
+                        Err(_) if checked.found => return Err(Error::NoCrLfAfterBoundary),
+                        Err(_) if limited.exceeded => {
+                            return Err(Error::LimitExceeded(LimitKind::PartSize));
+                        },
+                        other => other?,
+                    }
+                },
+            };
+            if ! found { return Err(truncated(&lt_boundary, read)); }
+            nodes.push(Node::Part(finish_part(part_headers, body, nodes.len(),
+                                              context.options)?));
+        }
+    }
+}
+
+// Whether a part is to be streamed to a file, rather than kept in memory.
+pub(crate) fn streams_to_file(headers: &Headers, options: &ReadOptions) -> bool {
+    ! options.in_memory_only && (options.always_use_files || is_file_part(headers))
+}
+
+// Check the whole body of an in-memory part, the `index`th of its multipart, and decode
+// it as the options ask.
+pub(crate) fn finish_part(headers: Headers, body: Vec<u8>, index: usize, options: &ReadOptions)
+                          -> Result<Part, Error>
+{
+    check_length(&headers, body.len(), options)?;
+    if body.len() > options.limits.max_part_size {
+        return Err(Error::LimitExceeded(LimitKind::PartSize));
+    }
+
+    let body = if options.decode_transfer_encoding {
+        transfer_encoding::decode(&headers, body, options.validate_7bit).map_err(|e| {
+            Error::Decoding(format!("{}: {}", describe_part(&headers, index), e).into())
+        })?
+    } else {
+        body
+    };
+    #[cfg(feature = "flate2")]
+    let body = if options.decode_content_encoding {
+        let limit = options.limits.max_part_size;
+        content_encoding::decode(&headers, body, limit).map_err(|e| match e {
+            Error::Decoding(e) => Error::Decoding(
+                format!("{}: {}", describe_part(&headers, index), e).into()),
+            e => e,
+        })?
+    } else {
+        body
+    };
+
+    Ok(Part { headers, body })
+}
+
+// How much to allocate up front for a part's body: its Content-Length, within reason.
+fn body_size_hint(headers: &Headers, options: &ReadOptions) -> usize {
+    let declared = match headers.get::<ContentLength>() {
+        Some(&ContentLength(length)) => length.min(usize::MAX as u64) as usize,
+        None => return 0,
+    };
+    // The header is not to be trusted with a large allocation
+    let max = options.spill_threshold.unwrap_or(usize::MAX)
+        .min(options.limits.max_part_size)
+        .min(1024 * 1024);
+    declared.min(max)
+}
+
+// The "--" of a closing delimiter is next.  Read the epilogue after it, if this is the
+// outermost multipart and the epilogue is wanted.
+fn read_closing<R: Read>(reader: &mut LookaheadReader<R>, context: &mut Context)
+                         -> Result<(), Error>
+{
+    if context.depth == 0 {
+        if let Some(ref mut epilogue) = context.epilogue {
+            reader.consume(2);
+            read_epilogue(reader, epilogue)?;
+        }
+    }
+    Ok(())
+}
+
+// Read the rest of the stream after a closing delimiter.  Any transport padding and the
+// line terminator ending the delimiter line are not part of the epilogue (RFC 2046 section
+// 5.1.1).
+fn read_epilogue<R: BufRead>(reader: &mut R, epilogue: &mut Vec<u8>) -> Result<(), Error> {
+    reader.read_to_end(epilogue)?;
+    let padding = epilogue.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+    let rest = &epilogue[padding..];
+    let lt = if rest.starts_with(b"\r\n") { 2 } else if rest.starts_with(b"\n") { 1 } else { 0 };
+    if lt > 0 {
+        epilogue.drain(..padding + lt);
+    }
+    Ok(())
+}
+
+// Stream a part's body up to `delimiter`.  If `strip_cr` is set the delimiter starts with
+// a lone LF, and a CR immediately before it is dropped as part of the line terminator.
+//
+// The part of a nested multipart also ends at any of the `enclosing` delimiters, as the
+// part enclosing it does, and is then cut short: it is returned as not found, after the
+// bytes preceding that delimiter.
+fn stream_body<R: Read, W: Write>(
+    reader: &mut LookaheadReader<R>,
+    delimiter: &BoundaryFinder,
+    writer: &mut W,
+    strip_cr: bool,
+    enclosing: &[Vec<u8>])
+    -> ::std::io::Result<(usize, bool)>
+{
+    let mut fenced = EnclosingCheck::new(writer, enclosing);
+    let result = if strip_cr {
+        stream_stripping_cr(reader, delimiter, &mut fenced)
+    } else {
+        reader.stream_until(delimiter, &mut fenced)
+    };
+    match (result, fenced.found) {
+        (Err(_), Some(at)) => Ok((at, false)),
+        (result, _) => result,
+    }
+}
+
+fn stream_stripping_cr<R: Read, W: Write>(
+    reader: &mut LookaheadReader<R>,
+    delimiter: &BoundaryFinder,
+    writer: &mut W)
+    -> ::std::io::Result<(usize, bool)>
+{
+    let mut stripper = CrStripper { inner: writer, held: false, written: 0 };
+    let (_, found) = reader.stream_until(delimiter, &mut stripper)?;
+    if ! found && stripper.held {
+        // It was not a line terminator after all
+        stripper.inner.write_all(b"\r")?;
+        stripper.written += 1;
+    }
+    Ok((stripper.written, found))
+}
+
+// A writer which passes everything on to `inner`, until one of `delimiters` is written.
+// It fails then, having passed on what precedes it, with `found` set to where it began.
+struct EnclosingCheck<'a, W: Write> {
+    inner: &'a mut W,
+    delimiters: &'a [Vec<u8>],
+    // The end of what has been written, where a delimiter may have begun
+    tail: Vec<u8>,
+    written: usize,
+    found: Option<usize>,
+}
+impl<'a, W: Write> EnclosingCheck<'a, W> {
+    fn new(inner: &'a mut W, delimiters: &'a [Vec<u8>]) -> EnclosingCheck<'a, W> {
+        EnclosingCheck { inner, delimiters, tail: Vec::new(), written: 0, found: None }
+    }
+}
+impl<'a, W: Write> Write for EnclosingCheck<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        if self.delimiters.is_empty() {
+            return self.inner.write(buf);
+        }
+        let keep = self.delimiters.iter().map(Vec::len).max().unwrap_or(1) - 1;
+        let mut joined = ::std::mem::take(&mut self.tail);
+        let before = joined.len();
+        joined.extend(&buf[..buf.len().min(keep)]);
+        // Where the first delimiter begins, which may be before `buf` does
+        let written = self.written;
+        let at = self.delimiters.iter().flat_map(|delimiter| {
+            let straddling = find_bytes(&joined, delimiter).map(|at| written - before + at);
+            let within = find_bytes(buf, delimiter).map(|at| written + at);
+            straddling.into_iter().chain(within)
+        }).min();
+        if let Some(at) = at {
+            self.inner.write_all(&buf[..at.saturating_sub(written)])?;
+            self.found = Some(at);
+            return Err(::std::io::Error::other("a delimiter of an enclosing multipart"));
+        }
+        self.inner.write_all(buf)?;
+        self.written += buf.len();
+        self.tail = if buf.len() >= keep {
+            buf[buf.len() - keep..].to_vec()
+        } else {
+            joined[joined.len().saturating_sub(keep)..].to_vec()
+        };
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// A writer which holds back a trailing CR until it knows more follows.
+struct CrStripper<'a, W: Write> {
+    inner: &'a mut W,
+    held: bool,
+    written: usize,
+}
+impl<'a, W: Write> Write for CrStripper<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.held {
+            self.inner.write_all(b"\r")?;
+            self.written += 1;
+            self.held = false;
+        }
+        let (content, held) = match buf.split_last() {
+            Some((&b'\r', content)) => (content, true),
+            _ => (buf, false),
+        };
+        self.inner.write_all(content)?;
+        self.written += content.len();
+        self.held = held;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Skip any spaces and tabs.
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> ::std::io::Result<()> {
+    loop {
+        let (skip, more) = {
+            let peeker = reader.fill_buf()?;
+            let skip = peeker.iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            (skip, skip > 0 && skip == peeker.len())
+        };
+        reader.consume(skip);
+        if ! more {
+            return Ok(());
+        }
+    }
+}
+
+// Read a block of part headers line by line, accepting either CRLF or LF line terminators,
+// into `buf` with CRLF line terminators (including the blank line) as httparse expects.
+fn read_headers_lenient<R: Read>(reader: &mut LookaheadReader<R>, buf: &mut Vec<u8>,
+                                 max_size: usize) -> Result<(), Error>
+{
+    let mut line: Vec<u8> = Vec::new();
+    let mut size = 0;
+    loop {
+        line.truncate(0);
+        let mut limited = LimitedWriter::new(&mut line, max_size - size);
+        let (read, found) = match reader.stream_until_token(b"\n", &mut limited) {
+            Err(_) if limited.exceeded => {
+                return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+            },
+            other => other?,
+        };
+        if ! found { return Err(Error::EofInPartHeaders); }
+        size += read + 1;
+        if size > max_size {
+            return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        buf.extend(&line);
+        buf.extend(b"\r\n");
+        if line.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+// The error for a body which ended within a part, before its closing delimiter.
+pub(crate) fn truncated(delimiter: &[u8], bytes_read: usize) -> Error {
+    Error::Truncated { expected_boundary: delimiter.to_vec(), bytes_read }
+}
+
+// If the options ask for it, check a part's length against its Content-Length header.
+pub(crate) fn check_length(headers: &Headers, read: usize, options: &ReadOptions)
+                           -> Result<(), Error>
+{
+    if ! options.check_part_lengths {
+        return Ok(());
+    }
+    match headers.get::<ContentLength>() {
+        Some(&ContentLength(declared)) if declared != read as u64 => {
+            Err(Error::LengthMismatch { declared, actual: read as u64 })
+        },
+        _ => Ok(()),
+    }
+}
+
+// Looks for a delimiter preceded by a bare CR or bare LF, which a parser more lenient with
+// line terminators would take to end the part, in a body checked piece by piece.
+#[derive(Clone, Debug)]
+pub(crate) struct BareDelimiters {
+    // The boundary (with its leading "--") after a CR and after an LF
+    delimiters: [Vec<u8>; 2],
+    // The end of what has been checked, where a delimiter may have begun
+    tail: Vec<u8>,
+}
+impl BareDelimiters {
+    pub(crate) fn new(boundary: &[u8]) -> BareDelimiters {
+        BareDelimiters {
+            delimiters: [[b"\r", boundary].concat(), [b"\n", boundary].concat()],
+            tail: Vec::new(),
+        }
+    }
+
+    // Whether `buf`, or its join with what was checked before it, holds one.
+    pub(crate) fn check(&mut self, buf: &[u8]) -> bool {
+        let keep = self.delimiters[0].len() - 1;
+        let mut joined = ::std::mem::take(&mut self.tail);
+        joined.extend(&buf[..buf.len().min(keep)]);
+        let found = self.delimiters.iter().any(|delimiter| {
+            find_bytes(buf, delimiter).is_some() || find_bytes(&joined, delimiter).is_some()
+        });
+        self.tail = if buf.len() >= keep {
+            buf[buf.len() - keep..].to_vec()
+        } else {
+            joined[joined.len().saturating_sub(keep)..].to_vec()
+        };
+        found
+    }
+}
+
+// A writer which passes everything on to `inner`, checking on the way with
+// `BareDelimiters`, if `check` is set.  It fails once one has been written, with `found`
+// set.
+struct BareDelimiterCheck<'a, W: Write> {
+    inner: &'a mut W,
+    bare: Option<BareDelimiters>,
+    found: bool,
+}
+impl<'a, W: Write> BareDelimiterCheck<'a, W> {
+    fn new(inner: &'a mut W, boundary: &[u8], check: bool) -> BareDelimiterCheck<'a, W> {
+        let bare = if check { Some(BareDelimiters::new(boundary)) } else { None };
+        BareDelimiterCheck { inner, bare, found: false }
+    }
+}
+impl<'a, W: Write> Write for BareDelimiterCheck<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        if self.bare.as_mut().is_some_and(|bare| bare.check(buf)) {
+            self.found = true;
+            return Err(::std::io::Error::other("bare line terminator before a delimiter"));
+        }
+        self.inner.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Whether a part should be streamed to a file: if its Content-Disposition is an
+// attachment or gives a filename.
+fn is_file_part(headers: &Headers) -> bool {
+    let cd: Option<&hyper::header::ContentDisposition> = headers.get();
+    if let Some(cd) = cd {
+        if cd.disposition == hyper::header::DispositionType::Attachment {
+            true
+        } else {
+            cd.parameters.iter().any(|x| matches!(*x, DispositionParam::Filename(_,_,_)))
+        }
+    } else {
+        false
+    }
+}
 
-/// Parse a MIME `multipart/*` from a `Read`able stream into a `Vec` of `Node`s, streaming
-/// files to disk and keeping the rest in memory.  Recursive `multipart/*` parts will are
-/// parsed as well and returned within a `Node::Multipart` variant.
-///
-/// If `always_use_files` is true, all parts will be streamed to files.  If false, only parts
-/// with a `ContentDisposition` header set to `Attachment` or otherwise containing a `Filename`
-/// parameter will be streamed to files.
-///
-/// It is presumed that the headers are still in the stream.  If you have them separately,
-/// use `read_multipart_body()` instead.
-pub fn read_multipart<S: Read>(
-    stream: &mut S,
-    always_use_files: bool)
-    -> Result<Vec<Node>, Error>
-{
-    let mut reader = BufReader::with_capacity(4096, stream);
-    let mut nodes: Vec<Node> = Vec::new();
+// Whether a part is itself a `multipart/*` container.
+fn is_multipart(headers: &Headers) -> bool {
+    matches!(content_type(headers), Some(Mime(TopLevel::Multipart, _, _)))
+}
 
-    let mut buf: Vec<u8> = Vec::new();
+// The first Content-Type header, parsed.  Whitespace around the value is not part of it
+// (RFC 7230 section 3.2), but hyper does not remove it, and will not parse the value with
+// it, so it is trimmed here.
+fn content_type(headers: &Headers) -> Option<Mime> {
+    let raw = headers.get_raw("Content-Type")?.first()?;
+    ::std::str::from_utf8(raw).ok()?.trim().parse().ok()
+}
 
-    let (_, found) = reader.stream_until_token(b"\r\n\r\n", &mut buf)?;
-    if ! found { return Err(Error::EofInMainHeaders); }
+// The parameter `key` of the first Content-Type header.
+fn content_type_param(headers: &Headers, key: &str) -> Option<String> {
+    let raw = headers.get_raw("Content-Type")?.first()?;
+    boundary::param(&String::from_utf8_lossy(raw), key)
+        .map(|value| String::from_utf8_lossy(&value).into_owned())
+}
 
-    // Keep the CRLFCRLF as httparse will expect it
-    buf.extend(b"\r\n\r\n".iter().cloned());
+// Parse a `bytes first-last/length` Content-Range header.
+fn content_range(headers: &Headers) -> Option<(u64, u64, Option<u64>)> {
+    let raw = headers.get_raw("Content-Range")?.first()?;
+    let value = ::std::str::from_utf8(raw).ok()?.trim();
+    let (unit, range) = value.split_once(' ')?;
+    if ! unit.eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let number = |digits: &str| -> Option<u64> {
+        if digits.is_empty() || ! digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let (range, length) = range.trim().split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = (number(first)?, number(last)?);
+    let length = match length {
+        "*" => None,
+        length => Some(number(length)?),
+    };
+    if last < first || length.is_some_and(|length| last >= length) {
+        return None;
+    }
+    Some((first, last, length))
+}
 
-    // Parse the headers
-    let mut header_memory = [httparse::EMPTY_HEADER; 64];
-    let headers = match httparse::parse_headers(&buf, &mut header_memory) {
+// Parse a block of part headers, which must include the terminating blank line.  Where a
+// part has more than one Content-Type header, the first is used and the others dropped,
+// unless `reject_conflicts` is set and they differ.
+fn parse_part_headers(buf: &[u8], max_headers: usize, reject_conflicts: bool)
+                      -> Result<Headers, Error>
+{
+    let mut header_memory = vec![httparse::EMPTY_HEADER; max_headers];
+    match httparse::parse_headers(buf, &mut header_memory) {
         Ok(httparse::Status::Complete((_, raw_headers))) => {
-            Headers::from_raw(raw_headers).map_err(|e| From::from(e))
+            let mut headers = Headers::from_raw(raw_headers)?;
+            first_content_type(&mut headers, reject_conflicts)?;
+            Ok(headers)
         },
         Ok(httparse::Status::Partial) => Err(Error::PartialHeaders),
+        Err(httparse::Error::TooManyHeaders) => Err(Error::LimitExceeded(LimitKind::Headers)),
         Err(err) => Err(From::from(err)),
-    }?;
-
-    inner(&mut reader, &headers, &mut nodes, always_use_files)?;
-    Ok(nodes)
+    }
 }
 
-/// Parse a MIME `multipart/*` from a `Read`able stream into a `Vec` of `Node`s, streaming
-/// files to disk and keeping the rest in memory.  Recursive `multipart/*` parts will are
-/// parsed as well and returned within a `Node::Multipart` variant.
-///
-/// If `always_use_files` is true, all parts will be streamed to files.  If false, only parts
-/// with a `ContentDisposition` header set to `Attachment` or otherwise containing a `Filename`
-/// parameter will be streamed to files.
-///
-/// It is presumed that you have the `Headers` already and the stream starts at the body.
-/// If the headers are still in the stream, use `read_multipart()` instead.
-pub fn read_multipart_body<S: Read>(
-    stream: &mut S,
-    headers: &Headers,
-    always_use_files: bool)
-    -> Result<Vec<Node>, Error>
-{
-    let mut reader = BufReader::with_capacity(4096, stream);
-    let mut nodes: Vec<Node> = Vec::new();
-    inner(&mut reader, headers, &mut nodes, always_use_files)?;
-    Ok(nodes)
+// Keep only the first of several Content-Type headers.  hyper will not parse a header
+// which is repeated, so without this such a part would have no content type at all.
+fn first_content_type(headers: &mut Headers, reject_conflicts: bool) -> Result<(), Error> {
+    let values: Vec<Vec<u8>> = match headers.get_raw("Content-Type") {
+        Some(raw) if raw.len() > 1 => raw.iter().map(|v| v.to_vec()).collect(),
+        _ => return Ok(()),
+    };
+    if reject_conflicts {
+        let parse = |value: &[u8]| String::from_utf8_lossy(value).trim().parse::<Mime>().ok();
+        let first = parse(&values[0]);
+        let conflict = values[1..].iter().find(|value| match (&first, parse(value)) {
+            (Some(first), Some(other)) => *first != other,
+            _ => values[0].trim_ascii() != value.trim_ascii(),
+        });
+        if let Some(conflict) = conflict {
+            return Err(Error::Decoding(format!(
+                "conflicting Content-Type headers: {:?} and {:?}",
+                String::from_utf8_lossy(&values[0]), String::from_utf8_lossy(conflict)).into()));
+        }
+    }
+    headers.set_raw("Content-Type", vec![values[0].clone()]);
+    Ok(())
 }
 
-fn inner<R: BufRead>(
-    reader: &mut R,
-    headers: &Headers,
-    nodes: &mut Vec<Node>,
-    always_use_files: bool)
-    -> Result<(), Error>
-{
-    let mut buf: Vec<u8> = Vec::new();
-
-    let boundary = get_multipart_boundary(headers)?;
+// A writer into a Vec which fails once more than `limit` bytes have been written to it.
+struct LimitedWriter<'a> {
+    inner: &'a mut Vec<u8>,
+    limit: usize,
+    exceeded: bool,
+}
+impl<'a> LimitedWriter<'a> {
+    fn new(inner: &'a mut Vec<u8>, limit: usize) -> LimitedWriter<'a> {
+        LimitedWriter { inner, limit, exceeded: false }
+    }
+}
+impl<'a> Write for LimitedWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        if self.inner.len() + buf.len() > self.limit {
+            self.exceeded = true;
+            return Err(::std::io::Error::other("limit exceeded"));
+        }
+        self.inner.write(buf)
+    }
 
-    // Read past the initial boundary
-    let (_, found) = reader.stream_until_token(&boundary, &mut buf)?;
-    if ! found { return Err(Error::EofBeforeFirstBoundary); }
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        Ok(())
+    }
+}
 
-    // Define the boundary, including the line terminator preceding it.
-    // Use their first line terminator to determine whether to use CRLF or LF.
-    let (lt, ltlt, lt_boundary) = {
-        let peeker = reader.fill_buf()?;
-        if peeker.len() > 1 && &peeker[..2]==b"\r\n" {
-            let mut output = Vec::with_capacity(2 + boundary.len());
-            output.push(b'\r');
-            output.push(b'\n');
-            output.extend(boundary.clone());
-            (vec![b'\r', b'\n'], vec![b'\r', b'\n', b'\r', b'\n'], output)
-        }
-        else if peeker.len() > 0 && peeker[0]==b'\n' {
-            let mut output = Vec::with_capacity(1 + boundary.len());
-            output.push(b'\n');
-            output.extend(boundary.clone());
-            (vec![b'\n'], vec![b'\n', b'\n'], output)
-        }
-        else {
-            return Err(Error::NoCrLfAfterBoundary);
+// A writer into a file which also keeps a copy of what is written, while `cap` is given
+// and until more than `cap` bytes have been.
+pub(crate) struct TeeWriter<W> {
+    file: W,
+    bytes: Option<Vec<u8>>,
+    cap: usize,
+}
+impl<W: Write> TeeWriter<W> {
+    pub(crate) fn new(file: W, cap: Option<usize>) -> TeeWriter<W> {
+        TeeWriter { file, bytes: cap.map(|_| Vec::new()), cap: cap.unwrap_or(0) }
+    }
+}
+impl<W: Write> Write for TeeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        let written = self.file.write(buf)?;
+        let cap = self.cap;
+        if self.bytes.as_ref().is_some_and(|bytes| bytes.len() + written > cap) {
+            self.bytes = None;
         }
-    };
-
-    loop {
-        // If the next two lookahead characters are '--', parsing is finished.
-        {
-            let peeker = reader.fill_buf()?;
-            if peeker.len() >= 2 && &peeker[..2] == b"--" {
-                return Ok(());
-            }
+        if let Some(ref mut bytes) = self.bytes {
+            bytes.extend(&buf[..written]);
         }
+        Ok(written)
+    }
 
-        // Read the line terminator after the boundary
-        let (_, found) = reader.stream_until_token(&lt, &mut buf)?;
-        if ! found { return Err(Error::NoCrLfAfterBoundary); }
-
-        // Read the headers (which end in 2 line terminators)
-        buf.truncate(0); // start fresh
-        let (_, found) = reader.stream_until_token(&ltlt, &mut buf)?;
-        if ! found { return Err(Error::EofInPartHeaders); }
-
-        // Keep the 2 line terminators as httparse will expect it
-        buf.extend(ltlt.iter().cloned());
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.file.flush()
+    }
+}
 
-        // Parse the headers
-        let part_headers = {
-            let mut header_memory = [httparse::EMPTY_HEADER; 4];
-            match httparse::parse_headers(&buf, &mut header_memory) {
-                Ok(httparse::Status::Complete((_, raw_headers))) => {
-                    Headers::from_raw(raw_headers).map_err(|e| From::from(e))
-                },
-                Ok(httparse::Status::Partial) => Err(Error::PartialHeaders),
-                Err(err) => Err(From::from(err)),
-            }?
-        };
+// A writer into a Vec which, once more than `threshold` bytes have been written to it,
+// moves them into a newly created file part and writes everything further there, keeping
+// a copy as the file branch does if `tee_cap` is given.
+struct SpillWriter<'a> {
+    inner: &'a mut Vec<u8>,
+    threshold: usize,
+    headers: &'a Headers,
+    config: &'a FilePartConfig,
+    tee_cap: Option<usize>,
+    spilled: Option<(FilePart, TeeWriter<File>)>,
+    // Set if the file part could not be created
+    error: Option<Error>,
+}
+impl<'a> SpillWriter<'a> {
+    fn new(inner: &'a mut Vec<u8>, threshold: usize, headers: &'a Headers,
+           config: &'a FilePartConfig, tee_cap: Option<usize>) -> SpillWriter<'a>
+    {
+        SpillWriter { inner, threshold, headers, config, tee_cap, spilled: None, error: None }
+    }
 
-        // Check for a nested multipart
-        let nested = {
-            let ct: Option<&ContentType> = part_headers.get();
-            if let Some(ct) = ct {
-                let &ContentType(Mime(ref top_level, _, _)) = ct;
-                *top_level == TopLevel::Multipart
-            } else {
-                false
+    fn spill(&mut self) -> Result<(), Error> {
+        let filepart = FilePart::create_with_config(self.headers.clone(), self.config)?;
+        let mut file = TeeWriter::new(File::create(&filepart.path)?, self.tee_cap);
+        file.write_all(self.inner)?;
+        self.inner.truncate(0);
+        self.spilled = Some((filepart, file));
+        Ok(())
+    }
+}
+impl<'a> Write for SpillWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        if self.spilled.is_none() && self.inner.len() + buf.len() > self.threshold {
+            if let Err(e) = self.spill() {
+                self.error = Some(e);
+                return Err(::std::io::Error::other("could not spill part to a file"));
             }
-        };
-        if nested {
-            // Recurse:
-            let mut inner_nodes: Vec<Node> = Vec::new();
-            inner(reader, &part_headers, &mut inner_nodes, always_use_files)?;
-            nodes.push(Node::Multipart((part_headers, inner_nodes)));
-            continue;
         }
+        match self.spilled {
+            Some((_, ref mut file)) => file.write(buf),
+            None => self.inner.write(buf),
+        }
+    }
 
-        let is_file = always_use_files || {
-            let cd: Option<&ContentDisposition> = part_headers.get();
-            if cd.is_some() {
-                if cd.unwrap().disposition == DispositionType::Attachment {
-                    true
-                } else {
-                    cd.unwrap().parameters.iter().any(|x| match x {
-                        &DispositionParam::Filename(_,_,_) => true,
-                        _ => false
-                    })
-                }
-            } else {
-                false
-            }
-        };
-        if is_file {
-            // Setup a file to capture the contents.
-            let mut filepart = FilePart::create(part_headers)?;
-            let mut file = File::create(filepart.path.clone())?;
-
-            // Stream out the file.
-            let (read, found) = reader.stream_until_token(&lt_boundary, &mut file)?;
-            if ! found { return Err(Error::EofInFile); }
-            filepart.size = Some(read);
-
-            // TODO: Handle Content-Transfer-Encoding.  RFC 7578 section 4.7 deprecated
-            // this, and the authors state "Currently, no deployed implementations that
-            // send such bodies have been discovered", so this is very low priority.
-
-            nodes.push(Node::File(filepart));
-        } else {
-            buf.truncate(0); // start fresh
-            let (_, found) = reader.stream_until_token(&lt_boundary, &mut buf)?;
-            if ! found { return Err(Error::EofInPart); }
-
-            nodes.push(Node::Part(Part {
-                headers: part_headers,
-                body: buf.clone(),
-            }));
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        match self.spilled {
+            Some((_, ref mut file)) => file.flush(),
+            None => Ok(()),
         }
     }
 }
 
-/// Get the `multipart/*` boundary string from `hyper::Headers`
+/// Get the `multipart/*` boundary string from `hyper::Headers`, with the leading `--` of
+/// a delimiter line.  See `get_multipart_boundary_from_headers()` for the bare boundary.
 pub fn get_multipart_boundary(headers: &Headers) -> Result<Vec<u8>, Error> {
+    let boundary = get_multipart_boundary_from_headers(headers)?;
+    let mut delimiter = Vec::with_capacity(2 + boundary.len());
+    delimiter.extend(b"--".iter().cloned());
+    delimiter.extend(boundary);
+    Ok(delimiter)
+}
+
+/// Get the `multipart/*` boundary from the Content-Type in `headers`, as it appears there
+/// (without the leading `--`, the form taken by `write_multipart()`).
+///
+/// This works the same for the headers of a request and for the headers of a nested
+/// `multipart/*` part, which carries its own boundary; the parser uses it at every level.
+///
+/// A quoted boundary is returned without its quotes, and with any backslash-escaped
+/// characters within them unescaped.  A boundary ending in a space is not allowed by
+/// RFC 2046, and returns `Error::InvalidBoundary`; an empty one is no boundary at all,
+/// and returns `Error::BoundaryNotSpecified`.
+pub fn get_multipart_boundary_from_headers(headers: &Headers) -> Result<Vec<u8>, Error> {
     // Verify that the request is 'Content-Type: multipart/*'.
-    let ct: &ContentType = match headers.get() {
-        Some(ct) => ct,
+    let Mime(top_level, _, params) = match content_type(headers) {
+        Some(mime) => mime,
         None => return Err(Error::NoRequestContentType),
     };
-    let ContentType(ref mime) = *ct;
-    let Mime(ref top_level, _, ref params) = *mime;
 
-    if *top_level != TopLevel::Multipart {
+    if top_level != TopLevel::Multipart {
         return Err(Error::NotMultipart);
     }
 
-    for &(ref attr, ref val) in params.iter() {
-        if let (&Attr::Boundary, &Value::Ext(ref val)) = (attr, val) {
-            let mut boundary = Vec::with_capacity(2 + val.len());
-            boundary.extend(b"--".iter().cloned());
-            boundary.extend(val.as_bytes());
-            return Ok(boundary);
-        }
+    // The raw value is preferred, as the mime crate neither unescapes quoted boundaries
+    // nor quotes them again when formatting.
+    let raw = headers.get_raw("Content-Type")
+        .and_then(|raw| raw.first())
+        .and_then(|raw| boundary::boundary_param(&String::from_utf8_lossy(raw)));
+    let boundary = raw.or_else(|| params.iter().find_map(|param| match *param {
+        (Attr::Boundary, Value::Ext(ref val)) => Some(val.as_bytes().to_vec()),
+        _ => None,
+    }));
+    match boundary {
+        Some(ref boundary) if boundary.iter().all(|&b| b == b' ' || b == b'\t') => {
+            Err(Error::BoundaryNotSpecified)
+        },
+        // Such a boundary is not allowed, as padding after a delimiter is ignored
+        Some(ref boundary) if boundary.last() == Some(&b' ') => Err(Error::InvalidBoundary),
+        Some(boundary) => Ok(boundary),
+        None => Err(Error::BoundaryNotSpecified),
     }
-    Err(Error::BoundaryNotSpecified)
 }
 
-#[inline]
-fn get_content_disposition_filename(cd: &ContentDisposition) -> Result<Option<String>, Error> {
-    if let Some(&DispositionParam::Filename(ref charset, _, ref bytes)) =
-        cd.parameters.iter().find(|&x| match *x {
-            DispositionParam::Filename(_,_,_) => true,
-            _ => false,
-        })
-    {
-        match charset_decode(charset, bytes) {
-            Ok(filename) => Ok(Some(filename)),
-            Err(e) => Err(Error::Decoding(e)),
-        }
-    } else {
-        Ok(None)
+// Describe a part for error messages, by its Content-Disposition name if it has one,
+// otherwise by its position within the enclosing multipart.
+fn describe_part(headers: &Headers, index: usize) -> String {
+    match get_content_disposition_name(headers) {
+        Some(name) => format!("part \"{}\"", name),
+        None => format!("part #{}", index),
     }
 }
 
+// The `name` parameter of the Content-Disposition header, if there is one.
+fn get_content_disposition_name(headers: &Headers) -> Option<String> {
+    disposition::name(headers, &Charset::Ext("UTF-8".to_owned()))
+}
+
 // This decodes bytes encoded according to a hyper::header::Charset encoding, using the
 // rust-encoding crate.  Only supports encodings defined in both crates.
 fn charset_decode(charset: &Charset, bytes: &[u8]) -> Result<String, Cow<'static, str>> {
@@ -386,21 +1853,115 @@ fn charset_decode(charset: &Charset, bytes: &[u8]) -> Result<String, Cow<'static
         Charset::Gb2312 => return Err("Gb2312 is not supported".into()),
         Charset::Big5 => all::BIG5_2003.decode(bytes, DecoderTrap::Strict)?,
         Charset::Koi8_R => all::KOI8_R.decode(bytes, DecoderTrap::Strict)?,
-        Charset::Ext(ref s) => match &**s {
-            "UTF-8" => all::UTF_8.decode(bytes, DecoderTrap::Strict)?,
-            _ => return Err("Encoding is not supported".into()),
-        },
+        Charset::Ext(ref s) if s.eq_ignore_ascii_case("UTF-8") || s.eq_ignore_ascii_case("UTF8") =>
+            all::UTF_8.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Ext(ref s) if s.eq_ignore_ascii_case("WINDOWS-1252") ||
+            s.eq_ignore_ascii_case("CP1252") =>
+            all::WINDOWS_1252.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Ext(ref s) if s.eq_ignore_ascii_case("LATIN1") =>
+            all::ISO_8859_1.decode(bytes, DecoderTrap::Strict)?,
+        Charset::Ext(ref s) => return Err(format!("charset {} is not supported", s).into()),
     })
 }
 
 /// Generate a valid multipart boundary, statistically unlikely to be found within
 /// the content of the parts.
+///
+/// The boundary is 68 characters drawn from the operating system's secure random number
+/// generator, so it cannot be guessed, and within the 70 character limit which
+/// `validate_boundary()` enforces.  See `generate_boundary_with_len()`.
 pub fn generate_boundary() -> Vec<u8> {
-    TextNonce::sized(68).unwrap().into_string().into_bytes().iter().map(|&ch| {
-        if ch==b'=' { return b'-'; }
-        else if ch==b'/' { return b'.'; }
-        else { return ch; }
-    }).collect()
+    generate_boundary_with_len(68)
+}
+
+/// As `generate_boundary()`, but `len` characters long.  Each character carries 6 bits
+/// of randomness, and is a letter, digit, `-` or `_`, so the boundary never needs quoting
+/// in a Content-Type header.
+///
+/// # Panics
+///
+/// If `len` is 0 or more than 70, the longest boundary RFC 2046 allows, or if the
+/// operating system's random number generator fails.
+pub fn generate_boundary_with_len(len: usize) -> Vec<u8> {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    assert!(len > 0 && len <= 70, "boundary length {} is not between 1 and 70", len);
+    let mut boundary = vec![0; len];
+    getrandom::fill(&mut boundary).expect("no random number generator available");
+    for b in boundary.iter_mut() {
+        *b = ALPHABET[(*b & 0x3f) as usize];
+    }
+    boundary
+}
+
+// Check that no header name or value has a CR or LF, which would end the header early,
+// letting the rest of it be read as headers of its own.
+pub(crate) fn check_headers(headers: &Headers) -> Result<(), Error> {
+    let invalid = |bytes: &[u8]| bytes.iter().any(|&b| b == b'\r' || b == b'\n');
+    for header in headers.iter() {
+        let name = header.name();
+        let bad_value = match headers.get_raw(name) {
+            Some(values) => values.iter().any(|value| invalid(value)),
+            None => invalid(header.value_string().as_bytes()),
+        };
+        if invalid(name.as_bytes()) || bad_value {
+            return Err(Error::InvalidHeaderValue(name.to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// Write out headers, each followed by the line terminator `lt`, returning the count of
+// bytes written.  A header given several times is written once for each value, as it was
+// received, rather than as one line of comma-separated values.
+pub(crate) fn write_headers<S: Write>(stream: &mut S, headers: &Headers, lt: &[u8])
+                                      -> ::std::io::Result<usize>
+{
+    let mut count = 0;
+    for_each_header_value(headers, |name, value| {
+        count += write_header(stream, name, value, lt)?;
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+// As `write_headers()`, but with each piece of each header written as a chunk.
+fn write_headers_chunked<S: Write>(stream: &mut S, headers: &Headers)
+                                   -> ::std::io::Result<()>
+{
+    for_each_header_value(headers, |name, value| {
+        write_chunk(stream, name.as_bytes())?;
+        write_chunk(stream, b": ")?;
+        write_chunk(stream, value)?;
+        write_chunk(stream, b"\r\n")
+    })
+}
+
+// Call `f` with the name and value of every header, once for each value of a header given
+// several times.
+fn for_each_header_value<F>(headers: &Headers, mut f: F) -> ::std::io::Result<()>
+    where F: FnMut(&str, &[u8]) -> ::std::io::Result<()>
+{
+    for header in headers.iter() {
+        let name = header.name();
+        match headers.get_raw(name) {
+            Some(values) => for value in values {
+                f(name, value)?;
+            },
+            None => f(name, header.value_string().as_bytes())?,
+        }
+    }
+    Ok(())
+}
+
+fn write_header<S: Write>(stream: &mut S, name: &str, value: &[u8], lt: &[u8])
+                          -> ::std::io::Result<usize>
+{
+    let mut count = stream.write_all_count(name.as_bytes())?;
+    count += stream.write_all_count(b": ")?;
+    count += stream.write_all_count(value)?;
+    count += stream.write_all_count(lt)?;
+    Ok(count)
 }
 
 // Convenience method, like write_all(), but returns the count of bytes written.
@@ -419,10 +1980,161 @@ impl<T: Write> WriteAllCount for T {
 /// given.  Top-level headers are NOT included in this stream; the caller must send
 /// those prior to calling write_multipart().
 /// Returns the number of bytes written, or an error.
+///
+/// Before anything is written, the boundary is checked with `validate_boundary()` and the
+/// parts (including the files of file parts) are scanned for it.  If any part contains
+/// the boundary of a multipart enclosing it, `Error::BoundaryCollision` is returned, as
+/// the body would otherwise not parse back into the same parts.
+/// Any header with a CR or LF in its name or value is refused with
+/// `Error::InvalidHeaderValue`, so that a value taken from user input cannot add headers
+/// (or a body) of its own.
+///
+/// The files of file parts are streamed from disk rather than read into memory.  If a file
+/// part gives a `size` which the file on disk does not match, `Error::Io` is returned.
 pub fn write_multipart<S: Write>(
     stream: &mut S,
-    boundary: &Vec<u8>,
-    nodes: &Vec<Node>)
+    boundary: &[u8],
+    nodes: &[Node])
+    -> Result<usize, Error>
+{
+    boundary::check_nodes(boundary, nodes)?;
+    write_multipart_inner(stream, boundary, nodes, b"\r\n")
+}
+
+/// As `write_multipart()`, but with the line terminator and preamble taken from
+/// `options`.  Returns `Error::BoundaryCollision` if the preamble contains the boundary.
+pub fn write_multipart_with_options<S: Write>(
+    stream: &mut S,
+    boundary: &[u8],
+    nodes: &[Node],
+    options: &WriteOptions)
+    -> Result<usize, Error>
+{
+    boundary::check_nodes(boundary, nodes)?;
+    let lt = options.line_ending.as_bytes();
+    let mut count = 0;
+    if let Some(ref preamble) = options.preamble {
+        let delimiter = [&b"--"[..], boundary].concat();
+        if stream::find_bytes(preamble, &delimiter).is_some() {
+            return Err(Error::BoundaryCollision);
+        }
+        count += stream.write_all_count(preamble)?;
+        count += stream.write_all_count(lt)?;
+    }
+    count += write_multipart_inner(stream, boundary, nodes, lt)?;
+    Ok(count)
+}
+
+/// As `write_multipart()`, but returning the body in memory rather than writing it to a
+/// stream.  File parts are read from disk into it.
+pub fn multipart_to_vec(boundary: &[u8], nodes: &[Node]) -> Result<Vec<u8>, Error> {
+    let mut body: Vec<u8> = Vec::new();
+    write_multipart(&mut body, boundary, nodes)?;
+    Ok(body)
+}
+
+/// Write parsed `nodes` back out as a body delimited by `boundary`, as a proxy passing a
+/// body on would, returning it in memory.  This is `multipart_to_vec()`, documented for
+/// this use.
+///
+/// Each part's headers are written in the order hyper keeps them, which is the order they
+/// were received, with their names cased and their values exactly as received; bodies
+/// are written as they were parsed.  So a body parsed with the default options, with CRLF
+/// line terminators throughout, comes back byte for byte, except for these:
+///
+/// * What precedes the first delimiter and follows the closing delimiter is not kept in
+///   `nodes`, so is dropped (see `read_multipart_full()` to keep it), and the body ends
+///   right after the closing delimiter.
+/// * Whitespace between a delimiter and its line terminator is dropped, and with
+///   `ReadOptions::lenient`, LF line terminators become CRLF.
+/// * Whitespace around a header value is dropped, and a header given several times is
+///   written with all its values together, where it first appeared.  Only the first of
+///   several Content-Type headers is kept by the parser.
+/// * A part which was transfer decoded or decompressed while parsing is written decoded,
+///   while still giving its original Content-Transfer-Encoding or Content-Encoding, so do
+///   not parse with those options a body which is to be passed on.
+///
+/// Nested multiparts are written with the boundaries their own headers give.
+pub fn reserialize(nodes: &[Node], boundary: &[u8]) -> Result<Vec<u8>, Error> {
+    multipart_to_vec(boundary, nodes)
+}
+
+/// As `write_multipart()`, but flushing `stream` each time roughly `flush_every` more bytes
+/// have been written to it (after every write, if `flush_every` is 0), and once more
+/// before returning.  Errors from flushing are returned as `Error::Io`.
+///
+/// For a socket, this hands the body on as it is written rather than leaving it in a
+/// buffer, so that a slow receiver holds the writer back.
+pub fn write_multipart_buffered<S: Write>(
+    stream: &mut S,
+    boundary: &[u8],
+    nodes: &[Node],
+    flush_every: usize)
+    -> Result<usize, Error>
+{
+    boundary::check_nodes(boundary, nodes)?;
+    let count = {
+        let mut flushing = FlushingWriter { inner: &mut *stream, flush_every, unflushed: 0 };
+        write_multipart_inner(&mut flushing, boundary, nodes, b"\r\n")?
+    };
+    stream.flush()?;
+    Ok(count)
+}
+
+// A writer which flushes once at least `flush_every` bytes have been written since it
+// last did.
+struct FlushingWriter<'a, W: Write> {
+    inner: &'a mut W,
+    flush_every: usize,
+    unflushed: usize,
+}
+impl<'a, W: Write> Write for FlushingWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.unflushed += written;
+        if self.unflushed >= self.flush_every {
+            self.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> ::std::io::Result<()> {
+        self.unflushed = 0;
+        self.inner.flush()
+    }
+}
+
+/// As `write_multipart()`, but using a newly generated boundary, which is returned (for the
+/// caller's Content-Type header) along with the number of bytes written.
+///
+/// A boundary is generated again if the first happens to occur within the parts, so this
+/// does not return `Error::BoundaryCollision` unless a nested multipart's own boundary
+/// collides.
+pub fn write_multipart_generate<S: Write>(
+    stream: &mut S,
+    nodes: &[Node])
+    -> Result<(Vec<u8>, usize), Error>
+{
+    const ATTEMPTS: usize = 8;
+    let mut attempt = 0;
+    let boundary = loop {
+        let boundary = generate_boundary();
+        attempt += 1;
+        match boundary::check_nodes(&boundary, nodes) {
+            Ok(()) => break boundary,
+            Err(Error::BoundaryCollision) if attempt < ATTEMPTS => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    let count = write_multipart_inner(stream, &boundary, nodes, b"\r\n")?;
+    Ok((boundary, count))
+}
+
+fn write_multipart_inner<S: Write>(
+    stream: &mut S,
+    boundary: &[u8],
+    nodes: &[Node],
+    lt: &[u8])
     -> Result<usize, Error>
 {
     let mut count: usize = 0;
@@ -430,68 +2142,57 @@ pub fn write_multipart<S: Write>(
     for node in nodes {
         // write a boundary
         count += stream.write_all_count(b"--")?;
-        count += stream.write_all_count(&boundary)?;
-        count += stream.write_all_count(b"\r\n")?;
+        count += stream.write_all_count(boundary)?;
+        count += stream.write_all_count(lt)?;
 
         match node {
-            &Node::Part(ref part) => {
+            Node::Part(part) => {
                 // write the part's headers
-                for header in part.headers.iter() {
-                    count += stream.write_all_count(header.name().as_bytes())?;
-                    count += stream.write_all_count(b": ")?;
-                    count += stream.write_all_count(header.value_string().as_bytes())?;
-                    count += stream.write_all_count(b"\r\n")?;
-                }
+                count += write_headers(stream, &part.headers, lt)?;
 
                 // write the blank line
-                count += stream.write_all_count(b"\r\n")?;
+                count += stream.write_all_count(lt)?;
 
                 // Write the part's content
                 count += stream.write_all_count(&part.body)?;
             },
-            &Node::File(ref filepart) => {
+            Node::File(filepart) => {
                 // write the part's headers
-                for header in filepart.headers.iter() {
-                    count += stream.write_all_count(header.name().as_bytes())?;
-                    count += stream.write_all_count(b": ")?;
-                    count += stream.write_all_count(header.value_string().as_bytes())?;
-                    count += stream.write_all_count(b"\r\n")?;
-                }
+                count += write_headers(stream, &filepart.headers, lt)?;
 
                 // write the blank line
-                count += stream.write_all_count(b"\r\n")?;
+                count += stream.write_all_count(lt)?;
 
-                // Write out the files's content
-                let mut file = File::open(&filepart.path)?;
-                count += std::io::copy(&mut file, stream)? as usize;
+                // Stream out the files's content, without holding it in memory
+                let (mut file, len) = open_file_part(filepart)?;
+                let copied = std::io::copy(&mut file, stream)?;
+                if copied != len {
+                    return Err(size_mismatch(filepart, len, copied));
+                }
+                count += copied as usize;
             },
-            &Node::Multipart((ref headers, ref subnodes)) => {
+            Node::Multipart((headers, subnodes)) => {
                 // Get boundary
-                let boundary = get_multipart_boundary(headers)?;
+                let boundary = get_multipart_boundary_from_headers(headers)?;
 
                 // write the multipart headers
-                for header in headers.iter() {
-                    count += stream.write_all_count(header.name().as_bytes())?;
-                    count += stream.write_all_count(b": ")?;
-                    count += stream.write_all_count(header.value_string().as_bytes())?;
-                    count += stream.write_all_count(b"\r\n")?;
-                }
+                count += write_headers(stream, headers, lt)?;
 
                 // write the blank line
-                count += stream.write_all_count(b"\r\n")?;
+                count += stream.write_all_count(lt)?;
 
                 // Recurse
-                count += write_multipart(stream, &boundary, &subnodes)?;
+                count += write_multipart_inner(stream, &boundary, subnodes, lt)?;
             },
         }
 
         // write a line terminator
-        count += stream.write_all_count(b"\r\n")?;
+        count += stream.write_all_count(lt)?;
     }
 
     // write a final boundary
     count += stream.write_all_count(b"--")?;
-    count += stream.write_all_count(&boundary)?;
+    count += stream.write_all_count(boundary)?;
     count += stream.write_all_count(b"--")?;
 
     Ok(count)
@@ -507,30 +2208,62 @@ pub fn write_chunk<S: Write>(
     Ok(())
 }
 
+// Open the file of a file part for writing out, returning it with its length.  If the
+// file part gives a size, the file must be that size.
+fn open_file_part(filepart: &FilePart) -> Result<(File, u64), Error> {
+    let file = File::open(&filepart.path)?;
+    let len = file.metadata()?.len();
+    if let Some(size) = filepart.size {
+        if size as u64 != len {
+            return Err(size_mismatch(filepart, size as u64, len));
+        }
+    }
+    Ok((file, len))
+}
+
+fn size_mismatch(filepart: &FilePart, expected: u64, actual: u64) -> Error {
+    Error::Io(::std::io::Error::new(
+        ::std::io::ErrorKind::InvalidData,
+        format!("file part {} is {} bytes, expected {}", filepart.path.display(), actual,
+                expected)))
+}
+
 /// Stream a multipart body to the output `stream` given, made up of the `parts`
 /// given, using Tranfer-Encoding: Chunked.  Top-level headers are NOT included in this
 /// stream; the caller must send those prior to calling write_multipart_chunked().
+///
+/// The boundary and parts are checked as described for `write_multipart()`.
 pub fn write_multipart_chunked<S: Write>(
     stream: &mut S,
-    boundary: &Vec<u8>,
-    nodes: &Vec<Node>)
+    boundary: &[u8],
+    nodes: &[Node])
+    -> Result<(), Error>
+{
+    boundary::check_nodes(boundary, nodes)?;
+    write_multipart_chunked_inner(stream, boundary, nodes)?;
+
+    // Write an empty chunk to signal the end of the body
+    write_chunk(stream, b"")?;
+
+    Ok(())
+}
+
+fn write_multipart_chunked_inner<S: Write>(
+    stream: &mut S,
+    boundary: &[u8],
+    nodes: &[Node])
     -> Result<(), Error>
 {
     for node in nodes {
         // write a boundary
         write_chunk(stream, b"--")?;
-        write_chunk(stream, &boundary)?;
+        write_chunk(stream, boundary)?;
         write_chunk(stream, b"\r\n")?;
 
         match node {
-            &Node::Part(ref part) => {
+            Node::Part(part) => {
                 // write the part's headers
-                for header in part.headers.iter() {
-                    write_chunk(stream, header.name().as_bytes())?;
-                    write_chunk(stream, b": ")?;
-                    write_chunk(stream, header.value_string().as_bytes())?;
-                    write_chunk(stream, b"\r\n")?;
-                }
+                write_headers_chunked(stream, &part.headers)?;
 
                 // write the blank line
                 write_chunk(stream, b"\r\n")?;
@@ -538,44 +2271,36 @@ pub fn write_multipart_chunked<S: Write>(
                 // Write the part's content
                 write_chunk(stream, &part.body)?;
             },
-            &Node::File(ref filepart) => {
+            Node::File(filepart) => {
                 // write the part's headers
-                for header in filepart.headers.iter() {
-                    write_chunk(stream, header.name().as_bytes())?;
-                    write_chunk(stream, b": ")?;
-                    write_chunk(stream, header.value_string().as_bytes())?;
-                    write_chunk(stream, b"\r\n")?;
-                }
+                write_headers_chunked(stream, &filepart.headers)?;
 
                 // write the blank line
                 write_chunk(stream, b"\r\n")?;
 
                 // Write out the files's length
-                let metadata = std::fs::metadata(&filepart.path)?;
-                write!(stream, "{:x}\r\n", metadata.len())?;
+                let (mut file, len) = open_file_part(filepart)?;
+                write!(stream, "{:x}\r\n", len)?;
 
                 // Write out the file's content
-                let mut file = File::open(&filepart.path)?;
-                std::io::copy(&mut file, stream)? as usize;
-                stream.write(b"\r\n")?;
+                let copied = std::io::copy(&mut file, stream)?;
+                if copied != len {
+                    return Err(size_mismatch(filepart, len, copied));
+                }
+                stream.write_all(b"\r\n")?;
             },
-            &Node::Multipart((ref headers, ref subnodes)) => {
+            Node::Multipart((headers, subnodes)) => {
                 // Get boundary
-                let boundary = get_multipart_boundary(headers)?;
+                let boundary = get_multipart_boundary_from_headers(headers)?;
 
                 // write the multipart headers
-                for header in headers.iter() {
-                    write_chunk(stream, header.name().as_bytes())?;
-                    write_chunk(stream, b": ")?;
-                    write_chunk(stream, header.value_string().as_bytes())?;
-                    write_chunk(stream, b"\r\n")?;
-                }
+                write_headers_chunked(stream, headers)?;
 
                 // write the blank line
                 write_chunk(stream, b"\r\n")?;
 
                 // Recurse
-                write_multipart_chunked(stream, &boundary, &subnodes)?;
+                write_multipart_chunked_inner(stream, &boundary, subnodes)?;
             },
         }
 
@@ -585,11 +2310,8 @@ pub fn write_multipart_chunked<S: Write>(
 
     // write a final boundary
     write_chunk(stream, b"--")?;
-    write_chunk(stream, &boundary)?;
+    write_chunk(stream, boundary)?;
     write_chunk(stream, b"--")?;
 
-    // Write an empty chunk to signal the end of the body
-    write_chunk(stream, b"")?;
-
     Ok(())
 }
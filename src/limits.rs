@@ -0,0 +1,71 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fmt::{self, Display};
+
+/// Limits on what the parser will accept, protecting against bodies crafted to exhaust
+/// memory or disk.
+///
+/// The `Default` limits are generous, and are what `read_multipart()` and
+/// `read_multipart_body()` use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// The maximum number of parts, counting the parts of nested multiparts as well as
+    /// the nested multiparts themselves.
+    pub max_parts: usize,
+    /// The maximum nesting depth of `multipart/*` parts within the body.  The parser
+    /// takes a stack frame per level, so this also bounds its stack use: raise it with
+    /// care, and only when parsing on a thread with a stack to match.
+    pub max_depth: usize,
+    /// The maximum size in bytes of a part kept in memory.  Parts streamed to files are
+    /// not subject to this limit.
+    pub max_part_size: usize,
+    /// The maximum number of headers a part may have.
+    pub max_headers: usize,
+    /// The maximum size in bytes of a part's headers, not counting the blank line which
+    /// ends them.
+    pub max_header_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_parts: 10_000,
+            max_depth: 32,
+            max_part_size: 64 * 1024 * 1024,
+            max_headers: 32,
+            max_header_size: 16 * 1024,
+        }
+    }
+}
+
+/// Which of the `Limits` was exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    /// `Limits::max_parts`
+    Parts,
+    /// `Limits::max_depth`
+    Depth,
+    /// `Limits::max_part_size`
+    PartSize,
+    /// `Limits::max_headers`
+    Headers,
+    /// `Limits::max_header_size`
+    HeaderSize,
+}
+
+impl Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            LimitKind::Parts => "too many parts",
+            LimitKind::Depth => "multipart nesting too deep",
+            LimitKind::PartSize => "part too large",
+            LimitKind::Headers => "too many part headers",
+            LimitKind::HeaderSize => "part headers too large",
+        })
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! The buffered reader the parser reads the stream through.
+
+use std::io::{self, BufRead, Read, Write};
+
+use super::boundary::{BoundaryFinder, Scan};
+
+/// A buffered reader, as `std::io::BufReader`, which can also look ahead by a few bytes
+/// where they fall across the end of its buffer.  `BufReader::fill_buf()` returns whatever
+/// remains buffered, which may be a single byte however large the buffer is.
+pub(crate) struct LookaheadReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    filled: usize,
+    // How much has been consumed since the start of the stream
+    consumed: usize,
+}
+
+impl<R: Read> LookaheadReader<R> {
+    /// Create a reader with a buffer of `capacity` bytes (or one byte if `capacity` is 0),
+    /// which is reused for the life of the reader.
+    pub(crate) fn with_capacity(capacity: usize, inner: R) -> LookaheadReader<R> {
+        LookaheadReader { inner, buf: vec![0; capacity.max(1)], pos: 0, filled: 0, consumed: 0 }
+    }
+
+    /// The next `count` bytes, without consuming them.  Fewer are returned only if the
+    /// stream ends first.
+    pub(crate) fn peek(&mut self, count: usize) -> io::Result<&[u8]> {
+        if self.filled - self.pos < count {
+            self.buf.copy_within(self.pos..self.filled, 0);
+            self.filled -= self.pos;
+            self.pos = 0;
+            if self.buf.len() < count {
+                self.buf.resize(count, 0);
+            }
+            while self.filled < count {
+                let read = self.inner.read(&mut self.buf[self.filled..])?;
+                if read == 0 {
+                    break;
+                }
+                self.filled += read;
+            }
+        }
+        let end = self.filled.min(self.pos + count);
+        Ok(&self.buf[self.pos..end])
+    }
+
+    /// The offset from the start of the stream of the next byte to be read.
+    pub(crate) fn position(&self) -> usize {
+        self.consumed
+    }
+
+    /// Write everything up to the next occurrence of `token` to `out`, and consume it along
+    /// with the token.  Returns how many bytes were written, and whether the token was
+    /// found; if the stream ends first, all that remained has been written.
+    pub(crate) fn stream_until_token<W: Write>(&mut self, token: &[u8], out: &mut W)
+                                               -> io::Result<(usize, bool)>
+    {
+        self.stream_until(&BoundaryFinder::with_delimiter(token), out)
+    }
+
+    /// As `stream_until_token()`, for the delimiter of `finder`.
+    pub(crate) fn stream_until<W: Write>(&mut self, finder: &BoundaryFinder, out: &mut W)
+                                         -> io::Result<(usize, bool)>
+    {
+        let len = finder.delimiter().len();
+        let mut written = 0;
+        loop {
+            // Buffer at least a delimiter's length, so that one starting in what was held
+            // back from the last search is found whole
+            match self.peek(len) {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            let available = &self.buf[self.pos..self.filled];
+            match finder.scan(available) {
+                Scan::Found(at) => {
+                    out.write_all(&available[..at])?;
+                    self.consume(at + len);
+                    return Ok((written + at, true));
+                },
+                Scan::Clear(_) if available.len() < len => {
+                    // The stream has ended
+                    let rest = available.len();
+                    out.write_all(available)?;
+                    self.consume(rest);
+                    return Ok((written + rest, false));
+                },
+                Scan::Clear(clear) => {
+                    // What might be the start of a delimiter running past the buffer is held
+                    // back
+                    out.write_all(&available[..clear])?;
+                    self.consume(clear);
+                    written += clear;
+                },
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for LookaheadReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Large reads with nothing buffered bypass the buffer, as with `BufReader`
+        if self.pos == self.filled && out.len() >= self.buf.len() {
+            let read = self.inner.read(out)?;
+            self.consumed += read;
+            return Ok(read);
+        }
+        let read = {
+            let mut available = self.fill_buf()?;
+            available.read(out)?
+        };
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl<R: Read> BufRead for LookaheadReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        let pos = self.filled.min(self.pos + amount);
+        self.consumed += pos - self.pos;
+        self.pos = pos;
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::path::PathBuf;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use super::Limits;
+
+/// Everything which controls how a body is parsed, for `read_multipart_with_options()` and
+/// `read_multipart_body_with_options()`.
+///
+/// The `Default` options are those used by `read_multipart()` and `read_multipart_body()`
+/// with both flags false.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReadOptions {
+    /// Stream every part to a file, not only those which look like file uploads.
+    pub always_use_files: bool,
+    /// Keep every part in memory, file uploads included, so that nothing is written to
+    /// disk.  This takes precedence over `always_use_files` and `spill_threshold`.
+    pub in_memory_only: bool,
+    /// Decode in-memory parts with a `Content-Transfer-Encoding` of `base64` or
+    /// `quoted-printable`.
+    pub decode_transfer_encoding: bool,
+    /// When decoding transfer encodings, check that parts with a `Content-Transfer-Encoding`
+    /// of `7bit` contain no 8-bit bytes, returning `Error::Decoding` if one does.
+    pub validate_7bit: bool,
+    /// Decompress in-memory parts with a `Content-Encoding` of `gzip` or `deflate`, after
+    /// any transfer decoding.  Parts streamed to files are left compressed.  Requires the
+    /// `flate2` feature.
+    #[cfg(feature = "flate2")]
+    pub decode_content_encoding: bool,
+    /// If set, parts which would be kept in memory are moved to a file part as soon as
+    /// they grow beyond this many bytes.
+    pub spill_threshold: Option<usize>,
+    /// Accept spaces and tabs after a boundary delimiter, and lone LF line terminators
+    /// wherever CRLF is expected.  Without this, the line terminator following the first
+    /// delimiter must immediately follow it, and sets the line terminator used throughout.
+    /// Ignored if `strict_crlf` is set.
+    pub lenient: bool,
+    /// If set, each part streamed to a file is also kept in memory, in `FilePart::bytes`,
+    /// so long as it is no larger than this many bytes.  Larger parts are only on disk,
+    /// with `bytes` left as `None`.  This includes parts which spill to a file under
+    /// `spill_threshold`.
+    pub tee_file_parts: Option<usize>,
+    /// Compute the SHA-256 digest of each part streamed to a file as it is written, and
+    /// store it as lowercase hex in `FilePart::hash`.  This includes parts which spill to a
+    /// file under `spill_threshold`.  Requires the `sha2` feature.
+    #[cfg(feature = "sha2")]
+    pub hash_file_parts: bool,
+    /// Check each part with a Content-Length header against the length of its body (as
+    /// received, before any transfer decoding), returning `Error::LengthMismatch` for a
+    /// part whose lengths differ.  A mismatch suggests a truncated body, or a delimiter
+    /// injected into a part's content.
+    pub check_part_lengths: bool,
+    /// Insist on CRLF line terminators around every delimiter, as RFC 2046 requires,
+    /// returning `Error::NoCrLfAfterBoundary` for a bare CR or LF.  Only spaces and tabs
+    /// may come between a delimiter and its CRLF, and no part may hold the boundary after
+    /// a bare CR or LF, as a parser which accepts those as line terminators would split
+    /// the part there.  This hardens against bodies crafted to be read differently by
+    /// different parsers.  It is the opposite of `lenient`, which is ignored when this
+    /// is set.
+    pub strict_crlf: bool,
+    /// Return `Error::Decoding` for a part with several Content-Type headers which differ.
+    /// Otherwise the first of them is used, and the rest ignored.
+    pub reject_conflicting_content_type: bool,
+    /// Return `Error::MissingContentDisposition` for a part of the body without a
+    /// Content-Disposition header, as every field of an HTML form must have one.  Only the
+    /// parts of the body itself are checked, not those within a nested multipart.  MIME
+    /// messages, such as email, need not give their parts one.
+    pub require_disposition: bool,
+    /// The size of the buffer the stream is read through, or if `None` the default of 4096
+    /// bytes.  See `ReadOptions::with_buffer_capacity()`.
+    pub buffer_capacity: Option<usize>,
+    /// Limits on what will be accepted.
+    pub limits: Limits,
+    /// Where and under what names file parts are written.
+    pub file_parts: FilePartConfig,
+}
+
+impl ReadOptions {
+    /// The default options, but reading the stream through a buffer of `capacity` bytes.
+    /// A larger buffer means fewer reads from the stream; the parse is the same whatever
+    /// its size.
+    pub fn with_buffer_capacity(capacity: usize) -> ReadOptions {
+        ReadOptions { buffer_capacity: Some(capacity), ..ReadOptions::default() }
+    }
+
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity.unwrap_or(4096)
+    }
+}
+
+/// Everything which controls how a body is written, for `write_multipart_with_options()`.
+///
+/// The `Default` options are those used by `write_multipart()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteOptions {
+    /// The line terminator written after each delimiter and header, and around each part.
+    pub line_ending: LineEnding,
+    /// If set, written before the first delimiter.  Mail clients show it to users whose
+    /// client cannot display MIME messages; HTTP receivers ignore it.
+    pub preamble: Option<Vec<u8>>,
+}
+
+/// The line terminator to write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// CRLF, as RFC 2046 requires
+    #[default]
+    CrLf,
+    /// A lone LF, for receivers (and golden files) which expect it.  The parser accepts
+    /// this, but other receivers may not.
+    Lf,
+}
+impl LineEnding {
+    pub(crate) fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::CrLf => b"\r\n",
+            LineEnding::Lf => b"\n",
+        }
+    }
+}
+
+/// Where the parser puts the files which file parts are streamed to.
+///
+/// Each file is created within its own new directory, so that files never overwrite one
+/// another even when client-supplied names are used.  Both the file and that directory
+/// are removed when the `FilePart` drops, unless `do_not_delete_on_drop()` is called.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilePartConfig {
+    /// The directory in which the per-file directories are created.  If `None`, the
+    /// system temporary directory is used.
+    pub directory: Option<PathBuf>,
+    /// The prefix of the per-file directory names, and of generated file names.
+    pub prefix: String,
+    /// Name files after the filename supplied in the part's Content-Disposition, rather
+    /// than generating a random name.  The supplied name is reduced to its final path
+    /// component, so it cannot point outside of the directory, and on every platform is
+    /// made valid for Windows: forbidden characters become `_`, and device names such as
+    /// `CON` are prefixed with `_`.  Control characters are dropped, the name is put in
+    /// Unicode normalization form C, and one longer than 255 bytes is shortened, keeping its
+    /// extension.  Parts whose filename is missing or unusable get a generated name.
+    pub preserve_filename: bool,
+}
+
+impl Default for FilePartConfig {
+    fn default() -> FilePartConfig {
+        FilePartConfig {
+            directory: None,
+            prefix: "mime_multipart".to_owned(),
+            preserve_filename: false,
+        }
+    }
+}
+
+// The longest file name, in bytes, which the common filesystems all accept.
+const MAX_FILENAME_BYTES: usize = 255;
+
+// Reduce a client-supplied filename to something safe to create within a directory on
+// any platform: its final component after either kind of path separator, in Unicode
+// normalization form C (so that it is spelled the same whichever form the client sent),
+// without control characters, with the characters Windows forbids replaced by `_`, and
+// without trailing dots or spaces (which Windows strips).  Windows device names such as
+// `CON` and `nul.txt` are prefixed with `_`, and names longer than `MAX_FILENAME_BYTES`
+// are shortened.  Returns `None` if nothing usable remains.
+pub(crate) fn sanitize_filename(filename: &str) -> Option<String> {
+    let last = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = last.nfc()
+        .filter(|c| !c.is_control())
+        .map(|c| if "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+    let cleaned = truncate_filename(cleaned.trim().trim_end_matches(['.', ' ']));
+    if cleaned.is_empty() {
+        None
+    } else if is_reserved_name(&cleaned) {
+        Some(format!("_{}", cleaned))
+    } else {
+        Some(cleaned)
+    }
+}
+
+// Shorten `filename` to at most `MAX_FILENAME_BYTES`, keeping its extension if it has a
+// short one.  It is cut between characters, and not before a combining mark, so that no
+// character loses its accent.
+fn truncate_filename(filename: &str) -> String {
+    if filename.len() <= MAX_FILENAME_BYTES {
+        return filename.to_owned();
+    }
+    let extension = match filename.rfind('.') {
+        Some(at) if filename.len() - at <= 16 => &filename[at..],
+        _ => "",
+    };
+    let stem = &filename[..filename.len() - extension.len()];
+    let mut end = MAX_FILENAME_BYTES - extension.len();
+    while end > 0 && (!stem.is_char_boundary(end)
+                      || stem[end..].chars().next().is_some_and(is_combining_mark)) {
+        end -= 1;
+    }
+    format!("{}{}", stem[..end].trim_end_matches(['.', ' ']), extension)
+}
+
+// Whether Windows takes `filename` to be a device, whatever its extension.
+fn is_reserved_name(filename: &str) -> bool {
+    let stem = filename.split('.').next().unwrap_or("").trim_end().to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT")) && stem.len() == 4
+                && stem.as_bytes()[3].is_ascii_digit() && stem.as_bytes()[3] != b'0'
+        },
+    }
+}
+
+// Windows refuses paths longer than MAX_PATH (260 characters) unless they are given in the
+// extended-length form, which has a `\\?\` prefix and must be absolute.
+#[cfg(windows)]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    const MAX_PATH: usize = 260;
+    let long = {
+        let string = path.to_string_lossy();
+        string.len() >= MAX_PATH && !string.starts_with(r"\\?\")
+    };
+    if !long {
+        return path;
+    }
+    let absolute = match ::std::path::absolute(&path) {
+        Ok(absolute) => absolute,
+        Err(_) => return path,
+    };
+    let absolute = absolute.to_string_lossy();
+    match absolute.strip_prefix(r"\\") {
+        Some(unc) => PathBuf::from(format!(r"\\?\UNC\{}", unc)),
+        None => PathBuf::from(format!(r"\\?\{}", absolute)),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: PathBuf) -> PathBuf {
+    path
+}
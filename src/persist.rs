@@ -0,0 +1,105 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Moving every file part of a parsed body into permanent storage at once.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{Error, FilePart, Node};
+
+/// Move the file of every file part in `nodes` (nested multiparts included) into `dir`,
+/// as `FilePart::persist()` does for one, updating each `path`.  Returns the new paths,
+/// in the order the parts appear.
+///
+/// Each file keeps its name, unless a file of that name is already in `dir` (or two parts
+/// share a name), in which case a number is added to it: `photo.jpg` becomes
+/// `photo-1.jpg`.  Files which cannot be renamed into `dir` (as when it is on another
+/// filesystem) are copied there instead.
+///
+/// This is all or nothing: if any file cannot be moved, those already moved are put back,
+/// and the error is returned with every part as it was.  On success, the files will not
+/// be deleted when their parts drop.
+pub fn persist_all(nodes: &mut [Node], dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut fileparts: Vec<&mut FilePart> = Vec::new();
+    collect(nodes, &mut fileparts);
+
+    let mut moved: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(fileparts.len());
+    for filepart in &fileparts {
+        let result = unique_path(dir, &filepart.path, &moved)
+            .and_then(|target| move_file(&filepart.path, &target).map(|_| target));
+        match result {
+            Ok(target) => moved.push((filepart.path.clone(), target)),
+            Err(e) => {
+                for (original, target) in moved.iter().rev() {
+                    let _ = move_file(target, original);
+                }
+                return Err(Error::Io(e));
+            },
+        }
+    }
+
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(moved.len());
+    for (filepart, (_, target)) in fileparts.into_iter().zip(moved) {
+        if let Some(tempdir) = filepart.tempdir.take() {
+            let _ = fs::remove_dir(tempdir);
+        }
+        filepart.path = target.clone();
+        paths.push(target);
+    }
+    Ok(paths)
+}
+
+fn collect<'a>(nodes: &'a mut [Node], fileparts: &mut Vec<&'a mut FilePart>) {
+    for node in nodes {
+        match *node {
+            Node::File(ref mut filepart) => fileparts.push(filepart),
+            Node::Multipart((_, ref mut subnodes)) => collect(subnodes, fileparts),
+            Node::Part(_) => {},
+        }
+    }
+}
+
+// A path in `dir` for the file at `path`, under its own name if that is free, and not
+// among the targets already `moved` to.
+fn unique_path(dir: &Path, path: &Path, moved: &[(PathBuf, PathBuf)]) -> io::Result<PathBuf> {
+    let name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{} has no file name", path.display())))?;
+    let taken = |candidate: &Path| {
+        candidate.symlink_metadata().is_ok() || moved.iter().any(|(_, t)| t == candidate)
+    };
+    let candidate = dir.join(name);
+    if ! taken(&candidate) {
+        return Ok(candidate);
+    }
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or(name.as_os_str()).to_string_lossy();
+    let extension = name.extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    for n in 1.. {
+        let candidate = dir.join(format!("{}-{}{}", stem, n, extension));
+        if ! taken(&candidate) {
+            return Ok(candidate);
+        }
+    }
+    unreachable!()
+}
+
+// Rename `from` to `to`, or failing that copy it and delete the original.
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    let result = fs::copy(from, to).and_then(|_| fs::remove_file(from));
+    if result.is_err() {
+        let _ = fs::remove_file(to);
+    }
+    result
+}
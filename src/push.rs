@@ -0,0 +1,346 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! An incremental parser which is handed the bytes of a `multipart/*` body as they
+//! arrive, rather than reading them from a stream, for event-driven I/O.
+
+use hyper::header::Headers;
+
+use super::boundary::{BoundaryFinder, Scan};
+use super::stream::find_bytes;
+use super::{get_multipart_boundary_from_headers, parse_part_headers, truncated, BareDelimiters,
+            Error, LimitKind, Limits, ReadOptions};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Looking for the first delimiter
+    Start,
+    // Just past the first delimiter, which may be the closing delimiter
+    AfterFirstDelimiter,
+    // Where the line terminator after the first delimiter, which is the one in use, is found
+    FirstLineTerminator,
+    // Just past a boundary delimiter, which may be the closing delimiter
+    AfterDelimiter,
+    // At the start of a part's headers
+    Headers,
+    // Within a part's body
+    Body,
+    // The closing delimiter was reached, or an error occurred
+    Done,
+}
+
+/// What a `Pusher` found in the bytes pushed into it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A part begins, with these headers
+    PartStart(Headers),
+    /// More of the current part's body.  A body may arrive in any number of these.
+    PartData(Vec<u8>),
+    /// The current part's body is complete
+    PartEnd,
+    /// The closing delimiter was reached.  Anything pushed after it is ignored.
+    Done,
+}
+
+/// A parser over the parts of a `multipart/*` body which is fed the body in chunks of
+/// any size with `push()`, returning what each chunk completes as `Event`s.  It does no
+/// I/O itself, so it can be driven from callbacks or by any runtime.
+///
+/// Whatever might be the start of a delimiter split across chunks is held back until
+/// the next chunk shows whether it is, so only that much of a body is ever buffered
+/// (and each part's headers, up to `Limits::max_header_size`).  Nested
+/// `multipart/*` parts are not recursed into; their bodies are passed on as data.
+///
+/// Once the body has been pushed, `finish()` checks that it was complete.  After an
+/// error from `push()` parsing stops for good, as it does for `MultipartParts`: later
+/// chunks are ignored, and the error is not returned again.
+#[derive(Clone, Debug)]
+pub struct Pusher {
+    // Bytes pushed but not yet consumed
+    buf: Vec<u8>,
+    // The line terminator in use (CRLF or LF)
+    lt: Vec<u8>,
+    // Finds the boundary with its leading "--": at first alone, and once the line
+    // terminator in use is known, following it
+    delimiter: BoundaryFinder,
+    limits: Limits,
+    // As for `ReadOptions`
+    lenient: bool,
+    strict: bool,
+    reject_conflicting_content_type: bool,
+    // Checks the current part's body for delimiters after a bare CR or LF, if strict
+    bare: Option<BareDelimiters>,
+    state: State,
+    // How much of the current part's body has been passed on
+    body_read: usize,
+}
+
+impl Pusher {
+    /// Create a parser for a body with the given `boundary`, without the leading `--`,
+    /// as for `MultipartParts::new()`.  Of the `limits`, the size and number of each
+    /// part's headers are enforced.
+    pub fn new(boundary: &[u8], limits: Limits) -> Pusher {
+        Pusher::with_options(boundary, &ReadOptions { limits, ..ReadOptions::default() })
+    }
+
+    /// Create a parser for a body with the `multipart/*` Content-Type found in `headers`.
+    pub fn from_headers(headers: &Headers, limits: Limits) -> Result<Pusher, Error> {
+        let boundary = get_multipart_boundary_from_headers(headers)?;
+        Ok(Pusher::new(&boundary, limits))
+    }
+
+    // As `new()`, but also reading the body as `lenient`, `strict_crlf` and
+    // `reject_conflicting_content_type` in `options` ask.
+    pub(crate) fn with_options(boundary: &[u8], options: &ReadOptions) -> Pusher {
+        Pusher {
+            buf: Vec::new(),
+            lt: Vec::new(),
+            delimiter: BoundaryFinder::new(boundary),
+            limits: options.limits.clone(),
+            lenient: options.lenient && ! options.strict_crlf,
+            strict: options.strict_crlf,
+            reject_conflicting_content_type: options.reject_conflicting_content_type,
+            bare: None,
+            state: State::Start,
+            body_read: 0,
+        }
+    }
+
+    /// Feed the next chunk of the body to the parser, returning the events it completes,
+    /// in order.  This may be none at all, if the chunk ends within a delimiter or a part's
+    /// headers.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Event>, Error> {
+        if self.state == State::Done {
+            return Ok(Vec::new());
+        }
+        self.buf.extend(data);
+        let mut events = Vec::new();
+        match self.advance(&mut events) {
+            Ok(()) => Ok(events),
+            Err(e) => {
+                self.state = State::Done;
+                self.buf.clear();
+                Err(e)
+            },
+        }
+    }
+
+    /// Declare that the whole body has been pushed.  Returns an error if the closing
+    /// delimiter has not been reached, as the other parsers do for a body which ends early.
+    pub fn finish(self) -> Result<(), Error> {
+        let buf = &self.buf[..];
+        match self.state {
+            State::Start => Err(Error::EofBeforeFirstBoundary),
+            State::AfterFirstDelimiter | State::FirstLineTerminator => {
+                if buf.starts_with(b"\r\n") || (buf.starts_with(b"\n") && ! self.strict) {
+                    Err(Error::MissingClosingBoundary)
+                } else {
+                    Err(Error::NoCrLfAfterBoundary)
+                }
+            },
+            State::AfterDelimiter => {
+                if buf.iter().all(|b| b" \t\r\n".contains(b)) {
+                    Err(Error::MissingClosingBoundary)
+                } else {
+                    Err(Error::NoCrLfAfterBoundary)
+                }
+            },
+            State::Headers if buf.is_empty() => Err(Error::MissingClosingBoundary),
+            State::Headers => Err(Error::EofInPartHeaders),
+            State::Body => Err(truncated(self.delimiter.delimiter(),
+                                         self.body_read + buf.len())),
+            State::Done => Ok(()),
+        }
+    }
+
+    // Consume as much of the buffer as can be, until more must be pushed to go on.
+    fn advance(&mut self, events: &mut Vec<Event>) -> Result<(), Error> {
+        loop {
+            match self.state {
+                State::Start => {
+                    // Discard everything before the first delimiter
+                    match self.delimiter.scan(&self.buf) {
+                        Scan::Found(at) => {
+                            self.buf.drain(..at + self.delimiter.delimiter().len());
+                            self.state = State::AfterFirstDelimiter;
+                        },
+                        Scan::Clear(clear) => {
+                            self.buf.drain(..clear);
+                            return Ok(());
+                        },
+                    }
+                },
+                State::AfterFirstDelimiter => {
+                    // A closing delimiter straight away is an empty multipart
+                    if self.buf.len() < 2 {
+                        return Ok(());
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.finish_body(events);
+                        continue;
+                    }
+                    self.state = State::FirstLineTerminator;
+                },
+                State::FirstLineTerminator => {
+                    // In lenient mode, allow linear whitespace after the boundary
+                    if self.lenient {
+                        let padding = self.buf.iter().take_while(|&&b| b == b' ' || b == b'\t')
+                            .count();
+                        self.buf.drain(..padding);
+                    }
+
+                    // Use their first line terminator to determine whether to use CRLF or LF.
+                    // In lenient mode a lone LF is always accepted, and any CR before it
+                    // dropped.
+                    self.lt = if self.lenient && self.buf.starts_with(b"\n") {
+                        b"\n".to_vec()
+                    } else if self.buf.len() < 2 {
+                        return Ok(());
+                    } else if self.buf.starts_with(b"\r\n") {
+                        if self.lenient { b"\n".to_vec() } else { b"\r\n".to_vec() }
+                    } else if self.buf.starts_with(b"\n") && ! self.strict {
+                        b"\n".to_vec()
+                    } else {
+                        return Err(Error::NoCrLfAfterBoundary);
+                    };
+                    let lt_boundary = [&self.lt[..], self.delimiter.delimiter()].concat();
+                    self.delimiter = BoundaryFinder::with_delimiter(&lt_boundary);
+                    self.state = State::AfterDelimiter;
+                },
+                State::AfterDelimiter => {
+                    // If the next two characters are '--', parsing is finished.
+                    if self.buf.len() < 2 {
+                        return Ok(());
+                    }
+                    if self.buf.starts_with(b"--") {
+                        self.finish_body(events);
+                        continue;
+                    }
+
+                    // Read the line terminator after the boundary.  What comes before it is
+                    // held until it arrives, so counts towards the size of the headers.
+                    match find_bytes(&self.buf, &self.lt) {
+                        Some(at) => {
+                            let padding = &self.buf[..at];
+                            if self.strict && ! padding.iter().all(|&b| b == b' ' || b == b'\t') {
+                                return Err(Error::NoCrLfAfterBoundary);
+                            }
+                            self.buf.drain(..at + self.lt.len());
+                            self.state = State::Headers;
+                        },
+                        None if self.buf.len() > self.limits.max_header_size => {
+                            return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+                        },
+                        None => return Ok(()),
+                    }
+                },
+                State::Headers => {
+                    // Read the headers (which end in 2 line terminators, or in lenient mode
+                    // a blank line ending in either)
+                    let headers = match self.take_headers()? {
+                        Some(headers) => headers,
+                        None => return Ok(()),
+                    };
+                    events.push(Event::PartStart(headers));
+                    self.state = State::Body;
+                    self.body_read = 0;
+                    if self.strict {
+                        let boundary = &self.delimiter.delimiter()[self.lt.len()..];
+                        self.bare = Some(BareDelimiters::new(boundary));
+                    }
+                },
+                State::Body => {
+                    // Pass on the body up to the delimiter.  Anything which might be the
+                    // start of a delimiter split across chunks must be held back, and in
+                    // lenient mode a trailing CR, which may be part of the line terminator.
+                    let (data, found) = match self.delimiter.scan(&self.buf) {
+                        Scan::Found(at) => (at, true),
+                        Scan::Clear(clear) => (clear, false),
+                    };
+                    let strip_cr = self.lenient && data > 0 && self.buf[data - 1] == b'\r';
+                    let len = if strip_cr { data - 1 } else { data };
+                    let (buf, bare) = (&self.buf, &mut self.bare);
+                    if bare.as_mut().is_some_and(|bare| bare.check(&buf[..len])) {
+                        return Err(Error::NoCrLfAfterBoundary);
+                    }
+                    if len > 0 {
+                        self.body_read += len;
+                        events.push(Event::PartData(self.buf[..len].to_vec()));
+                    }
+                    if ! found {
+                        self.buf.drain(..len);
+                        return Ok(());
+                    }
+                    events.push(Event::PartEnd);
+                    self.buf.drain(..data + self.delimiter.delimiter().len());
+                    self.state = State::AfterDelimiter;
+                },
+                State::Done => return Ok(()),
+            }
+        }
+    }
+
+    // Take a part's headers from the front of the buffer, if they are all there.
+    fn take_headers(&mut self) -> Result<Option<Headers>, Error> {
+        let max = self.limits.max_header_size;
+        if self.lenient {
+            // Line by line, as `read_headers_lenient()` reads them
+            let end = match lenient_headers_end(&self.buf) {
+                Some(end) if end > max => {
+                    return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+                },
+                Some(end) => end,
+                None if self.buf.len() > max => {
+                    return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+                },
+                None => return Ok(None),
+            };
+            let mut buf: Vec<u8> = Vec::with_capacity(end + 2);
+            for line in self.buf[..end - 1].split(|&b| b == b'\n') {
+                buf.extend(line.strip_suffix(b"\r").unwrap_or(line));
+                buf.extend(b"\r\n");
+            }
+            self.buf.drain(..end);
+            return parse_part_headers(&buf, self.limits.max_headers,
+                                      self.reject_conflicting_content_type).map(Some);
+        }
+        let ltlt = [&self.lt[..], &self.lt[..]].concat();
+        let at = match find_bytes(&self.buf, &ltlt) {
+            Some(at) if at > max => return Err(Error::LimitExceeded(LimitKind::HeaderSize)),
+            Some(at) => at,
+            None if self.buf.len() > max + ltlt.len() => {
+                return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+            },
+            None => return Ok(None),
+        };
+        let end = at + ltlt.len();
+        let headers = parse_part_headers(&self.buf[..end], self.limits.max_headers,
+                                         self.reject_conflicting_content_type)?;
+        self.buf.drain(..end);
+        Ok(Some(headers))
+    }
+
+    // The closing delimiter has been reached; the epilogue is ignored.
+    fn finish_body(&mut self, events: &mut Vec<Event>) {
+        self.buf.clear();
+        self.state = State::Done;
+        events.push(Event::Done);
+    }
+}
+
+// Where a block of headers with CRLF or LF line terminators ends: just past its first blank
+// line.
+fn lenient_headers_end(buf: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(at) = find_bytes(&buf[start..], b"\n") {
+        if at == 0 || buf[start..start + at] == *b"\r" {
+            return Some(start + at + 1);
+        }
+        start += at + 1;
+    }
+    None
+}
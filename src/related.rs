@@ -0,0 +1,45 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Support for `multipart/related` bodies (RFC 2387), whose parts refer to one another
+//! by Content-ID.
+
+use super::Node;
+
+/// Find the node whose `Content-ID` header is `cid`, searching nested multiparts too.
+///
+/// The angle brackets around a Content-ID are optional on both sides of the comparison,
+/// and `cid` may also be given as a `cid:` URL (RFC 2392), as it appears in references
+/// from other parts.
+pub fn find_by_content_id<'a>(nodes: &'a [Node], cid: &str) -> Option<&'a Node> {
+    let cid = normalize(cid.trim().strip_prefix("cid:").unwrap_or(cid.trim()));
+    find(nodes, cid)
+}
+
+fn find<'a>(nodes: &'a [Node], cid: &str) -> Option<&'a Node> {
+    for node in nodes {
+        let matches = node.headers().get_raw("Content-ID")
+            .and_then(|raw| raw.first())
+            .map(|value| normalize(&String::from_utf8_lossy(value)) == cid)
+            .unwrap_or(false);
+        if matches {
+            return Some(node);
+        }
+        if let Node::Multipart((_, ref subnodes)) = *node {
+            if let Some(found) = find(subnodes, cid) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+// A Content-ID without surrounding whitespace and angle brackets.
+fn normalize(cid: &str) -> &str {
+    let cid = cid.trim();
+    cid.strip_prefix('<').and_then(|c| c.strip_suffix('>')).unwrap_or(cid).trim()
+}
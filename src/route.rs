@@ -0,0 +1,119 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing a `multipart/*` body with the destination of each part chosen by the caller.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use hyper::header::Headers;
+
+use super::{Error, FilePart, LimitKind, MultipartParts, Part, ReadOptions};
+
+/// Where `read_multipart_routed()` is to put the body of a part.
+pub enum PartSink {
+    /// Keep it in memory, as a `Part`.  The `max_part_size` of the `Limits` in use applies.
+    Memory,
+    /// Stream it to a file created (or truncated) at this path, as a `FilePart` which does
+    /// not delete the file when it drops.
+    File(PathBuf),
+    /// Stream it into this writer, which is then flushed and dropped.
+    Writer(Box<dyn Write>),
+}
+impl fmt::Debug for PartSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PartSink::Memory => f.write_str("Memory"),
+            PartSink::File(ref path) => f.debug_tuple("File").field(path).finish(),
+            PartSink::Writer(_) => f.write_str("Writer(..)"),
+        }
+    }
+}
+
+/// A part as read by `read_multipart_routed()`, according to where it was sent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RoutedNode {
+    /// A part kept in memory (`PartSink::Memory`)
+    Part(Part),
+    /// A part streamed to a file (`PartSink::File`), with its `size` set
+    File(FilePart),
+    /// A part streamed into a writer (`PartSink::Writer`), with the number of bytes written
+    Written((Headers, usize)),
+}
+impl RoutedNode {
+    /// The headers of the part, wherever it went.
+    pub fn headers(&self) -> &Headers {
+        match *self {
+            RoutedNode::Part(ref part) => &part.headers,
+            RoutedNode::File(ref filepart) => &filepart.headers,
+            RoutedNode::Written((ref headers, _)) => headers,
+        }
+    }
+}
+
+/// Parse a `multipart/*` body from `stream`, asking `router` where to put each part as
+/// soon as its headers have been read.  The `boundary` is given without the leading `--`.
+///
+/// This generalizes the `always_use_files` and `spill_threshold` settings: the router can
+/// keep small fields in memory, write uploads to their final location, and pass others
+/// straight through to some other writer.  Nested multiparts are not recursed into; their
+/// whole body is routed like any other part.  The default `Limits` are applied; use
+/// `read_multipart_routed_with_options()` to choose others.
+pub fn read_multipart_routed<S, F>(stream: S, boundary: &[u8], router: F)
+                                   -> Result<Vec<RoutedNode>, Error>
+    where S: Read,
+          F: FnMut(&Headers) -> PartSink
+{
+    read_multipart_routed_with_options(stream, boundary, &ReadOptions::default(), router)
+}
+
+/// As `read_multipart_routed()`, but applying the `limits` of `options`: `max_parts`,
+/// `max_header_size`, `max_headers`, and `max_part_size` for the parts kept in memory.  The
+/// router decides what the other options would, so they are not used.
+pub fn read_multipart_routed_with_options<S, F>(
+    stream: S,
+    boundary: &[u8],
+    options: &ReadOptions,
+    mut router: F)
+    -> Result<Vec<RoutedNode>, Error>
+    where S: Read,
+          F: FnMut(&Headers) -> PartSink
+{
+    let limits = &options.limits;
+    let mut parts = MultipartParts::with_limits(stream, boundary, limits.clone());
+    let mut nodes: Vec<RoutedNode> = Vec::new();
+    while let Some(part) = parts.next_part() {
+        let mut part = part?;
+        if nodes.len() == limits.max_parts {
+            return Err(Error::LimitExceeded(LimitKind::Parts));
+        }
+        let node = match router(&part.headers) {
+            PartSink::Memory => {
+                let mut body: Vec<u8> = Vec::new();
+                part.copy_to(&mut body, limits.max_part_size)?;
+                RoutedNode::Part(Part { headers: part.headers.clone(), body })
+            },
+            PartSink::File(path) => {
+                let mut file = File::create(&path)?;
+                let size = part.copy_to(&mut file, usize::MAX)?;
+                file.flush()?;
+                let mut filepart = FilePart::new(part.headers.clone(), &path);
+                filepart.size = Some(size);
+                RoutedNode::File(filepart)
+            },
+            PartSink::Writer(mut writer) => {
+                let size = part.copy_to(&mut writer, usize::MAX)?;
+                writer.flush()?;
+                RoutedNode::Written((part.headers.clone(), size))
+            },
+        };
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
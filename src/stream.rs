@@ -0,0 +1,456 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Streaming access to the parts of a `multipart/*` body, without holding any part
+//! in memory.
+
+use std::io::{self, Read, Write};
+
+use hyper::header::Headers;
+use memchr::memmem;
+
+use super::boundary::{BoundaryFinder, Scan};
+use super::{get_multipart_boundary_from_headers, parse_part_headers, truncated, Error, LimitKind,
+            Limits};
+
+// How much to read from the underlying stream at a time
+const READ_SIZE: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // Nothing has been read yet
+    Start,
+    // Just past a boundary delimiter, which may be the closing delimiter
+    AfterDelimiter,
+    // At the start of a part's headers
+    Headers,
+    // Within a part's body
+    Body,
+    // The closing delimiter was reached, or an error occurred
+    Done,
+}
+
+/// A streaming parser over the parts of a `multipart/*` body.
+///
+/// Parts are visited in order with `next_part()`.  Each `PartStream` returned is
+/// bounded to the content of its part and borrows the parser, so only one part can
+/// be read at a time.  Any content of a part not read before the next call to
+/// `next_part()` is skipped.
+///
+/// Nested `multipart/*` parts are not recursed into, but as a `PartStream` is itself
+/// `Read`, another `MultipartParts` can be layered on top of it.
+pub struct MultipartParts<R: Read> {
+    reader: R,
+    // Bytes read from `reader` but not yet consumed, starting at `pos`
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    // The line terminator in use (CRLF or LF)
+    lt: Vec<u8>,
+    // Finds the boundary with its leading "--": at first alone, and once the line
+    // terminator in use is known, following it
+    delimiter: BoundaryFinder,
+    limits: Limits,
+    state: State,
+    // How much of the current part's body has been consumed
+    body_read: usize,
+}
+
+impl<R: Read> MultipartParts<R> {
+    /// Create a parser over the body in `reader`.  The `boundary` is given as it appears
+    /// in the Content-Type header, without the leading `--` (the same form taken by
+    /// `write_multipart()`).
+    pub fn new(reader: R, boundary: &[u8]) -> MultipartParts<R> {
+        MultipartParts::with_limits(reader, boundary, Limits::default())
+    }
+
+    // As `new()`, but with `limits` on the size and number of each part's headers in place
+    // of the defaults.
+    pub(crate) fn with_limits(reader: R, boundary: &[u8], limits: Limits) -> MultipartParts<R> {
+        MultipartParts {
+            reader,
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            lt: Vec::new(),
+            delimiter: BoundaryFinder::new(boundary),
+            limits,
+            state: State::Start,
+            body_read: 0,
+        }
+    }
+
+    /// Create a parser over the body in `reader`, taking the boundary from the
+    /// `multipart/*` Content-Type found in `headers`.
+    pub fn from_headers(reader: R, headers: &Headers) -> Result<MultipartParts<R>, Error> {
+        let boundary = get_multipart_boundary_from_headers(headers)?;
+        Ok(MultipartParts::new(reader, &boundary))
+    }
+
+    /// Advance to the next part, returning `None` once the closing boundary has been
+    /// reached.
+    ///
+    /// This is not an `Iterator` because each part borrows the parser.
+    pub fn next_part(&mut self) -> Option<Result<PartStream<'_, R>, Error>> {
+        match self.next_headers()? {
+            Ok(headers) => Some(Ok(PartStream { parts: self, headers })),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    // As `advance()`, but stopping for good after an error.
+    fn next_headers(&mut self) -> Option<Result<Headers, Error>> {
+        match self.advance() {
+            Ok(headers) => headers.map(Ok),
+            Err(e) => {
+                self.state = State::Done;
+                Some(Err(e))
+            },
+        }
+    }
+
+    // Move forward to the body of the next part, returning its headers.
+    fn advance(&mut self) -> Result<Option<Headers>, Error> {
+        loop {
+            match self.state {
+                State::Start => {
+                    // Read past the initial boundary
+                    loop {
+                        match self.scan()? {
+                            Some((before, found)) => {
+                                self.pos += before;
+                                if found {
+                                    self.pos += self.delimiter.delimiter().len();
+                                    break;
+                                }
+                            },
+                            None => return Err(Error::EofBeforeFirstBoundary),
+                        }
+                    }
+
+                    // Use their first line terminator to determine whether to use CRLF or LF.
+                    self.fill_to(2)?;
+                    let peeker = &self.buf[self.pos..];
+                    self.lt = if peeker.starts_with(b"--") {
+                        self.state = State::Done;
+                        continue;
+                    } else if peeker.starts_with(b"\r\n") {
+                        b"\r\n".to_vec()
+                    } else if peeker.starts_with(b"\n") {
+                        b"\n".to_vec()
+                    } else {
+                        return Err(Error::NoCrLfAfterBoundary);
+                    };
+                    let lt_boundary = [&self.lt[..], self.delimiter.delimiter()].concat();
+                    self.delimiter = BoundaryFinder::with_delimiter(&lt_boundary);
+                    self.state = State::AfterDelimiter;
+                },
+                State::AfterDelimiter => {
+                    // If the next two lookahead characters are '--', parsing is finished.
+                    self.fill_to(2)?;
+                    if self.buf[self.pos..].starts_with(b"--") {
+                        self.state = State::Done;
+                        continue;
+                    }
+
+                    // Read the line terminator after the boundary.  What comes before it
+                    // is buffered, so counts towards the size of the headers.  If the body
+                    // ends here, all that is missing is the closing delimiter.
+                    let lt = self.lt.clone();
+                    match self.find_within(&lt, self.limits.max_header_size)? {
+                        Some(at) => self.pos += at + lt.len(),
+                        None if self.buf[self.pos..].iter().all(|b| b" \t\r\n".contains(b)) => {
+                            return Err(Error::MissingClosingBoundary);
+                        },
+                        None => return Err(Error::NoCrLfAfterBoundary),
+                    }
+                    self.fill_to(1)?;
+                    if self.pos == self.buf.len() {
+                        return Err(Error::MissingClosingBoundary);
+                    }
+                    self.state = State::Headers;
+                },
+                State::Headers => {
+                    // Read the headers (which end in 2 line terminators)
+                    let mut ltlt = self.lt.clone();
+                    ltlt.extend(&self.lt);
+                    let at = match self.find_within(&ltlt, self.limits.max_header_size)? {
+                        Some(at) => at,
+                        None => return Err(Error::EofInPartHeaders),
+                    };
+                    let end = self.pos + at + ltlt.len();
+                    let headers = parse_part_headers(&self.buf[self.pos..end],
+                                                     self.limits.max_headers, false)?;
+                    self.pos = end;
+                    self.state = State::Body;
+                    self.body_read = 0;
+                    return Ok(Some(headers));
+                },
+                State::Body => {
+                    // Skip whatever of the previous part was not read
+                    self.skip_body()?;
+                },
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    // Read from the current part's body, returning 0 once the delimiter is reached.
+    fn read_body(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        if self.state != State::Body || out.is_empty() {
+            return Ok(0);
+        }
+        let (before, found) = self.scan()?.ok_or_else(|| self.truncated())?;
+        if found && before == 0 {
+            self.end_body();
+            return Ok(0);
+        }
+        let count = before.min(out.len());
+        out[..count].copy_from_slice(&self.buf[self.pos..self.pos + count]);
+        self.pos += count;
+        self.body_read += count;
+        Ok(count)
+    }
+
+    // Skip the rest of the current part's body without copying it, returning how much
+    // was skipped.
+    fn skip_body(&mut self) -> Result<usize, Error> {
+        if self.state != State::Body {
+            return Ok(0);
+        }
+        let mut skipped = 0;
+        loop {
+            let (before, found) = self.scan()?.ok_or_else(|| self.truncated())?;
+            self.pos += before;
+            self.body_read += before;
+            skipped += before;
+            if found {
+                self.end_body();
+                return Ok(skipped);
+            }
+        }
+    }
+
+    // Consume the delimiter which ends a part's body.
+    fn end_body(&mut self) {
+        self.pos += self.delimiter.delimiter().len();
+        self.state = State::AfterDelimiter;
+    }
+
+    // The error for a body which ends within the current part, counting what is still
+    // buffered as read.
+    fn truncated(&self) -> Error {
+        truncated(self.delimiter.delimiter(), self.body_read + self.buf.len() - self.pos)
+    }
+
+    // Find how much of what is buffered comes before the next delimiter, reading more if
+    // none of it can be told not to be the start of one.  Returns that many bytes, and
+    // whether the delimiter follows them, or `None` if the stream ends first.
+    fn scan(&mut self) -> io::Result<Option<(usize, bool)>> {
+        loop {
+            match self.delimiter.scan(&self.buf[self.pos..]) {
+                Scan::Found(at) => return Ok(Some((at, true))),
+                Scan::Clear(clear) if clear > 0 => return Ok(Some((clear, false))),
+                Scan::Clear(_) => if self.fill()? == 0 {
+                    return Ok(None);
+                },
+            }
+        }
+    }
+
+    // Read more of the underlying stream into the buffer, returning how much was read.
+    fn fill(&mut self) -> io::Result<usize> {
+        if self.eof {
+            return Ok(0);
+        }
+        if self.pos > 0 {
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+        }
+        let len = self.buf.len();
+        self.buf.resize(len + READ_SIZE, 0);
+        let result = loop {
+            match self.reader.read(&mut self.buf[len..]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                other => break other,
+            }
+        };
+        let read = *result.as_ref().unwrap_or(&0);
+        self.buf.truncate(len + read);
+        if read == 0 {
+            self.eof = true;
+        }
+        result
+    }
+
+    // Fill the buffer until at least `count` unconsumed bytes are available, or EOF.
+    fn fill_to(&mut self, count: usize) -> io::Result<()> {
+        while self.buf.len() - self.pos < count {
+            if self.fill()? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Fill the buffer until `token` is found, returning its offset from `pos`, but giving
+    // up with `Error::LimitExceeded` if it does not start within `max` bytes.
+    fn find_within(&mut self, token: &[u8], max: usize) -> Result<Option<usize>, Error> {
+        let mut from = 0;
+        loop {
+            if let Some(at) = find_bytes(&self.buf[self.pos + from..], token) {
+                return match from + at {
+                    at if at > max => Err(Error::LimitExceeded(LimitKind::HeaderSize)),
+                    at => Ok(Some(at)),
+                };
+            }
+            if self.buf.len() - self.pos > max + token.len() {
+                return Err(Error::LimitExceeded(LimitKind::HeaderSize));
+            }
+            from = (self.buf.len() - self.pos).saturating_sub(token.len() - 1);
+            if self.fill()? == 0 {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// The body of a single part, as returned by `MultipartParts::next_part()`.
+pub struct PartStream<'a, R: Read> {
+    parts: &'a mut MultipartParts<R>,
+    /// The headers of the part
+    pub headers: Headers,
+}
+
+impl<'a, R: Read> PartStream<'a, R> {
+    // Copy the rest of the body into `writer`, returning the number of bytes copied.  Gives
+    // up with `Error::LimitExceeded` once more than `limit` bytes have been copied.
+    pub(crate) fn copy_to<W: Write>(&mut self, writer: &mut W, limit: usize)
+                                    -> Result<usize, Error>
+    {
+        let mut chunk = [0; READ_SIZE];
+        let mut size = 0;
+        loop {
+            match self.parts.read_body(&mut chunk)? {
+                0 => return Ok(size),
+                read => {
+                    size += read;
+                    if size > limit {
+                        return Err(Error::LimitExceeded(LimitKind::PartSize));
+                    }
+                    writer.write_all(&chunk[..read])?;
+                },
+            }
+        }
+    }
+}
+
+impl<'a, R: Read> Read for PartStream<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.parts.read_body(buf).map_err(body_error)
+    }
+}
+
+fn body_error(e: Error) -> io::Error {
+    match e {
+        Error::Truncated { .. } => io::Error::new(io::ErrorKind::UnexpectedEof, e),
+        e => e.into(),
+    }
+}
+
+/// A streaming parser which yields the headers of each part first, so that whether to
+/// read the part's body can be decided from them.
+///
+/// `next_headers()` moves to the next part and returns its headers.  The part's body is
+/// then read through `Read`, which returns 0 at the end of the body, or passed over with
+/// `skip()`; whatever is left unread is skipped by the next call to `next_headers()`.  A
+/// skipped body is still read from the stream to find its end, but is never copied.
+///
+/// Unlike `MultipartParts`, no borrow is held between the headers and the body, so the
+/// reader can be handed on to whatever deals with the part.
+pub struct PartReader<R: Read> {
+    parts: MultipartParts<R>,
+}
+
+impl<R: Read> PartReader<R> {
+    /// Create a reader over the body in `reader`.  The `boundary` is given without the
+    /// leading `--`, as for `MultipartParts::new()`.
+    pub fn new(reader: R, boundary: &[u8]) -> PartReader<R> {
+        PartReader { parts: MultipartParts::new(reader, boundary) }
+    }
+
+    /// Create a reader over the body in `reader`, taking the boundary from the
+    /// `multipart/*` Content-Type found in `headers`.
+    pub fn from_headers(reader: R, headers: &Headers) -> Result<PartReader<R>, Error> {
+        Ok(PartReader { parts: MultipartParts::from_headers(reader, headers)? })
+    }
+
+    /// Move to the next part, skipping any of the current part's body not yet read, and
+    /// return its headers.  Returns `None` once the closing boundary has been reached.
+    pub fn next_headers(&mut self) -> Option<Result<Headers, Error>> {
+        self.parts.next_headers()
+    }
+
+    /// Skip the rest of the current part's body, returning the number of bytes skipped.
+    pub fn skip(&mut self) -> Result<usize, Error> {
+        self.parts.skip_body()
+    }
+}
+
+impl<R: Read> Read for PartReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.parts.read_body(buf).map_err(body_error)
+    }
+}
+
+/// What `validate_multipart()` found in a body.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MultipartStats {
+    /// The number of parts
+    pub parts: usize,
+    /// The number of bytes read from the stream
+    pub total_bytes: usize,
+    /// The size of each part's body, in order
+    pub part_sizes: Vec<usize>,
+}
+
+/// Check that the body in `stream` is a well-formed `multipart/*` body with the given
+/// `boundary` (without the leading `--`), without keeping any of it.  Part bodies are
+/// read and discarded, so nothing is buffered or written to disk.
+///
+/// Nested multiparts are counted as single parts, and not checked within.
+pub fn validate_multipart<R: Read>(stream: R, boundary: &[u8]) -> Result<MultipartStats, Error> {
+    let mut counter = CountingReader { inner: stream, count: 0 };
+    let mut part_sizes: Vec<usize> = Vec::new();
+    {
+        let mut parts = MultipartParts::new(&mut counter, boundary);
+        while let Some(part) = parts.next_part() {
+            part_sizes.push(part?.copy_to(&mut io::sink(), usize::MAX)?);
+        }
+    }
+    Ok(MultipartStats {
+        parts: part_sizes.len(),
+        total_bytes: counter.count,
+        part_sizes,
+    })
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read;
+        Ok(read)
+    }
+}
+
+pub(crate) fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    memmem::find(haystack, needle)
+}
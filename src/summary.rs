@@ -0,0 +1,75 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A summary of a parsed body's metadata, for logging.  With the `serde` feature it can
+//! be serialized, as to JSON.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use hyper::header::Headers;
+
+use super::{disposition, get_content_disposition_name, total_size, Node};
+
+/// The metadata of a parsed `multipart/*` body, as returned by `summarize()`.  No body or
+/// file content is included.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MultipartSummary {
+    /// A summary of each part, in order
+    pub parts: Vec<PartSummary>,
+}
+
+/// The metadata of one part of a `multipart/*` body.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PartSummary {
+    /// The `name` of the Content-Disposition header
+    pub name: Option<String>,
+    /// The filename of the Content-Disposition header
+    pub filename: Option<String>,
+    /// The Content-Type header
+    pub content_type: Option<String>,
+    /// The size of the body in bytes, or for a nested multipart the total size of its parts
+    pub size: u64,
+    /// Whether the part was streamed to a file
+    pub file: bool,
+    /// For a nested multipart, a summary of each of its parts
+    pub parts: Vec<PartSummary>,
+}
+
+/// Summarize the metadata of `nodes`: their field names, filenames, content types and
+/// sizes.
+pub fn summarize(nodes: &[Node]) -> MultipartSummary {
+    MultipartSummary { parts: nodes.iter().map(summarize_node).collect() }
+}
+
+fn summarize_node(node: &Node) -> PartSummary {
+    let headers = node.headers();
+    let mut summary = PartSummary {
+        name: get_content_disposition_name(headers),
+        filename: disposition::filename(headers).ok().flatten(),
+        content_type: content_type(headers),
+        ..PartSummary::default()
+    };
+    match *node {
+        Node::Part(ref part) => summary.size = part.body.len() as u64,
+        Node::File(_) => {
+            summary.size = total_size(::std::slice::from_ref(node));
+            summary.file = true;
+        },
+        Node::Multipart((_, ref subnodes)) => {
+            summary.size = total_size(subnodes);
+            summary.parts = subnodes.iter().map(summarize_node).collect();
+        },
+    }
+    summary
+}
+
+fn content_type(headers: &Headers) -> Option<String> {
+    super::content_type(headers).map(|mime| mime.to_string())
+}
@@ -14,6 +14,7 @@ use hyper::net::NetworkStream;
 use hyper::server::Request as HyperRequest;
 
 use mock::MockStream;
+use stream::find_bytes;
 
 use hyper::header::{Headers, ContentDisposition, DispositionParam, ContentType,
                     DispositionType};
@@ -53,7 +54,7 @@ fn parser() {
     let req = HyperRequest::new(&mut stream, sock).unwrap();
     let (_, _, headers, _, _, mut reader) = req.deconstruct();
 
-    match read_multipart_body(&mut reader, &headers, false) {
+    match read_multipart_body(&mut reader, &headers, false, false) {
         Ok(nodes) => {
 
             assert_eq!(nodes.len(), 3);
@@ -129,23 +130,23 @@ fn mixed_parser() {
     let req = HyperRequest::new(&mut stream, sock).unwrap();
     let (_, _, headers, _, _, mut reader) = req.deconstruct();
 
-    match read_multipart_body(&mut reader, &headers, false) {
+    match read_multipart_body(&mut reader, &headers, false, false) {
         Ok(nodes) => {
 
             assert_eq!(nodes.len(), 2);
 
             if let Node::Part(ref part) = nodes[0] {
                 let cd: &ContentDisposition = part.headers.get().unwrap();
-                let cd_name: String = get_content_disposition_name(&cd).unwrap();
+                let cd_name: String = get_content_disposition_name(cd).unwrap();
                 assert_eq!(&*cd_name, "submit-name");
-                assert_eq!(::std::str::from_utf8(&*part.body).unwrap(), "Larry");
+                assert_eq!(::std::str::from_utf8(&part.body).unwrap(), "Larry");
             } else {
                 panic!("1st node of wrong type");
             }
 
             if let Node::Multipart((ref headers, ref subnodes)) = nodes[1] {
                 let cd: &ContentDisposition = headers.get().unwrap();
-                let cd_name: String = get_content_disposition_name(&cd).unwrap();
+                let cd_name: String = get_content_disposition_name(cd).unwrap();
                 assert_eq!(&*cd_name, "files");
 
                 assert_eq!(subnodes.len(), 2);
@@ -212,16 +213,16 @@ fn test_line_feed() {
     let req = HyperRequest::new(&mut stream, sock).unwrap();
     let (_, _, headers, _, _, mut reader) = req.deconstruct();
 
-    if let Err(e) = read_multipart_body(&mut reader, &headers, false) {
+    if let Err(e) = read_multipart_body(&mut reader, &headers, false, false) {
         panic!("{}", e);
     }
 }
 
 #[inline]
 fn get_content_disposition_name(cd: &ContentDisposition) -> Option<String> {
-    if let Some(&DispositionParam::Ext(_, ref value)) = cd.parameters.iter()
+    if let Some(DispositionParam::Ext(_, value)) = cd.parameters.iter()
         .find(|&x| match *x {
-            DispositionParam::Ext(ref token,_) => &*token == "name",
+            DispositionParam::Ext(ref token,_) => token == "name",
             _ => false,
         })
     {
@@ -262,9 +263,7 @@ fn test_output() {
         body: b"Dilger".to_vec(),
     };
 
-    let mut nodes: Vec<Node> = Vec::new();
-    nodes.push(Node::Part(first_name));
-    nodes.push(Node::Part(last_name));
+    let nodes: Vec<Node> = vec![Node::Part(first_name), Node::Part(last_name)];
 
     let count = match write_multipart(&mut output, &boundary, &nodes) {
         Ok(c) => c,
@@ -311,9 +310,7 @@ fn test_chunked() {
         body: b"Dilger".to_vec(),
     };
 
-    let mut nodes: Vec<Node> = Vec::new();
-    nodes.push(Node::Part(first_name));
-    nodes.push(Node::Part(last_name));
+    let nodes: Vec<Node> = vec![Node::Part(first_name), Node::Part(last_name)];
 
     assert!(write_multipart_chunked(&mut output, &boundary, &nodes).is_ok());
 
@@ -324,3 +321,4883 @@ fn test_chunked() {
 
     assert_eq!(output.len(), 557);
 }
+
+#[test]
+fn test_chunked_repeated_headers() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![b"form-data; name=\"tagged\"".to_vec()]);
+    headers.set_raw("X-Tag", vec![b"first".to_vec(), b"second, with a comma".to_vec()]);
+    let nodes = vec![Node::Part(Part { headers, body: b"value".to_vec() })];
+
+    let mut chunked: Vec<u8> = Vec::new();
+    write_multipart_chunked(&mut chunked, b"AaB03x", &nodes).unwrap();
+
+    // Undo the chunked encoding
+    let mut body: Vec<u8> = Vec::new();
+    let mut rest = &chunked[..];
+    loop {
+        let line = find_bytes(rest, b"\r\n").unwrap();
+        let len = usize::from_str_radix(::std::str::from_utf8(&rest[..line]).unwrap(), 16)
+            .unwrap();
+        if len == 0 {
+            break;
+        }
+        body.extend(&rest[line + 2..line + 2 + len]);
+        rest = &rest[line + 2 + len + 2..];
+    }
+
+    // Each value is on a line of its own, as write_multipart() writes them
+    let mut expected: Vec<u8> = Vec::new();
+    write_multipart(&mut expected, b"AaB03x", &nodes).unwrap();
+    assert_eq!(String::from_utf8_lossy(&body), String::from_utf8_lossy(&expected));
+    assert!(find_bytes(&body, b"X-Tag: first\r\nX-Tag: second, with a comma\r\n").is_some());
+
+    let mut content_type = Headers::new();
+    content_type.set_raw("Content-Type",
+                         vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let parsed = read_multipart_body(&mut &body[..], &content_type, false, false).unwrap();
+    assert_eq!(parsed, nodes);
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error as StdError;
+
+    let err = Error::from(::std::io::Error::other("oops"));
+    let source = err.source().unwrap();
+    assert_eq!(format!("{}", source), "oops");
+    assert!(format!("{:?}", err).ends_with(": Custom { kind: Other, error: \"oops\" }"));
+
+    assert!(Error::NotMultipart.source().is_none());
+    assert_eq!(format!("{}", Error::NotMultipart),
+               "The Hyper request Content-Type top-level Mime was not multipart.");
+}
+
+#[test]
+fn test_error_into_io_error() {
+    use std::io::ErrorKind;
+
+    let err: ::std::io::Error = Error::Decoding("bad base64".into()).into();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let inner = err.into_inner().unwrap().downcast::<Error>().unwrap();
+    match *inner {
+        Error::Decoding(ref msg) => assert_eq!(msg, "bad base64"),
+        ref e => panic!("wrong error: {:?}", e),
+    }
+
+    let err: ::std::io::Error = Error::Io(::std::io::Error::new(ErrorKind::BrokenPipe, "gone"))
+        .into();
+    assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+    assert_eq!(format!("{}", err), "gone");
+
+    // Through `?`
+    fn parse(body: &[u8]) -> ::std::io::Result<Vec<Node>> {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"text/plain".to_vec()]);
+        Ok(read_multipart_body(&mut &body[..], &headers, false, false)?)
+    }
+    assert_eq!(parse(b"").unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_base64_transfer_encoding() {
+    let decoding = ReadOptions { decode_transfer_encoding: true, ..ReadOptions::default() };
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"pixel\"\r\n\
+                  Content-Type: image/png\r\n\
+                  Content-Transfer-Encoding: base64\r\n\
+                  \r\n\
+                  iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR4nGP4z8DwHwAFAAH/iZk9\r\n\
+                  HQAAAABJRU5ErkJggg==\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart_with_options(&mut &input[..], &decoding).unwrap();
+    assert_eq!(nodes.len(), 1);
+    if let Node::Part(ref part) = nodes[0] {
+        assert_eq!(part.body.len(), 70);
+        assert_eq!(&part.body[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&part.body[62..], b"IEND\xaeB`\x82");
+    } else {
+        panic!("1st node of wrong type");
+    }
+
+    // Without decoding, the body is left as received
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    if let Node::Part(ref part) = nodes[0] {
+        assert!(part.body.starts_with(b"iVBORw0KGgo"));
+    } else {
+        panic!("1st node of wrong type");
+    }
+}
+
+#[test]
+fn test_base64_transfer_encoding_malformed() {
+    let decoding = ReadOptions { decode_transfer_encoding: true, ..ReadOptions::default() };
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"broken\"\r\n\
+                  Content-Transfer-Encoding: base64\r\n\
+                  \r\n\
+                  iVBORw0KGgo*AAAA\r\n\
+                  --AaB03x--";
+
+    match read_multipart_with_options(&mut &input[..], &decoding) {
+        Err(Error::Decoding(msg)) => assert!(msg.contains("part \"broken\""), "{}", msg),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+// A minimal quoted-printable encoder, producing soft line breaks every 76 characters.
+fn encode_quoted_printable(input: &[u8]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::new();
+    let mut line_len = 0;
+    for &b in input {
+        let encoded = if b == b'=' || !(b' '..=b'~').contains(&b) {
+            format!("={:02X}", b).into_bytes()
+        } else {
+            vec![b]
+        };
+        if line_len + encoded.len() > 75 {
+            output.extend(b"=\r\n");
+            line_len = 0;
+        }
+        line_len += encoded.len();
+        output.extend(encoded);
+    }
+    output
+}
+
+#[test]
+fn test_quoted_printable_transfer_encoding() {
+    let decoding = ReadOptions { decode_transfer_encoding: true, ..ReadOptions::default() };
+    let text = "Prix = 5€, café crème; a line long enough to need at least one soft line break \
+                somewhere along the way.";
+    let encoded = encode_quoted_printable(text.as_bytes());
+    assert!(encoded.windows(3).any(|w| w == b"=\r\n"));
+
+    let mut input: Vec<u8> = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                               \r\n\
+                               --AaB03x\r\n\
+                               Content-Type: text/plain; charset=utf-8\r\n\
+                               Content-Transfer-Encoding: quoted-printable\r\n\
+                               \r\n".to_vec();
+    input.extend(&encoded);
+    input.extend(b"\r\n--AaB03x--");
+
+    let nodes = read_multipart_with_options(&mut &input[..], &decoding).unwrap();
+    if let Node::Part(ref part) = nodes[0] {
+        assert_eq!(::std::str::from_utf8(&part.body).unwrap(), text);
+    } else {
+        panic!("1st node of wrong type");
+    }
+
+    // Lowercase hex digits and soft line breaks with transport padding
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Transfer-Encoding: quoted-printable\r\n\
+                  \r\n\
+                  a=3Db=3d=  \r\n\
+                  c=c3=a9\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart_with_options(&mut &input[..], &decoding).unwrap();
+    if let Node::Part(ref part) = nodes[0] {
+        assert_eq!(::std::str::from_utf8(&part.body).unwrap(), "a=b=cé");
+    } else {
+        panic!("1st node of wrong type");
+    }
+}
+
+#[test]
+fn test_quoted_printable_transfer_encoding_malformed() {
+    let decoding = ReadOptions { decode_transfer_encoding: true, ..ReadOptions::default() };
+    for body in &[&b"trailing ="[..], &b"bad =G1 escape"[..], &b"sign =+1"[..]] {
+        let mut input: Vec<u8> = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                                   \r\n\
+                                   --AaB03x\r\n\
+                                   Content-Transfer-Encoding: quoted-printable\r\n\
+                                   \r\n".to_vec();
+        input.extend(*body);
+        input.extend(b"\r\n--AaB03x--");
+
+        match read_multipart_with_options(&mut &input[..], &decoding) {
+            Err(Error::Decoding(msg)) => assert!(msg.contains("part #0"), "{}", msg),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
+
+// A reader which hands out a single byte per read() call
+struct OneByteReader<'a>(&'a [u8]);
+impl<'a> Read for OneByteReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+        if self.0.is_empty() || buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.0[0];
+        self.0 = &self.0[1..];
+        Ok(1)
+    }
+}
+
+#[test]
+fn test_streaming_parts() {
+    let input = b"preamble\r\n\
+                  --abcdefg\r\n\
+                  Content-Type: application/json\r\n\
+                  \r\n\
+                  {\"id\": 15}\r\n\
+                  --abcdefg\r\n\
+                  Content-Disposition: Attachment; filename=\"file.txt\"\r\n\
+                  \r\n\
+                  Almost a boundary:\r\n--abcdef\r\n\
+                  --abcdefg\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  skipped\r\n\
+                  --abcdefg--";
+
+    let expected: Vec<&[u8]> = vec![b"{\"id\": 15}", b"Almost a boundary:\r\n--abcdef"];
+
+    // Whole-buffer and byte-at-a-time reads must give identical results
+    let whole = MultipartParts::new(&input[..], b"abcdefg");
+    let fragmented = MultipartParts::new(OneByteReader(&input[..]), b"abcdefg");
+    fn check<R: Read>(mut parts: MultipartParts<R>, expected: &[&[u8]]) {
+        for body in expected {
+            let mut part = parts.next_part().unwrap().unwrap();
+            let mut content: Vec<u8> = Vec::new();
+            part.read_to_end(&mut content).unwrap();
+            assert_eq!(&content[..], *body);
+        }
+
+        // The third part is left unread
+        let part = parts.next_part().unwrap().unwrap();
+        assert_eq!(part.headers.get::<ContentType>().unwrap().0, mime!(Text/Plain));
+
+        assert!(parts.next_part().is_none());
+    }
+    check(whole, &expected);
+    check(fragmented, &expected);
+}
+
+#[test]
+fn test_streaming_parts_truncated() {
+    let input = b"--abcdefg\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  cut off";
+    let mut parts = MultipartParts::new(&input[..], b"abcdefg");
+    let mut part = parts.next_part().unwrap().unwrap();
+    let mut content: Vec<u8> = Vec::new();
+    let err = part.read_to_end(&mut content).unwrap_err();
+    assert_eq!(err.kind(), ::std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn test_limits() {
+    let many_parts = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                       \r\n\
+                       --AaB03x\r\n\
+                       Content-Type: text/plain\r\n\
+                       \r\n\
+                       one\r\n\
+                       --AaB03x\r\n\
+                       Content-Type: text/plain\r\n\
+                       \r\n\
+                       two\r\n\
+                       --AaB03x\r\n\
+                       Content-Type: text/plain\r\n\
+                       \r\n\
+                       three\r\n\
+                       --AaB03x--";
+    let nested = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                   \r\n\
+                   --AaB03x\r\n\
+                   Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                   \r\n\
+                   --BbC04y\r\n\
+                   Content-Type: multipart/mixed; boundary=CcD05z\r\n\
+                   \r\n\
+                   --CcD05z\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   deep\r\n\
+                   --CcD05z--\r\n\
+                   --BbC04y--\r\n\
+                   --AaB03x--";
+    let big_part = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                     \r\n\
+                     --AaB03x\r\n\
+                     Content-Type: text/plain\r\n\
+                     \r\n\
+                     This body is longer than thirty-two bytes.\r\n\
+                     --AaB03x--";
+    let many_headers = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                         \r\n\
+                         --AaB03x\r\n\
+                         X-One: 1\r\n\
+                         X-Two: 2\r\n\
+                         X-Three: 3\r\n\
+                         \r\n\
+                         body\r\n\
+                         --AaB03x--";
+
+    let limits = Limits {
+        max_parts: 2,
+        max_depth: 1,
+        max_part_size: 32,
+        max_headers: 2,
+        max_header_size: 1024,
+    };
+    let cases: Vec<(&[u8], LimitKind)> = vec![
+        (many_parts, LimitKind::Parts),
+        (nested, LimitKind::Depth),
+        (big_part, LimitKind::PartSize),
+        (many_headers, LimitKind::Headers),
+    ];
+    for (input, kind) in cases {
+        // Within the default limits, all of these parse
+        assert!(read_multipart(&mut &input[..], false).is_ok());
+
+        match read_multipart_with_limits(&mut &input[..], false, &limits) {
+            Err(Error::LimitExceeded(k)) => assert_eq!(k, kind),
+            other => panic!("Expected {:?} to be exceeded, got {:?}", kind, other),
+        }
+    }
+}
+
+// Counts what each thread has allocated, so that a test can check how much memory parsing
+// takes whatever other tests are running
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATED: ::std::cell::Cell<isize> = const { ::std::cell::Cell::new(0) };
+    static PEAK: ::std::cell::Cell<isize> = const { ::std::cell::Cell::new(0) };
+}
+
+fn count_allocated(change: isize) {
+    let _ = ALLOCATED.try_with(|allocated| {
+        allocated.set(allocated.get() + change);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+    });
+}
+
+unsafe impl ::std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: ::std::alloc::Layout) -> *mut u8 {
+        count_allocated(layout.size() as isize);
+        ::std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout) {
+        count_allocated(-(layout.size() as isize));
+        ::std::alloc::System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: ::std::alloc::Layout, new_size: usize)
+                      -> *mut u8
+    {
+        count_allocated(new_size as isize - layout.size() as isize);
+        ::std::alloc::System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+// The most this thread had allocated at once while running `f`, beyond what it had before.
+fn peak_allocation<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let before = ALLOCATED.with(|allocated| allocated.get());
+    PEAK.with(|peak| peak.set(before));
+    let result = f();
+    (result, (PEAK.with(|peak| peak.get()) - before).max(0) as usize)
+}
+
+// A body of `count` bytes of `byte`, then `rest`
+fn padded_body(byte: u8, count: u64, rest: &[u8]) -> impl Read + '_ {
+    ::std::io::repeat(byte).take(count).chain(rest)
+}
+
+#[test]
+fn test_preamble_not_buffered() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let rest = b"\r\n--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x--";
+    let size = 16 << 20;
+
+    // A preamble much larger than any limit is passed over, unless it is asked for
+    let (nodes, peak) = peak_allocation(|| {
+        read_multipart_body(&mut padded_body(b'p', size, rest), &headers, false, false)
+    });
+    assert_eq!(nodes.unwrap().len(), 1);
+    assert!(peak < 1 << 20, "{} bytes allocated", peak);
+
+    let (count, peak) = peak_allocation(|| {
+        let mut parts = MultipartParts::new(padded_body(b'p', size, rest), b"AaB03x");
+        let mut count = 0;
+        while let Some(part) = parts.next_part() {
+            part.unwrap();
+            count += 1;
+        }
+        count
+    });
+    assert_eq!(count, 1);
+    assert!(peak < 1 << 20, "{} bytes allocated", peak);
+
+    let mut stream = (&b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\r\n"[..])
+        .chain(padded_body(b'p', 1000, rest));
+    let multipart = read_multipart_full(&mut stream, &ReadOptions::default()).unwrap();
+    assert_eq!(multipart.preamble, vec![b'p'; 1000]);
+}
+
+#[test]
+fn test_delimiter_line_limit() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let size = 16 << 20;
+    let is_limited = |result: Result<(), Error>| {
+        matches!(result, Err(Error::LimitExceeded(LimitKind::HeaderSize)))
+    };
+
+    // A delimiter line which never ends fails once it is longer than the headers may be,
+    // rather than being buffered
+    let start = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x ";
+    {
+        let stream = || (&start[..]).chain(::std::io::repeat(b'x').take(size));
+        let (result, peak) = peak_allocation(|| {
+            read_multipart_body(&mut stream(), &headers, false, false).map(|_| ())
+        });
+        assert!(is_limited(result));
+        assert!(peak < 1 << 20, "{} bytes allocated", peak);
+
+        let (result, peak) = peak_allocation(|| {
+            let mut parts = MultipartParts::new(stream(), b"AaB03x");
+            while let Some(part) = parts.next_part() {
+                part?;
+            }
+            Ok(())
+        });
+        assert!(is_limited(result));
+        assert!(peak < 1 << 20, "{} bytes allocated", peak);
+    }
+}
+
+#[test]
+fn test_leaves() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"album\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.txt\"\r\n\
+                  \r\n\
+                  sand\r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: form-data; name=\"day2\"\r\n\
+                  Content-Type: multipart/mixed; boundary=CcD05z\r\n\
+                  \r\n\
+                  --CcD05z\r\n\
+                  Content-Disposition: form-data; name=\"caption\"\r\n\
+                  \r\n\
+                  Mountains\r\n\
+                  --CcD05z--\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"footer\"\r\n\
+                  \r\n\
+                  The end\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    let found: Vec<(Vec<String>, Leaf)> = leaves(&nodes).collect();
+    assert_eq!(found.len(), 4);
+
+    let paths: Vec<Vec<&str>> = found.iter()
+        .map(|(path, _)| path.iter().map(|s| &**s).collect())
+        .collect();
+    assert_eq!(paths, vec![
+        vec!["title"],
+        vec!["album"],
+        vec!["album", "day2", "caption"],
+        vec!["footer"],
+    ]);
+
+    match found[1].1 {
+        Leaf::File(filepart) => assert_eq!(filepart.filename().unwrap().unwrap(), "beach.txt"),
+        _ => panic!("2nd leaf of wrong type"),
+    }
+    match found[2].1 {
+        Leaf::Part(part) => assert_eq!(part.body, b"Mountains"),
+        _ => panic!("3rd leaf of wrong type"),
+    }
+
+    // Walking a single nested node gives the leaves below it
+    assert_eq!(nodes[1].leaves().count(), 2);
+}
+
+#[test]
+fn test_builder() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("notes.txt");
+    ::std::fs::write(&path, b"file contents").unwrap();
+
+    let nodes = MultipartBuilder::new()
+        .add_text("say \"hi\"", "Hello")
+        .add_file("upload", &path)
+        .add_bytes("photo", "café.gif", mime!(Image/Gif), b"GIF89a".to_vec())
+        .build();
+
+    let mut output: Vec<u8> = Vec::new();
+    let count = write_multipart(&mut output, b"AaB03x", &nodes).unwrap();
+    assert_eq!(count, output.len());
+    assert_eq!(::std::str::from_utf8(&output).unwrap(),
+               "--AaB03x\r\n\
+                Content-Disposition: form-data; name=\"say \\\"hi\\\"\"\r\n\
+                \r\n\
+                Hello\r\n\
+                --AaB03x\r\n\
+                Content-Disposition: form-data; name=\"upload\"; filename=\"notes.txt\"\r\n\
+                Content-Type: application/octet-stream\r\n\
+                \r\n\
+                file contents\r\n\
+                --AaB03x\r\n\
+                Content-Disposition: form-data; name=\"photo\"; filename=\"caf_.gif\"; \
+                filename*=UTF-8''caf%C3%A9.gif\r\n\
+                Content-Type: image/gif\r\n\
+                \r\n\
+                GIF89a\r\n\
+                --AaB03x--");
+
+    // write_to() generates its own boundary, which must parse back
+    let mut output: Vec<u8> = Vec::new();
+    let (boundary, count) = MultipartBuilder::new()
+        .add_text("first_name", "Michael")
+        .add_file("upload", &path)
+        .write_to(&mut output)
+        .unwrap();
+    assert_eq!(count, output.len());
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![
+        format!("multipart/form-data; boundary={}", String::from_utf8(boundary).unwrap())
+            .into_bytes()]);
+    let parsed = read_multipart_body(&mut &output[..], &headers, false, false).unwrap();
+    assert_eq!(parsed.len(), 2);
+    if let Node::File(ref filepart) = parsed[1] {
+        assert_eq!(filepart.filename().unwrap().unwrap(), "notes.txt");
+        assert_eq!(::std::fs::read(&filepart.path).unwrap(), b"file contents");
+    } else {
+        panic!("2nd node of wrong type");
+    }
+}
+
+#[test]
+fn test_boundary_validation_and_collision() {
+    assert!(validate_boundary(b"AaB03x").is_ok());
+    assert!(validate_boundary(b"gc0p4Jq0M2Yt08j34c0p'()+_,-./:=? end").is_ok());
+    assert!(matches!(validate_boundary(b""), Err(Error::InvalidBoundary)));
+    assert!(matches!(validate_boundary(b"trailing "), Err(Error::InvalidBoundary)));
+    assert!(matches!(validate_boundary(b"semi;colon"), Err(Error::InvalidBoundary)));
+    assert!(validate_boundary(&[b'a'; 70]).is_ok());
+    assert!(matches!(validate_boundary(&[b'a'; 71]), Err(Error::InvalidBoundary)));
+    assert!(matches!(validate_boundary(&[b'a'; 100]), Err(Error::InvalidBoundary)));
+
+    let nodes = MultipartBuilder::new()
+        .add_text("innocent", "nothing to see")
+        .add_text("sneaky", "line one\r\n--AaB03x\r\nline two")
+        .build();
+    let mut output: Vec<u8> = Vec::new();
+    match write_multipart(&mut output, b"AaB03x", &nodes) {
+        Err(Error::BoundaryCollision) => {},
+        other => panic!("Expected a collision, got {:?}", other),
+    }
+    assert!(output.is_empty());
+
+    // A part within a nested multipart may not contain the outer boundary either
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=BbC04y".to_vec()]);
+    let nodes = vec![Node::Multipart((headers, nodes))];
+    assert!(matches!(write_multipart_chunked(&mut output, b"AaB03x", &nodes),
+                     Err(Error::BoundaryCollision)));
+
+    // Files are scanned too
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("collision.txt");
+    let mut content = vec![b'x'; 10000];
+    content.extend(b"--AaB03x");
+    ::std::fs::write(&path, &content).unwrap();
+    let nodes = MultipartBuilder::new().add_file("upload", &path).build();
+    assert!(matches!(write_multipart(&mut output, b"AaB03x", &nodes),
+                     Err(Error::BoundaryCollision)));
+}
+
+#[test]
+fn test_header_injection() {
+    // A field name built by hand with a CRLF in it, as if taken from user input
+    let name = "innocent\"\r\nContent-Type: text/html\r\n\r\n<script>";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition",
+                    vec![format!("form-data; name=\"{}\"", name).into_bytes()]);
+    let nodes = vec![Node::Part(Part { headers: headers.clone(), body: b"value".to_vec() })];
+    let mut output: Vec<u8> = Vec::new();
+    match write_multipart(&mut output, b"AaB03x", &nodes) {
+        Err(Error::InvalidHeaderValue(ref header)) if header == "Content-Disposition" => {},
+        other => panic!("Expected an invalid header, got {:?}", other),
+    }
+    assert!(output.is_empty());
+    assert!(matches!(write_multipart_generate(&mut output, &nodes),
+                     Err(Error::InvalidHeaderValue(_))));
+
+    // Within a nested multipart, and in a header name
+    let mut outer = Headers::new();
+    outer.set_raw("Content-Type", vec![b"multipart/mixed; boundary=BbC04y".to_vec()]);
+    let nested = vec![Node::Multipart((outer, nodes))];
+    assert!(matches!(write_multipart_chunked(&mut output, b"AaB03x", &nested),
+                     Err(Error::InvalidHeaderValue(_))));
+    let mut bad_name = Headers::new();
+    bad_name.set_raw("X-Injected\r\nContent-Type", vec![b"text/html".to_vec()]);
+    let mut writer = MultipartWriter::new(Vec::new(), b"AaB03x").unwrap();
+    assert!(matches!(writer.write_part(&bad_name, &mut &b"value"[..]),
+                     Err(Error::InvalidHeaderValue(_))));
+    assert_eq!(writer.count(), 0);
+    assert!(writer.get_ref().is_empty());
+    assert!(matches!(writer.write_part(&headers, &mut &b"value"[..]),
+                     Err(Error::InvalidHeaderValue(_))));
+
+    // The builder percent-encodes the line break instead, so the name stays in one header
+    let nodes = MultipartBuilder::new().add_text(name, "value").build();
+    write_multipart(&mut output, b"AaB03x", &nodes).unwrap();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let parsed = read_multipart_body(&mut &output[..], &headers, false, false).unwrap();
+    assert_eq!(parsed.len(), 1);
+    let part = match parsed[0] {
+        Node::Part(ref part) => part,
+        _ => panic!("Expected a part"),
+    };
+    assert_eq!(part.headers.len(), 1);
+    assert!(part.headers.get_raw("Content-Type").is_none());
+    assert_eq!(part.body, b"value");
+    assert_eq!(super::get_content_disposition_name(&part.headers).unwrap(),
+               "innocent\"%0D%0AContent-Type: text/html%0D%0A%0D%0A<script>");
+}
+
+#[test]
+fn test_nested_output() {
+    let inner = MultipartBuilder::new()
+        .add_bytes("file1", "file1.txt", mime!(Text/Plain), b"... contents of file1.txt ...".to_vec())
+        .add_text("note", "hello")
+        .build();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![b"form-data; name=\"files\"".to_vec()]);
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=BbC04y".to_vec()]);
+    let nodes = vec![Node::Multipart((headers, inner)),
+                     MultipartBuilder::new().add_text("after", "last").build().remove(0)];
+
+    let mut output: Vec<u8> = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\r\n".to_vec();
+    write_multipart(&mut output, b"AaB03x", &nodes).unwrap();
+    assert!(!output.windows(8).any(|w| w == b"----BbC0"));
+
+    let parsed = read_multipart(&mut &output[..], false).unwrap();
+    assert_eq!(parsed.len(), 2);
+    if let Node::Multipart((_, ref subnodes)) = parsed[0] {
+        assert_eq!(subnodes.len(), 2);
+    } else {
+        panic!("1st node of wrong type");
+    }
+    if let Node::Part(ref part) = parsed[1] {
+        assert_eq!(part.body, b"last");
+    } else {
+        panic!("2nd node of wrong type");
+    }
+}
+
+fn part_with_disposition(value: &[u8]) -> Part {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![value.to_vec()]);
+    Part { headers, body: Vec::new() }
+}
+
+#[test]
+fn test_part_name_and_filename() {
+    let part = part_with_disposition(b"form-data; name=\"quoted\"; filename=\"report.pdf\"");
+    assert_eq!(part.name().unwrap(), "quoted");
+    assert_eq!(part.filename().unwrap().unwrap(), "report.pdf");
+
+    let part = part_with_disposition(b"form-data; name=unquoted; filename=plain.txt");
+    assert_eq!(part.name().unwrap(), "unquoted");
+    assert_eq!(part.filename().unwrap().unwrap(), "plain.txt");
+
+    let part = part_with_disposition(b"attachment; filename*=utf-8''%E2%82%AC%20rates.txt");
+    assert!(part.name().is_none());
+    assert_eq!(part.filename().unwrap().unwrap(), "\u{20ac} rates.txt");
+
+    let part = Part { headers: Headers::new(), body: Vec::new() };
+    assert!(part.name().is_none());
+    assert!(part.filename().unwrap().is_none());
+
+    // FileParts, as produced by the parser
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"data.csv\"\r\n\
+                  \r\n\
+                  a,b,c\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    if let Node::File(ref filepart) = nodes[0] {
+        assert_eq!(filepart.name().unwrap(), "upload");
+        assert_eq!(filepart.filename().unwrap().unwrap(), "data.csv");
+    } else {
+        panic!("1st node of wrong type");
+    }
+}
+
+#[test]
+fn test_extended_filename() {
+    let part = part_with_disposition(b"attachment; filename*=UTF-8''%E2%82%AC.txt");
+    assert_eq!(part.filename().unwrap().unwrap(), "\u{20ac}.txt");
+
+    let part = part_with_disposition(b"attachment; filename*=ISO-8859-1'fr'caf%E9.txt");
+    assert_eq!(part.filename().unwrap().unwrap(), "caf\u{e9}.txt");
+
+    // The extended form wins, whichever order the parameters come in
+    let part = part_with_disposition(
+        b"attachment; filename*=UTF-8''%E2%82%AC.txt; filename=\"EUR.txt\"");
+    assert_eq!(part.filename().unwrap().unwrap(), "\u{20ac}.txt");
+    let part = part_with_disposition(
+        b"attachment; filename=\"EUR.txt\"; filename*=UTF-8''%E2%82%AC.txt");
+    assert_eq!(part.filename().unwrap().unwrap(), "\u{20ac}.txt");
+
+    let part = part_with_disposition(b"attachment; filename*=UTF-8''%E2%8G%AC.txt");
+    assert!(matches!(part.filename(), Err(Error::Decoding(_))));
+    let part = part_with_disposition(b"attachment; filename*=UTF-8''trailing%2");
+    assert!(matches!(part.filename(), Err(Error::Decoding(_))));
+    let part = part_with_disposition(b"attachment; filename*=x-klingon''abc.txt");
+    assert!(matches!(part.filename(), Err(Error::Decoding(_))));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_parser() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"submit-name\"\r\n\
+                  \r\n\
+                  Larry\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"files\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"file1.txt\"\r\n\
+                  \r\n\
+                  ... contents of file1.txt ...\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"after\"\r\n\
+                  \r\n\
+                  last\r\n\
+                  --AaB03x--";
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let nodes = runtime.block_on(async {
+        let reader = tokio::io::BufReader::new(&input[..]);
+        read_multipart_body_async(reader, b"AaB03x", false).await
+    }).unwrap();
+
+    assert_eq!(nodes.len(), 3);
+    if let Node::Part(ref part) = nodes[0] {
+        assert_eq!(part.body, b"Larry");
+    } else {
+        panic!("1st node of wrong type");
+    }
+    if let Node::Multipart((_, ref subnodes)) = nodes[1] {
+        if let Node::File(ref filepart) = subnodes[0] {
+            assert_eq!(filepart.size, Some(29));
+            assert_eq!(::std::fs::read(&filepart.path).unwrap(),
+                       b"... contents of file1.txt ...");
+        } else {
+            panic!("1st subnode of wrong type");
+        }
+    } else {
+        panic!("2nd node of wrong type");
+    }
+    if let Node::Part(ref part) = nodes[2] {
+        assert_eq!(part.body, b"last");
+    } else {
+        panic!("3rd node of wrong type");
+    }
+
+    // And the same, a byte at a time
+    let fragmented = runtime.block_on(async {
+        use tokio::io::AsyncWriteExt;
+        let (mut tx, rx) = tokio::io::duplex(1);
+        let writer = async move {
+            for b in input.iter() {
+                tx.write_all(&[*b]).await.unwrap();
+            }
+        };
+        let (_, nodes) = tokio::join!(writer,
+                                      read_multipart_body_async(rx, b"AaB03x", false));
+        nodes
+    }).unwrap();
+    assert_eq!(fragmented.len(), 3);
+}
+
+// The async parser reads bodies just as the blocking one does, with whatever options
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_matches_sync() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let bodies: Vec<&[u8]> = vec![
+        // Two parts, one nested, with a preamble and epilogue
+        b"preamble\r\n\
+          --AaB03x\r\n\
+          Content-Disposition: form-data; name=\"field\"\r\n\
+          \r\n\
+          a\nb\rc\r\n\
+          --AaB03x\r\n\
+          Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+          \r\n\
+          --BbC04y\r\n\
+          Content-Type: text/plain\r\n\
+          \r\n\
+          nested\r\n\
+          --BbC04y--\r\n\
+          nested epilogue\r\n\
+          --AaB03x--\r\n\
+          epilogue",
+        // Padding after the delimiters
+        b"--AaB03x \t\r\n\
+          Content-Disposition: form-data; name=\"field\"\r\n\
+          \r\n\
+          value\r\n\
+          --AaB03x  \r\n\
+          \r\n\
+          second\r\n\
+          --AaB03x--",
+        // Bare LFs, mixed in with CRLFs
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"field\"\n\
+          Content-Type: text/plain\r\n\
+          \n\
+          line one\r\nline two\n\
+          --AaB03x\n\
+          \n\
+          ends in CR\r\r\n\
+          --AaB03x--\n",
+        // Bare LFs throughout
+        b"--AaB03x\n\
+          Content-Disposition: form-data; name=\"field\"\n\
+          \n\
+          value\n\
+          --AaB03x--",
+        // A delimiter after a bare LF within a part
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"field\"\r\n\
+          \r\n\
+          value\n\
+          --AaB03x\r\n\
+          smuggled\r\n\
+          --AaB03x--",
+        // Truncated within a part, after and within a nested multipart, and after a
+        // delimiter
+        b"--AaB03x\r\n\
+          \r\n\
+          val",
+        b"--AaB03x\r\n\
+          Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+          \r\n\
+          --BbC04y\r\n\
+          Content-Type: text/plain\r\n\
+          \r\n\
+          nested\r\n\
+          --BbC04y--\r\n\
+          nested epilogue",
+        b"--AaB03x\r\n\
+          Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+          \r\n\
+          --BbC04y\r\n\
+          Content-Type: text/plain\r\n\
+          \r\n\
+          nest",
+        b"--AaB03x\r\n\
+          \r\n\
+          value\r\n\
+          --AaB03x\r\n",
+        // Cut off within a nested multipart by the enclosing one's delimiter
+        b"--AaB03x\r\n\
+          Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+          \r\n\
+          --BbC04y\r\n\
+          Content-Type: text/plain\r\n\
+          \r\n\
+          nest\r\n\
+          --AaB03x\r\n\
+          \r\n\
+          next\r\n\
+          --AaB03x--",
+    ];
+    let mut variants = vec![ReadOptions::default(),
+                            ReadOptions { lenient: true, ..ReadOptions::default() },
+                            ReadOptions { strict_crlf: true, ..ReadOptions::default() },
+                            ReadOptions { require_disposition: true, ..ReadOptions::default() },
+                            ReadOptions::with_buffer_capacity(1)];
+    variants[4].lenient = true;
+    variants.push(ReadOptions { limits: Limits { max_parts: 2, max_header_size: 30,
+                                                 ..Limits::default() },
+                                ..ReadOptions::default() });
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    for options in &mut variants {
+        options.in_memory_only = true;
+        for body in &bodies {
+            let sync = read_multipart_body_with_options(&mut &body[..], &headers, options);
+            let asynchronous = runtime.block_on(
+                read_multipart_body_async_with_options(&body[..], b"AaB03x", options));
+            assert_eq!(format!("{:?}", asynchronous), format!("{:?}", sync),
+                       "{:?} with {:?}", String::from_utf8_lossy(body), options);
+        }
+    }
+}
+
+// The async parser spills, keeps and hashes file parts as the blocking one does
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_file_part_options() {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"file\"; filename=\"abc.txt\"\r\n\
+                  \r\n\
+                  abc\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"text\"\r\n\
+                  \r\n\
+                  hello world\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"short\"\r\n\
+                  \r\n\
+                  hi\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"large\"; filename=\"large.bin\"\r\n\
+                  \r\n");
+    body.extend(vec![b'x'; 100]);
+    body.extend(b"\r\n--AaB03x--");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    #[allow(unused_mut)]
+    let mut options = ReadOptions {
+        spill_threshold: Some(4),
+        tee_file_parts: Some(16),
+        ..ReadOptions::with_buffer_capacity(3)
+    };
+    #[cfg(feature = "sha2")]
+    {
+        options.hash_file_parts = true;
+    }
+    // Everything but the paths of the files, which differ
+    let describe = |nodes: Vec<Node>| -> Vec<String> {
+        nodes.iter().map(|node| match *node {
+            Node::Part(ref part) => format!("part {:?}", part.body),
+            Node::File(ref filepart) => format!("file {:?} {:?} {:?} {:?}",
+                                                filepart.read_to_vec().unwrap(),
+                                                filepart.size, filepart.bytes, filepart.hash),
+            Node::Multipart(_) => panic!("node of wrong type"),
+        }).collect()
+    };
+    let sync = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    assert!(matches!(sync[1], Node::File(ref filepart) if filepart.bytes.is_some()));
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let asynchronous = runtime.block_on(
+        read_multipart_body_async_with_options(&body[..], b"AaB03x", &options)).unwrap();
+    assert_eq!(describe(asynchronous), describe(sync));
+}
+
+#[test]
+fn test_file_part_config() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"evil\"; filename=\"../../etc/passwd\"\r\n\
+                  \r\n\
+                  root:x:0:0\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"dots\"; filename=\"..\"\r\n\
+                  \r\n\
+                  dots\r\n\
+                  --AaB03x--";
+
+    let target = tempfile::tempdir().unwrap();
+    let options = ReadOptions {
+        file_parts: FilePartConfig {
+            directory: Some(target.path().to_owned()),
+            prefix: "upload-".to_owned(),
+            preserve_filename: true,
+        },
+        ..ReadOptions::default()
+    };
+    let nodes = read_multipart_with_options(&mut &input[..], &options).unwrap();
+    assert_eq!(nodes.len(), 2);
+
+    if let Node::File(ref filepart) = nodes[0] {
+        // Only the final component is used, and it stays within the target directory
+        assert_eq!(filepart.path.file_name().unwrap(), "passwd");
+        assert!(filepart.path.starts_with(target.path()));
+        assert_eq!(filepart.path.parent().unwrap().parent().unwrap(), target.path());
+        assert_eq!(::std::fs::read(&filepart.path).unwrap(), b"root:x:0:0");
+    } else {
+        panic!("1st node of wrong type");
+    }
+    if let Node::File(ref filepart) = nodes[1] {
+        // Nothing usable remains, so a name is generated
+        let name = filepart.path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("upload-") && name.len() > "upload-".len());
+        assert!(filepart.path.starts_with(target.path()));
+    } else {
+        panic!("2nd node of wrong type");
+    }
+
+    assert_eq!(options::sanitize_filename("C:\\Users\\me\\a.txt").as_deref(), Some("a.txt"));
+    assert_eq!(options::sanitize_filename("a\u{0}b").as_deref(), Some("ab"));
+    assert_eq!(options::sanitize_filename("dir/"), None);
+    assert_eq!(options::sanitize_filename("a<b>:c|d?*\"e\".txt").as_deref(),
+               Some("a_b__c_d___e_.txt"));
+    assert_eq!(options::sanitize_filename("report.txt. . ").as_deref(), Some("report.txt"));
+    assert_eq!(options::sanitize_filename("CON").as_deref(), Some("_CON"));
+    assert_eq!(options::sanitize_filename("nul.tar.gz").as_deref(), Some("_nul.tar.gz"));
+    assert_eq!(options::sanitize_filename("Com1 .txt").as_deref(), Some("_Com1 .txt"));
+    assert_eq!(options::sanitize_filename("com0.txt").as_deref(), Some("com0.txt"));
+    assert_eq!(options::sanitize_filename("console.txt").as_deref(), Some("console.txt"));
+
+    // The files and their directories are removed on drop
+    let path = if let Node::File(ref filepart) = nodes[0] { filepart.path.clone() } else {
+        unreachable!()
+    };
+    drop(nodes);
+    assert!(!path.exists());
+    assert!(!path.parent().unwrap().exists());
+}
+
+#[test]
+fn test_spill_threshold() {
+    let large = vec![b'x'; 100];
+    let mut input: Vec<u8> = b"--AaB03x\r\n\
+                               Content-Disposition: form-data; name=\"small\"\r\n\
+                               \r\n\
+                               0123456789\r\n\
+                               --AaB03x\r\n\
+                               Content-Disposition: form-data; name=\"large\"\r\n\
+                               \r\n".to_vec();
+    input.extend(&large);
+    input.extend(b"\r\n--AaB03x--");
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    let nodes = read_multipart_body_threshold(&mut &input[..], &headers, 32).unwrap();
+    assert_eq!(nodes.len(), 2);
+    if let Node::Part(ref part) = nodes[0] {
+        assert_eq!(part.body, b"0123456789");
+    } else {
+        panic!("1st node of wrong type");
+    }
+    if let Node::File(ref filepart) = nodes[1] {
+        assert_eq!(filepart.name().as_deref(), Some("large"));
+        assert_eq!(filepart.size, Some(100));
+        assert_eq!(::std::fs::read(&filepart.path).unwrap(), large);
+    } else {
+        panic!("2nd node of wrong type");
+    }
+
+    // A part of exactly the threshold stays in memory
+    let nodes = read_multipart_body_threshold(&mut &input[..], &headers, 100).unwrap();
+    assert!(matches!(nodes[1], Node::Part(ref part) if part.body == large));
+
+    // Truncated after spilling
+    let truncated = &input[..input.len() - 12];
+    match read_multipart_body_threshold(&mut &truncated[..], &headers, 32) {
+        Err(Error::Truncated { .. }) => {},
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_field_map() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"file\"\r\n\
+                  \r\n\
+                  first\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"other\"\r\n\
+                  \r\n\
+                  between\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"file\"\r\n\
+                  \r\n\
+                  second\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"file\"\r\n\
+                  \r\n\
+                  third\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    let map = into_field_map(nodes);
+    assert_eq!(map.len(), 2);
+    let bodies: Vec<&[u8]> = map["file"].iter().map(|node| match *node {
+        Node::Part(ref part) => &part.body[..],
+        _ => panic!("node of wrong type"),
+    }).collect();
+    assert_eq!(bodies, vec![&b"first"[..], &b"second"[..], &b"third"[..]]);
+    assert_eq!(map["other"].len(), 1);
+}
+
+#[test]
+fn test_write_large_file_part() {
+    // Counts what is written, remembering the largest single write
+    struct CountingWriter {
+        count: usize,
+        largest: usize,
+    }
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.count += buf.len();
+            self.largest = self.largest.max(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("large");
+    let size = 8 * 1024 * 1024;
+    ::std::fs::write(&path, vec![b'x'; size]).unwrap();
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"application/octet-stream".to_vec()]);
+    let mut filepart = FilePart::new(headers, &path);
+    filepart.size = Some(size);
+    let nodes = vec![Node::File(filepart)];
+
+    let mut writer = CountingWriter { count: 0, largest: 0 };
+    let count = write_multipart(&mut writer, b"AaB03x", &nodes).unwrap();
+    assert_eq!(count, writer.count);
+    assert!(count > size);
+    // The file was streamed through in pieces, not written out in one buffer
+    assert!(writer.largest <= 64 * 1024);
+
+    // A size which does not match the file on disk is an error
+    let mut nodes = nodes;
+    if let Node::File(ref mut filepart) = nodes[0] {
+        filepart.size = Some(size - 1);
+    }
+    match write_multipart(&mut ::std::io::sink(), b"AaB03x", &nodes) {
+        Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::InvalidData => {},
+        other => panic!("expected Error::Io, got {:?}", other),
+    }
+    match write_multipart_chunked(&mut ::std::io::sink(), b"AaB03x", &nodes) {
+        Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::InvalidData => {},
+        other => panic!("expected Error::Io, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_truncated() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  \r\n\
+                  0123456789\r\n\
+                  --AaB03x--";
+
+    // Cut off within the file
+    let cut = input.len() - b"56789\r\n--AaB03x--".len();
+    match read_multipart(&mut &input[..cut], false) {
+        Err(Error::Truncated { ref expected_boundary, bytes_read }) => {
+            assert_eq!(expected_boundary, b"\r\n--AaB03x");
+            assert_eq!(bytes_read, 5);
+        },
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+
+    // The streaming parsers and the pusher report it the same way, however the body is
+    // split up
+    let body = &input[b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\r\n".len()..cut];
+    let is_expected = |e: &Error| match *e {
+        Error::Truncated { ref expected_boundary, bytes_read } =>
+            expected_boundary == b"\r\n--AaB03x" && bytes_read == 5,
+        _ => false,
+    };
+    for &whole in &[true, false] {
+        let stream: Box<dyn Read> = if whole {
+            Box::new(body)
+        } else {
+            Box::new(OneByteReader(body))
+        };
+        let mut parts = MultipartParts::new(stream, b"AaB03x");
+        parts.next_part().unwrap().unwrap().copy_to(&mut ::std::io::sink(), usize::MAX).unwrap();
+        let err = parts.next_part().unwrap().unwrap().copy_to(&mut Vec::new(), usize::MAX)
+            .unwrap_err();
+        assert!(is_expected(&err), "got {:?}", err);
+
+        let stream: Box<dyn Read> = if whole {
+            Box::new(body)
+        } else {
+            Box::new(OneByteReader(body))
+        };
+        let mut reader = PartReader::new(stream, b"AaB03x");
+        reader.next_headers().unwrap().unwrap();
+        reader.next_headers().unwrap().unwrap();
+        let err = reader.skip().unwrap_err();
+        assert!(is_expected(&err), "got {:?}", err);
+    }
+    for chunk_size in 1..body.len() {
+        let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+        for chunk in body.chunks(chunk_size) {
+            pusher.push(chunk).unwrap();
+        }
+        let err = pusher.finish().unwrap_err();
+        assert!(is_expected(&err), "chunks of {} bytes: got {:?}", chunk_size, err);
+    }
+
+    // Cut off within an in-memory part, part way into the delimiter (which, as it was
+    // never completed, counts as body)
+    let cut = input.len() - b"AaB03x\r\nContent-Disposition: form-data; name=\"upload\"; \
+                              filename=\"a.txt\"\r\n\r\n0123456789\r\n--AaB03x--".len();
+    match read_multipart(&mut &input[..cut], false) {
+        Err(e @ Error::Truncated { .. }) => {
+            assert_eq!(format!("{}", e), "Truncated: the body ended after 9 bytes of a part, \
+                                          expecting \"\\r\\n--AaB03x\"");
+        },
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+
+    // Cut off after a nested multipart's closing delimiter, where the whole of the nested
+    // multipart counts as the part's body
+    let nested = b"--AaB03x\r\n\
+                   Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                   \r\n\
+                   --BbC04y\r\n\
+                   Content-Type: text/plain\r\n\
+                   \r\n\
+                   nested\r\n\
+                   --BbC04y--\r\n\
+                   epilogue";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=AaB03x".to_vec()]);
+    match read_multipart_body(&mut &nested[..], &headers, false, false) {
+        Err(Error::Truncated { bytes_read, .. }) => {
+            assert_eq!(bytes_read, nested.len() - find_bytes(nested, b"--BbC04y").unwrap());
+        },
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+
+    // Cut off within a nested multipart by the enclosing one's closing delimiter, which
+    // ends the nested part's body for every parser
+    let cut = b"--X\r\n\
+                Content-Type: multipart/mixed; boundary=Y\r\n\
+                \r\n\
+                --Y\r\n\
+                A: b\r\n\
+                \r\n\
+                in\r\n\
+                --X--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=X".to_vec()]);
+    let results = vec![
+        read_multipart_body(&mut &cut[..], &headers, false, false).map(|_| ()),
+        parse_multipart_borrowed("multipart/mixed; boundary=X", cut).map(|_| ()),
+    ];
+    for result in results {
+        match result {
+            Err(Error::Truncated { expected_boundary, bytes_read }) => {
+                assert_eq!(expected_boundary, b"\r\n--Y");
+                assert_eq!(bytes_read, 2);
+            },
+            other => panic!("expected Truncated, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_nested_boundary() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"submit-name\"\r\n\
+                  \r\n\
+                  Larry\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"files\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"file1.txt\"\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  ... contents of file1.txt ...\r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"file2.gif\"\r\n\
+                  Content-Type: image/gif\r\n\
+                  \r\n\
+                  ...contents of file2.gif...\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"after\"\r\n\
+                  \r\n\
+                  last\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    assert_eq!(nodes.len(), 3);
+    match nodes[1] {
+        Node::Multipart((ref headers, ref subnodes)) => {
+            assert_eq!(get_multipart_boundary_from_headers(headers).unwrap(), b"BbC04y");
+            assert_eq!(get_multipart_boundary(headers).unwrap(), b"--BbC04y");
+            assert_eq!(subnodes.len(), 2);
+            let names: Vec<Option<String>> = subnodes.iter().map(|node| match *node {
+                Node::File(ref filepart) => filepart.filename().unwrap(),
+                _ => panic!("subnode of wrong type"),
+            }).collect();
+            assert_eq!(names, vec![Some("file1.txt".to_owned()), Some("file2.gif".to_owned())]);
+        },
+        _ => panic!("2nd node of wrong type"),
+    }
+    assert!(matches!(nodes[2], Node::Part(ref part) if part.body == b"last"));
+}
+
+#[test]
+fn test_parse_multipart_bytes() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 contents\r\n\
+                 --AaB03x--";
+
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"value"));
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(::std::fs::read(&filepart.path).unwrap(), b"contents");
+        },
+        _ => panic!("2nd node of wrong type"),
+    }
+
+    let nodes = parse_multipart_bytes("multipart/mixed; boundary=\"AaB03x\"", body, true)
+        .unwrap();
+    assert!(matches!(nodes[0], Node::File(_)));
+
+    match parse_multipart_bytes("text/plain", body, false) {
+        Err(Error::NotMultipart) => {},
+        other => panic!("expected NotMultipart, got {:?}", other),
+    }
+    match parse_multipart_bytes("not a mime type", body, false) {
+        Err(Error::NotMultipart) => {},
+        other => panic!("expected NotMultipart, got {:?}", other),
+    }
+    match parse_multipart_bytes("multipart/form-data", body, false) {
+        Err(Error::BoundaryNotSpecified) => {},
+        other => panic!("expected BoundaryNotSpecified, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_part_text() {
+    let part = |content_type: Option<&str>, body: &[u8]| {
+        let mut headers = Headers::new();
+        if let Some(content_type) = content_type {
+            headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        }
+        Part { headers, body: body.to_vec() }
+    };
+
+    assert_eq!(part(Some("text/plain; charset=ISO-8859-1"), b"caf\xe9").text().unwrap(), "café");
+    assert_eq!(part(Some("text/plain; charset=latin1"), b"caf\xe9").text().unwrap(), "café");
+    assert_eq!(part(Some("text/plain; charset=windows-1252"), b"\x80 caf\xe9").text().unwrap(),
+               "€ café");
+    assert_eq!(part(Some("text/plain; charset=utf-8"), "café €".as_bytes()).text().unwrap(),
+               "café €");
+    // UTF-8 is the default
+    assert_eq!(part(Some("text/plain"), "café".as_bytes()).text().unwrap(), "café");
+    assert_eq!(part(None, "café".as_bytes()).text().unwrap(), "café");
+
+    // Latin-1 is not valid UTF-8
+    assert!(matches!(part(None, b"caf\xe9").text(), Err(Error::Decoding(_))));
+    match part(Some("text/plain; charset=x-unknown"), b"text").text() {
+        Err(Error::Decoding(ref e)) => assert!(e.contains("X-UNKNOWN")),
+        other => panic!("expected Decoding, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_text_with_default_charset() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"_charset_\"\r\n\
+                  \r\n\
+                  windows-1252\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"comment\"\r\n\
+                  \r\n\
+                  \x80 caf\xe9\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"notes\"\r\n\
+                  Content-Type: text/plain; charset=utf-8\r\n\
+                  \r\n\
+                  caf\xc3\xa9\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+    let charset = form_charset(&nodes).map(|c| c.to_string()).unwrap_or("UTF-8".to_owned());
+    assert!(charset.eq_ignore_ascii_case("windows-1252"));
+    let text = |i: usize, default: &str| match nodes[i] {
+        Node::Part(ref part) => part.text_with_default_charset(default),
+        _ => panic!("node of wrong type"),
+    };
+
+    // The form's charset is used where the part gives none
+    assert_eq!(text(1, &charset).unwrap(), "€ café");
+    assert!(matches!(text(1, "UTF-8"), Err(Error::Decoding(_))));
+    match nodes[1] {
+        Node::Part(ref part) => assert!(matches!(part.text(), Err(Error::Decoding(_)))),
+        _ => panic!("node of wrong type"),
+    }
+
+    // A part's own charset takes precedence
+    assert_eq!(text(2, &charset).unwrap(), "café");
+    assert!(matches!(text(1, "x-unknown"), Err(Error::Decoding(_))));
+}
+
+#[test]
+fn test_file_part_persist() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"one\"; filename=\"one.txt\"\r\n\
+                  \r\n\
+                  first\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"two\"; filename=\"two.txt\"\r\n\
+                  \r\n\
+                  second\r\n\
+                  --AaB03x--";
+
+    let mut nodes = read_multipart(&mut &input[..], false).unwrap();
+    let second = match nodes.pop() {
+        Some(Node::File(filepart)) => filepart,
+        _ => panic!("2nd node of wrong type"),
+    };
+    let first = match nodes.pop() {
+        Some(Node::File(filepart)) => filepart,
+        _ => panic!("1st node of wrong type"),
+    };
+    assert!(first.delete_on_drop() && second.delete_on_drop());
+
+    // Dropped: deleted
+    let first_path = first.path.clone();
+    assert!(first_path.exists());
+    drop(first);
+    assert!(!first_path.exists());
+    assert!(!first_path.parent().unwrap().exists());
+
+    // Persisted: kept
+    let target = tempfile::tempdir().unwrap();
+    let old_path = second.path.clone();
+    let kept = second.persist(target.path().join("kept.txt")).unwrap();
+    assert!(!kept.delete_on_drop());
+    assert_eq!(kept.path, target.path().join("kept.txt"));
+    assert_eq!(kept.name().as_deref(), Some("two"));
+    drop(kept);
+    assert_eq!(::std::fs::read(target.path().join("kept.txt")).unwrap(), b"second");
+    assert!(!old_path.exists());
+    assert!(!old_path.parent().unwrap().exists());
+}
+
+#[test]
+fn test_file_part_read_back() {
+    let nodes = MultipartBuilder::new()
+        .add_bytes("upload", "data.bin", "application/octet-stream".parse().unwrap(),
+                   b"\x00\x01binary\xff".to_vec())
+        .build();
+    let mut body: Vec<u8> = Vec::new();
+    write_multipart(&mut body, b"AaB03x", &nodes).unwrap();
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let nodes = read_multipart_body(&mut &body[..], &headers, true, false).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.read_to_vec().unwrap(), b"\x00\x01binary\xff");
+            // And again
+            assert_eq!(filepart.read_to_vec().unwrap(), b"\x00\x01binary\xff");
+            let mut contents = Vec::new();
+            filepart.open().unwrap().read_to_end(&mut contents).unwrap();
+            assert_eq!(contents, b"\x00\x01binary\xff");
+        },
+        _ => panic!("node of wrong type"),
+    }
+
+    let missing = FilePart::new(Headers::new(), Path::new("/nonexistent/mime-multipart"));
+    assert!(matches!(missing.read_to_vec(), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_lenient() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    // Spaces and tabs after the delimiters
+    let padded = b"--AaB03x \t\r\n\
+                   Content-Disposition: form-data; name=\"field\"\r\n\
+                   \r\n\
+                   value\r\n\
+                   --AaB03x  \r\n\
+                   Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                   \r\n\
+                   contents\r\n\
+                   --AaB03x--";
+    match read_multipart_body(&mut &padded[..], &headers, false, false) {
+        Err(Error::NoCrLfAfterBoundary) => {},
+        other => panic!("expected NoCrLfAfterBoundary, got {:?}", other),
+    }
+    let nodes = read_multipart_body_lenient(&mut &padded[..], &headers, false).unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"value"));
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.size, Some(8));
+            assert_eq!(filepart.read_to_vec().unwrap(), b"contents");
+        },
+        _ => panic!("2nd node of wrong type"),
+    }
+
+    // Bare LFs, mixed in with CRLFs
+    let mixed = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\n\
+                  Content-Type: text/plain\r\n\
+                  \n\
+                  line one\r\nline two\n\
+                  --AaB03x\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\n\
+                  \n\
+                  ends in CR\r\r\n\
+                  --AaB03x--\n";
+    assert!(read_multipart_body(&mut &mixed[..], &headers, false, false).is_err());
+    let nodes = read_multipart_body_lenient(&mut &mixed[..], &headers, false).unwrap();
+    assert_eq!(nodes.len(), 2);
+    match nodes[0] {
+        Node::Part(ref part) => {
+            assert_eq!(part.body, b"line one\r\nline two");
+            assert_eq!(part.content_type(), Some("text/plain".parse().unwrap()));
+        },
+        _ => panic!("1st node of wrong type"),
+    }
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.read_to_vec().unwrap(), b"ends in CR\r");
+            assert_eq!(filepart.size, Some(11));
+        },
+        _ => panic!("2nd node of wrong type"),
+    }
+}
+
+#[test]
+fn test_epilogue() {
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  This is the preamble.\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  nested\r\n\
+                  --BbC04y--\r\n\
+                  nested epilogue\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  body\r\n\
+                  --AaB03x--  \r\n\
+                  This is the epilogue.\r\n\
+                  -- signature --\r\n";
+
+    let multipart = read_multipart_full(&mut &input[..], &ReadOptions::default()).unwrap();
+    assert_eq!(multipart.nodes.len(), 2);
+    assert!(matches!(multipart.nodes[1], Node::Part(ref part) if part.body == b"body"));
+    assert_eq!(multipart.preamble, b"This is the preamble.");
+    assert_eq!(multipart.epilogue, b"This is the epilogue.\r\n-- signature --\r\n");
+
+    // None at all
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  body\r\n\
+                  --AaB03x--";
+    let multipart = read_multipart_full(&mut &input[..], &ReadOptions::default()).unwrap();
+    assert!(multipart.preamble.is_empty());
+    assert!(multipart.epilogue.is_empty());
+}
+
+#[test]
+fn test_preamble() {
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  This is a multi-part message in MIME format.\r\n\
+                  \r\n\
+                  \t  Indented, with trailing space \r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  body\r\n\
+                  --AaB03x--";
+    let multipart = read_multipart_full(&mut &input[..], &ReadOptions::default()).unwrap();
+    assert_eq!(multipart.preamble,
+               &b"This is a multi-part message in MIME format.\r\n\
+                  \r\n\
+                  \t  Indented, with trailing space \r\n"[..]);
+    assert_eq!(multipart.nodes.len(), 1);
+
+    // Without a boundary there is still no body
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  Only a preamble\r\n";
+    match read_multipart_full(&mut &input[..], &ReadOptions::default()) {
+        Err(Error::EofBeforeFirstBoundary) => {},
+        other => panic!("expected EofBeforeFirstBoundary, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_clone_nodes() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  \r\n\
+                  contents\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    let clones = nodes.clone();
+    assert_eq!(clones.len(), 2);
+    match (&nodes[0], &clones[0]) {
+        (Node::Part(ref a), Node::Part(ref b)) => assert_eq!(a.body, b.body),
+        _ => panic!("1st node of wrong type"),
+    }
+    let path = match (&nodes[1], &clones[1]) {
+        (Node::File(ref a), Node::File(ref b)) => {
+            assert_eq!(a.path, b.path);
+            assert!(a.delete_on_drop());
+            assert!(!b.delete_on_drop());
+            a.path.clone()
+        },
+        _ => panic!("2nd node of wrong type"),
+    };
+
+    // Dropping the clones leaves the file; dropping the original removes it
+    drop(clones);
+    assert!(path.exists());
+    drop(nodes);
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_node_equality() {
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  nested\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let reordered = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                      \r\n\
+                      --AaB03x\r\n\
+                      Content-Type: text/plain\r\n\
+                      Content-Disposition: form-data; name=\"field\"\r\n\
+                      \r\n\
+                      value\r\n\
+                      --AaB03x\r\n\
+                      Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                      \r\n\
+                      --BbC04y\r\n\
+                      Content-Type: text/plain\r\n\
+                      \r\n\
+                      nested\r\n\
+                      --BbC04y--\r\n\
+                      --AaB03x--";
+
+    let a = read_multipart(&mut &input[..], false).unwrap();
+    let b = read_multipart(&mut &input[..], false).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(a, read_multipart(&mut &reordered[..], false).unwrap());
+
+    let mut c = b.clone();
+    if let Node::Multipart((_, ref mut subnodes)) = c[1] {
+        if let Node::Part(ref mut part) = subnodes[0] {
+            part.body = b"changed".to_vec();
+        }
+    }
+    assert!(a != c);
+
+    // File parts compare by path, not contents
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"text/plain".to_vec()]);
+    let file = FilePart::new(headers.clone(), Path::new("/tmp/a"));
+    assert_eq!(file, FilePart::new(headers.clone(), Path::new("/tmp/a")));
+    assert_eq!(Node::File(file.clone()), Node::File(file.clone()));
+    assert!(file != FilePart::new(headers, Path::new("/tmp/b")));
+}
+
+#[test]
+fn test_write_multipart_generate() {
+    let nodes = MultipartBuilder::new()
+        .add_text("field", "value")
+        .add_bytes("upload", "a.bin", "application/octet-stream".parse().unwrap(),
+                   b"\x00binary".to_vec())
+        .build();
+
+    let mut body: Vec<u8> = Vec::new();
+    let (boundary, count) = write_multipart_generate(&mut body, &nodes).unwrap();
+    assert_eq!(count, body.len());
+    validate_boundary(&boundary).unwrap();
+
+    let content_type = format!("multipart/form-data; boundary={}",
+                               String::from_utf8(boundary).unwrap());
+    let parsed = parse_multipart_bytes(&content_type, &body, false).unwrap();
+    assert_eq!(parsed.len(), 2);
+    assert!(matches!(parsed[0], Node::Part(ref part) if part.body == b"value"));
+    match parsed[1] {
+        Node::File(ref filepart) => assert_eq!(filepart.read_to_vec().unwrap(), b"\x00binary"),
+        _ => panic!("2nd node of wrong type"),
+    }
+}
+
+#[test]
+fn test_generate_boundary() {
+    let legal = |b: &u8| b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_';
+
+    let mut seen = ::std::collections::HashSet::new();
+    for _ in 0..100 {
+        let boundary = generate_boundary();
+        assert_eq!(boundary.len(), 68);
+        assert!(boundary.iter().all(legal));
+        validate_boundary(&boundary).unwrap();
+        assert!(seen.insert(boundary));
+    }
+
+    for len in [1, 32, 70].iter() {
+        let boundary = generate_boundary_with_len(*len);
+        assert_eq!(boundary.len(), *len);
+        assert!(boundary.iter().all(legal));
+        validate_boundary(&boundary).unwrap();
+    }
+    assert_ne!(generate_boundary_with_len(32), generate_boundary_with_len(32));
+
+    // Every character is used, roughly evenly
+    let mut counts = [0usize; 256];
+    for _ in 0..200 {
+        for b in generate_boundary_with_len(64) {
+            counts[b as usize] += 1;
+        }
+    }
+    let used: Vec<usize> = counts.iter().cloned().filter(|&c| c > 0).collect();
+    assert_eq!(used.len(), 64);
+    // Expected 200 of each; this fails with negligible probability
+    assert!(used.iter().all(|&c| c > 100 && c < 300));
+}
+
+#[test]
+fn test_multipart_writer() {
+    let mut writer = MultipartWriter::new(Vec::new(), b"AaB03x").unwrap();
+    assert!(matches!(MultipartWriter::new(Vec::new(), b"bad boundary "),
+                     Err(Error::InvalidBoundary)));
+
+    let mut expected: Vec<Node> = Vec::new();
+    for (i, body) in [&b"first"[..], &b"second"[..], &b"third"[..]].iter().enumerate() {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![b"text/plain".to_vec()]);
+        headers.set_raw("Content-Disposition",
+                        vec![format!("form-data; name=\"part{}\"", i).into_bytes()]);
+        writer.write_part(&headers, &mut &body[..]).unwrap();
+        expected.push(Node::Part(Part { headers, body: body.to_vec() }));
+    }
+    let count = writer.count();
+    let body = writer.finish().unwrap();
+    assert_eq!(body.len(), count + b"--AaB03x--".len());
+
+    // The same as writing all the parts at once
+    let mut all_at_once: Vec<u8> = Vec::new();
+    write_multipart(&mut all_at_once, b"AaB03x", &expected).unwrap();
+    assert_eq!(body, all_at_once);
+
+    let parsed = parse_multipart_bytes("multipart/mixed; boundary=AaB03x", &body, false).unwrap();
+    assert_eq!(parsed, expected);
+
+    // With a generated boundary
+    let mut writer = MultipartWriter::generate(Vec::new());
+    let boundary = writer.boundary().to_vec();
+    writer.write_part(&Headers::new(), &mut &b"frame"[..]).unwrap();
+    let body = writer.finish().unwrap();
+    assert!(body.starts_with(&[&b"--"[..], &boundary[..], &b"\r\n"[..]].concat()));
+}
+
+#[test]
+fn test_x_mixed_replace_writer() {
+    let mut writer = XMixedReplaceWriter::with_boundary(Vec::new(), b"frame").unwrap();
+    assert_eq!(writer.content_type(), "multipart/x-mixed-replace; boundary=frame");
+    writer.push_frame("image/jpeg".parse().unwrap(), b"\xff\xd8one\xff\xd9").unwrap();
+    assert!(writer.get_ref().starts_with(b"--frame\r\n"));
+    writer.push_frame("image/jpeg".parse().unwrap(), b"\xff\xd8two\xff\xd9").unwrap();
+    let body = writer.finish().unwrap();
+
+    assert_eq!(&body[..], &b"--frame\r\n\
+                             Content-Type: image/jpeg\r\n\
+                             Content-Length: 7\r\n\
+                             \r\n\
+                             \xff\xd8one\xff\xd9\r\n\
+                             --frame\r\n\
+                             Content-Type: image/jpeg\r\n\
+                             Content-Length: 7\r\n\
+                             \r\n\
+                             \xff\xd8two\xff\xd9\r\n\
+                             --frame--"[..]);
+
+    let generated = XMixedReplaceWriter::new(Vec::new());
+    assert!(generated.content_type().len() > "multipart/x-mixed-replace; boundary=".len());
+}
+
+#[test]
+fn test_header_size_limit() {
+    let mut input: Vec<u8> = b"--AaB03x\r\n\
+                               Content-Type: text/plain\r\n\
+                               X-Padding: ".to_vec();
+    input.extend(vec![b'a'; 100_000]);
+    input.extend(b"\r\n\r\nbody\r\n--AaB03x--");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=AaB03x".to_vec()]);
+
+    for lenient in [false, true].iter() {
+        let options = ReadOptions { lenient: *lenient, ..ReadOptions::default() };
+        match read_multipart_body_with_options(&mut &input[..], &headers, &options) {
+            Err(Error::LimitExceeded(LimitKind::HeaderSize)) => {},
+            other => panic!("expected the header size limit to be exceeded, got {:?}", other),
+        }
+    }
+
+    let mut parts = MultipartParts::new(&input[..], b"AaB03x");
+    match parts.next_part() {
+        Some(Err(Error::LimitExceeded(LimitKind::HeaderSize))) => {},
+        other => panic!("expected the header size limit to be exceeded, got {:?}",
+                        other.map(|r| r.map(|p| p.headers))),
+    }
+
+    // Up to the limit, headers parse as before
+    let mut options = ReadOptions::default();
+    options.limits.max_header_size = 200_000;
+    let nodes = read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap();
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"body"));
+
+    // Incomplete headers within the limit are still reported as such
+    let truncated = &input[..1000];
+    match read_multipart_body(&mut &truncated[..], &headers, false, false) {
+        Err(Error::EofInPartHeaders) => {},
+        other => panic!("expected EofInPartHeaders, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_node_headers() {
+    let input = b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  text\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: attachment; filename=\"a.gif\"\r\n\
+                  Content-Type: image/gif\r\n\
+                  \r\n\
+                  GIF89a\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: multipart/alternative; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/html\r\n\
+                  \r\n\
+                  <p>html</p>\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    let types: Vec<String> = nodes.iter().map(|node| {
+        let ct: &ContentType = node.headers().get().unwrap();
+        let ContentType(Mime(ref top, ref sub, _)) = *ct;
+        format!("{}/{}", top, sub)
+    }).collect();
+    assert_eq!(types, vec!["text/plain", "image/gif", "multipart/alternative"]);
+}
+
+#[test]
+fn test_find_by_content_id() {
+    let input = b"Content-Type: multipart/related; boundary=AaB03x; type=\"text/html\"\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/html\r\n\
+                  Content-ID: <page@example.com>\r\n\
+                  \r\n\
+                  <img src=\"cid:logo@example.com\">\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: multipart/alternative; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: image/png\r\n\
+                  Content-ID: <logo@example.com>\r\n\
+                  \r\n\
+                  PNG\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  Content-ID: bare@example.com\r\n\
+                  \r\n\
+                  bare\r\n\
+                  --AaB03x--";
+
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+
+    // Follow the reference from the page to the (nested) image
+    let page = match find_by_content_id(&nodes, "<page@example.com>") {
+        Some(Node::Part(ref part)) => part,
+        other => panic!("page not found: {:?}", other),
+    };
+    let body = String::from_utf8(page.body.clone()).unwrap();
+    let reference = body.split('"').nth(1).unwrap();
+    assert_eq!(reference, "cid:logo@example.com");
+    match find_by_content_id(&nodes, reference) {
+        Some(Node::Part(ref part)) => assert_eq!(part.body, b"PNG"),
+        other => panic!("logo not found: {:?}", other),
+    }
+
+    assert!(find_by_content_id(&nodes, "page@example.com").is_some());
+    assert!(matches!(find_by_content_id(&nodes, "<bare@example.com>"),
+                     Some(Node::Part(ref part)) if part.body == b"bare"));
+    assert!(find_by_content_id(&nodes, "missing@example.com").is_none());
+}
+
+#[test]
+fn test_transfer_encoding_tokens() {
+    let body_with = |encoding: &str, body: &[u8]| {
+        let mut input = format!("Content-Type: multipart/mixed; boundary=AaB03x\r\n\
+                                 \r\n\
+                                 --AaB03x\r\n\
+                                 Content-Type: application/octet-stream\r\n\
+                                 Content-Transfer-Encoding: {}\r\n\
+                                 \r\n", encoding).into_bytes();
+        input.extend(body);
+        input.extend(b"\r\n--AaB03x--");
+        input
+    };
+    let options = ReadOptions {
+        decode_transfer_encoding: true,
+        validate_7bit: true,
+        ..ReadOptions::default()
+    };
+    let parse = |input: &[u8], options: &ReadOptions| {
+        read_multipart_with_options(&mut &input[..], options).map(|nodes| match nodes[0] {
+            Node::Part(ref part) => part.body.clone(),
+            _ => panic!("node of wrong type"),
+        })
+    };
+
+    assert_eq!(parse(&body_with("base64", b"aGk="), &options).unwrap(), b"hi");
+    assert_eq!(parse(&body_with("quoted-printable", b"h=69"), &options).unwrap(), b"hi");
+    assert_eq!(parse(&body_with("7bit", b"plain"), &options).unwrap(), b"plain");
+    assert_eq!(parse(&body_with("8bit", b"caf\xc3\xa9"), &options).unwrap(), b"caf\xc3\xa9");
+    assert_eq!(parse(&body_with("binary", b"\x00\xff="), &options).unwrap(), b"\x00\xff=");
+    // Unknown encodings are treated as binary
+    assert_eq!(parse(&body_with("x-unknown", b"aGk="), &options).unwrap(), b"aGk=");
+
+    // 8-bit bytes in a 7bit body
+    match parse(&body_with("7bit", b"caf\xc3\xa9"), &options) {
+        Err(Error::Decoding(ref e)) => assert!(e.contains("offset 3")),
+        other => panic!("expected Decoding, got {:?}", other),
+    }
+    let unvalidated = ReadOptions { validate_7bit: false, ..options.clone() };
+    assert_eq!(parse(&body_with("7bit", b"caf\xc3\xa9"), &unvalidated).unwrap(),
+               b"caf\xc3\xa9");
+}
+
+#[test]
+fn test_read_multipart_from_http() {
+    let request = b"POST /upload HTTP/1.1\r\n\
+                    Host: example.com\r\n\
+                    Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                    Content-Length: 138\r\n\
+                    \r\n\
+                    --AaB03x\r\n\
+                    Content-Disposition: form-data; name=\"field\"\r\n\
+                    \r\n\
+                    value\r\n\
+                    --AaB03x\r\n\
+                    Content-Disposition: form-data; name=\"other\"\r\n\
+                    \r\n\
+                    more\r\n\
+                    --AaB03x--";
+    let nodes = read_multipart_from_http(&mut &request[..]).unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"value"));
+    assert!(matches!(nodes[1], Node::Part(ref part) if part.body == b"more"));
+
+    let response = b"HTTP/1.1 200 OK\r\n\
+                     Content-Type: multipart/byteranges; boundary=AaB03x\r\n\
+                     \r\n\
+                     --AaB03x\r\n\
+                     Content-Range: bytes 0-4/10\r\n\
+                     \r\n\
+                     01234\r\n\
+                     --AaB03x--";
+    let nodes = read_multipart_from_http(&mut &response[..]).unwrap();
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"01234"));
+
+    let truncated = &request[..60];
+    match read_multipart_from_http(&mut &truncated[..]) {
+        Err(Error::EofInMainHeaders) => {},
+        other => panic!("expected EofInMainHeaders, got {:?}", other),
+    }
+    let not_multipart = b"POST / HTTP/1.1\r\nContent-Type: text/plain\r\n\r\nhello";
+    match read_multipart_from_http(&mut &not_multipart[..]) {
+        Err(Error::NotMultipart) => {},
+        other => panic!("expected NotMultipart, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_validate_multipart() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"empty\"\r\n\
+                 \r\n\
+                 \r\n\
+                 --AaB03x--";
+
+    let stats = validate_multipart(&body[..], b"AaB03x").unwrap();
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    assert_eq!(stats.parts, nodes.len());
+    let sizes: Vec<usize> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => part.body.len(),
+        Node::File(ref filepart) => filepart.size.unwrap(),
+        _ => panic!("node of wrong type"),
+    }).collect();
+    assert_eq!(stats.part_sizes, sizes);
+    assert_eq!(stats.part_sizes, vec![5, 13, 0]);
+    assert_eq!(stats.total_bytes, body.len());
+
+    let cut = find_bytes(body, b"contents").unwrap();
+    match validate_multipart(&body[..cut], b"AaB03x") {
+        Err(Error::Truncated { .. }) => {},
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+    assert!(validate_multipart(&body[..], b"other").is_err());
+}
+
+#[test]
+fn test_sniff_content_type() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let sniff = |name: &str, contents: &[u8]| {
+        let path = tempdir.path().join(name);
+        ::std::fs::write(&path, contents).unwrap();
+        FilePart::new(Headers::new(), &path).sniff_content_type()
+    };
+
+    let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+    assert_eq!(sniff("png", png), Some("image/png".parse().unwrap()));
+    assert_eq!(sniff("jpeg", b"\xff\xd8\xff\xe0\x00\x10JFIF"), Some("image/jpeg".parse().unwrap()));
+    assert_eq!(sniff("gif", b"GIF89a\x01\x00"), Some("image/gif".parse().unwrap()));
+    assert_eq!(sniff("pdf", b"%PDF-1.7\n"), Some("application/pdf".parse().unwrap()));
+    assert_eq!(sniff("zip", b"PK\x03\x04\x14\x00"), Some("application/zip".parse().unwrap()));
+    assert_eq!(sniff("blob", b"\x00\x01\x02\x03 unknown"), None);
+    assert_eq!(sniff("empty", b""), None);
+    assert_eq!(sniff("short", b"\x89P"), None);
+
+    // Headers are not consulted
+    let path = tempdir.path().join("png");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"text/plain".to_vec()]);
+    assert_eq!(FilePart::new(headers, &path).sniff_content_type(),
+               Some("image/png".parse().unwrap()));
+    assert_eq!(FilePart::new(Headers::new(), &tempdir.path().join("missing"))
+               .sniff_content_type(), None);
+}
+
+#[test]
+fn test_read_multipart_routed() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"piped\"\r\n\
+                 \r\n\
+                 piped contents\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x--";
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let upload_path = tempdir.path().join("upload");
+    let piped = Rc::new(RefCell::new(Vec::new()));
+    let mut routed: Vec<Option<String>> = Vec::new();
+    let nodes = read_multipart_routed(&body[..], b"AaB03x", |headers| {
+        let name = super::get_content_disposition_name(headers);
+        routed.push(name.clone());
+        match name.as_deref() {
+            Some("upload") => PartSink::File(upload_path.clone()),
+            Some("piped") => PartSink::Writer(Box::new(Shared(piped.clone()))),
+            _ => PartSink::Memory,
+        }
+    }).unwrap();
+
+    assert_eq!(routed, vec![Some("upload".to_owned()), Some("piped".to_owned()),
+                            Some("field".to_owned())]);
+    assert_eq!(nodes.len(), 3);
+    match nodes[0] {
+        RoutedNode::File(ref filepart) => {
+            assert_eq!(filepart.path, upload_path);
+            assert_eq!(filepart.size, Some(13));
+            assert!(!filepart.delete_on_drop());
+            assert_eq!(filepart.read_to_vec().unwrap(), b"file contents");
+        },
+        ref other => panic!("expected a file, got {:?}", other),
+    }
+    match nodes[1] {
+        RoutedNode::Written((ref headers, size)) => {
+            assert_eq!(super::get_content_disposition_name(headers), Some("piped".to_owned()));
+            assert_eq!(size, 14);
+        },
+        ref other => panic!("expected a written part, got {:?}", other),
+    }
+    assert_eq!(&*piped.borrow(), b"piped contents");
+    match nodes[2] {
+        RoutedNode::Part(ref part) => assert_eq!(part.body, b"value"),
+        ref other => panic!("expected a part, got {:?}", other),
+    }
+    drop(nodes);
+    assert!(upload_path.exists());
+
+    // The limits given are applied
+    let route = |options: &ReadOptions| {
+        read_multipart_routed_with_options(&body[..], b"AaB03x", options, |_| PartSink::Memory)
+    };
+    assert_eq!(route(&ReadOptions::default()).unwrap().len(), 3);
+    let mut options = ReadOptions::default();
+    options.limits.max_parts = 2;
+    assert!(matches!(route(&options), Err(Error::LimitExceeded(LimitKind::Parts))));
+    let mut options = ReadOptions::default();
+    options.limits.max_part_size = 13;
+    assert!(matches!(route(&options), Err(Error::LimitExceeded(LimitKind::PartSize))));
+    let mut options = ReadOptions::default();
+    options.limits.max_header_size = 40;
+    assert!(matches!(route(&options), Err(Error::LimitExceeded(LimitKind::HeaderSize))));
+}
+
+#[test]
+fn test_field_name_charset() {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend_from_slice(b"--AaB03x\r\n\
+                             Content-Disposition: form-data; name=\"_charset_\"\r\n\
+                             \r\n\
+                             utf-8\r\n\
+                             --AaB03x\r\n\
+                             Content-Disposition: form-data; name=\"caf\xc3\xa9\"\r\n\
+                             \r\n\
+                             coffee\r\n\
+                             --AaB03x\r\n\
+                             Content-Disposition: form-data; name*=UTF-8''%E2%82%AC\r\n\
+                             \r\n\
+                             euro\r\n\
+                             --AaB03x--");
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", &body, false)
+        .unwrap();
+    let charset = form_charset(&nodes).unwrap();
+    assert_eq!(charset, Charset::Ext("UTF-8".to_owned()));
+    let names: Vec<Option<String>> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => part.name_with_charset(&charset),
+        _ => panic!("node of wrong type"),
+    }).collect();
+    assert_eq!(names, vec![Some("_charset_".to_owned()), Some("café".to_owned()),
+                           Some("€".to_owned())]);
+    match nodes[1] {
+        Node::Part(ref part) => assert_eq!(part.name(), Some("café".to_owned())),
+        _ => panic!("node of wrong type"),
+    }
+
+    // A legacy charset changes how raw bytes in names are read
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"_charset_\"\r\n\
+                 \r\n\
+                 windows-1252\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"caf\xe9\"\r\n\
+                 \r\n\
+                 coffee\r\n\
+                 --AaB03x--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    let charset = form_charset(&nodes).unwrap();
+    match nodes[1] {
+        Node::Part(ref part) => {
+            assert_eq!(part.name_with_charset(&charset), Some("café".to_owned()));
+        },
+        _ => panic!("node of wrong type"),
+    }
+
+    assert_eq!(form_charset(&[]), None);
+}
+
+#[test]
+fn test_content_disposition() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![
+        b"attachment; Name=\"upload\"; filename=\"plain.txt\"; \
+          filename*=UTF-8''na%C3%AFve.txt; creation-date=\"Wed, 12 Feb 1997 16:29:51 -0500\"; \
+          size=42; note=\"a; b\"".to_vec()]);
+    let part = Part { headers, body: Vec::new() };
+    let cd = part.content_disposition().unwrap();
+    assert_eq!(cd.disposition, "attachment");
+    assert_eq!(cd.name, Some("upload".to_owned()));
+    assert_eq!(cd.filename, Some("naïve.txt".to_owned()));
+    let mut params: Vec<(&str, &str)> = cd.params.iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    params.sort();
+    assert_eq!(params, vec![
+        ("Name", "upload"),
+        ("creation-date", "Wed, 12 Feb 1997 16:29:51 -0500"),
+        ("filename", "plain.txt"),
+        ("filename*", "UTF-8''na%C3%AFve.txt"),
+        ("note", "a; b"),
+        ("size", "42"),
+    ]);
+    assert_eq!(cd.name, part.name());
+    assert_eq!(cd.filename, part.filename().unwrap());
+
+    let part = Part { headers: Headers::new(), body: Vec::new() };
+    match part.content_disposition() {
+        Err(Error::Hyper(::hyper::Error::Header)) => {},
+        other => panic!("expected a missing header error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn test_read_multipart_http() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x--";
+
+    let mut map = ::http::HeaderMap::new();
+    map.insert(::http::header::CONTENT_TYPE,
+               "multipart/form-data; boundary=AaB03x".parse().unwrap());
+    map.append("x-repeated", "one".parse().unwrap());
+    map.append("x-repeated", "two".parse().unwrap());
+    let headers = headers_from_http(&map);
+    assert_eq!(get_multipart_boundary_from_headers(&headers).unwrap(), b"AaB03x");
+    assert_eq!(headers.get_raw("X-Repeated").unwrap().to_vec(),
+               vec![b"one".to_vec(), b"two".to_vec()]);
+
+    let nodes = read_multipart_body_http_headers(&map, &mut &body[..], false).unwrap();
+    assert_eq!(nodes.len(), 1);
+
+    let (parts, ()) = ::http::Request::post("/upload")
+        .header("Content-Type", "multipart/form-data; boundary=AaB03x")
+        .body(())
+        .unwrap()
+        .into_parts();
+    let nodes = read_multipart_http(&parts, &mut &body[..], false).unwrap();
+    match nodes[0] {
+        Node::Part(ref part) => {
+            assert_eq!(part.name(), Some("field".to_owned()));
+            assert_eq!(part.body, b"value");
+        },
+        _ => panic!("node of wrong type"),
+    }
+
+    match read_multipart_body_http_headers(&::http::HeaderMap::new(), &mut &body[..], false) {
+        Err(Error::NoRequestContentType) => {},
+        other => panic!("expected NoRequestContentType, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_boundary_finder() {
+    let body: &[u8] = b"preamble\r\n--AaB03x\r\n\
+                        Content-Disposition: form-data; name=\"field\"\r\n\
+                        \r\n\
+                        --AaB03 is not the boundary\r\n\
+                        --AaB03x\r\n\
+                        \r\n\
+                        value\r\n\
+                        --AaB03x--";
+
+    let finder = BoundaryFinder::new(b"AaB03x");
+    assert_eq!(finder.find(body), Some(BoundaryMatch { position: 10, closing: false }));
+    assert_eq!(finder.find(b"no delimiter here"), None);
+    assert_eq!(finder.find(b"\r\n--AaB03x--"), Some(BoundaryMatch { position: 2, closing: true }));
+
+    let expected = vec![
+        BoundaryMatch { position: 10, closing: false },
+        BoundaryMatch { position: find_bytes(body, b"--AaB03x\r\n\r\n").unwrap(), closing: false },
+        BoundaryMatch { position: body.len() - 10, closing: true },
+    ];
+    for &size in &[1, 3, body.len()] {
+        let mut finder = BoundaryFinder::new(b"AaB03x");
+        let mut found: Vec<BoundaryMatch> = Vec::new();
+        for chunk in body.chunks(size) {
+            found.extend(finder.push(chunk));
+        }
+        found.extend(finder.finish());
+        assert_eq!(found, expected, "chunks of {} bytes", size);
+    }
+
+    // A delimiter at the very end, without enough following it to be a closing delimiter
+    let mut finder = BoundaryFinder::new(b"AaB03x");
+    assert_eq!(finder.push(b"abc\r\n--AaB03x-"), vec![]);
+    assert_eq!(finder.finish(), Some(BoundaryMatch { position: 5, closing: false }));
+    assert_eq!(finder.finish(), None);
+}
+
+#[test]
+fn test_quoted_boundary() {
+    let boundary = |content_type: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        get_multipart_boundary_from_headers(&headers)
+    };
+
+    assert_eq!(boundary("multipart/form-data; boundary=AaB03x").unwrap(), b"AaB03x");
+    assert_eq!(boundary("multipart/form-data; boundary=\"----WebKitFormBoundary7MA4YWxk\"")
+               .unwrap(), b"----WebKitFormBoundary7MA4YWxk");
+    assert_eq!(boundary("multipart/mixed; charset=utf-8; BOUNDARY=\"a b\"").unwrap(), b"a b");
+    assert_eq!(boundary("multipart/mixed; boundary=\"a\\\"b;c\"; charset=utf-8").unwrap(),
+               b"a\"b;c");
+    match boundary("multipart/mixed; boundary=\"ab \"") {
+        Err(Error::InvalidBoundary) => {},
+        other => panic!("expected InvalidBoundary, got {:?}", other),
+    }
+
+    // As set through the typed header
+    let mut headers = Headers::new();
+    headers.set(ContentType("multipart/mixed; boundary=\"a b\"".parse().unwrap()));
+    assert_eq!(get_multipart_boundary_from_headers(&headers).unwrap(), b"a b");
+
+    // A body with a quoted boundary containing a space
+    let body = b"--a b\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --a b--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=\"a b\"", body, false)
+        .unwrap();
+    assert_eq!(nodes.len(), 1);
+}
+
+#[test]
+fn test_deep_nesting() {
+    // Nested far more deeply than the stack could take, were there no limit
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        for level in 0..depth {
+            body.extend(format!("--b{}\r\nContent-Type: multipart/mixed; boundary=b{}\r\n\r\n",
+                                level, level + 1).as_bytes());
+        }
+        body.extend(format!("--b{}\r\nContent-Type: text/plain\r\n\r\ndeep\r\n--b{}--",
+                            depth, depth).as_bytes());
+        for level in (0..depth).rev() {
+            body.extend(format!("\r\n--b{}--", level).as_bytes());
+        }
+        body
+    }
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=b0".to_vec()]);
+    let body = nested(1_000_000);
+    match read_multipart_body(&mut &body[..], &headers, false, false) {
+        Err(Error::LimitExceeded(LimitKind::Depth)) => {},
+        other => panic!("expected the depth limit to be exceeded, got {:?}", other),
+    }
+
+    // Nesting up to the limit is fine
+    let max_depth = Limits::default().max_depth;
+    let body = nested(max_depth);
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    let mut depth = 0;
+    let mut level = &nodes;
+    while let Node::Multipart((_, ref inner)) = level[0] {
+        depth += 1;
+        level = inner;
+    }
+    assert_eq!(depth, max_depth);
+    let body = nested(max_depth + 1);
+    match read_multipart_body(&mut &body[..], &headers, false, false) {
+        Err(Error::LimitExceeded(LimitKind::Depth)) => {},
+        other => panic!("expected the depth limit to be exceeded, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_check_part_lengths() {
+    let body = |field_length: usize, file_length: usize| format!(
+        "--AaB03x\r\n\
+         Content-Disposition: form-data; name=\"field\"\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         value\r\n\
+         --AaB03x\r\n\
+         Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         file contents\r\n\
+         --AaB03x\r\n\
+         Content-Disposition: form-data; name=\"undeclared\"\r\n\
+         \r\n\
+         anything\r\n\
+         --AaB03x--", field_length, file_length).into_bytes();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let options = ReadOptions { check_part_lengths: true, ..ReadOptions::default() };
+
+    let matching = body(5, 13);
+    let nodes = read_multipart_body_with_options(&mut &matching[..], &headers, &options)
+        .unwrap();
+    assert_eq!(nodes.len(), 3);
+
+    for &(field_length, file_length, declared, actual) in &[(6, 13, 6, 5), (5, 12, 12, 13)] {
+        let mismatching = body(field_length, file_length);
+        match read_multipart_body_with_options(&mut &mismatching[..], &headers, &options) {
+            Err(Error::LengthMismatch { declared: d, actual: a }) => {
+                assert_eq!((d, a), (declared, actual));
+            },
+            other => panic!("expected a length mismatch, got {:?}", other),
+        }
+        // Not checked unless asked for
+        assert!(read_multipart_body(&mut &mismatching[..], &headers, false, false).is_ok());
+    }
+}
+
+#[test]
+fn test_text_fields() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"title\"\r\n\
+                 \r\n\
+                 Holiday\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+                 \r\n\
+                 sand\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"caption\"\r\n\
+                 \r\n\
+                 A day at the beach\r\n\
+                 --AaB03x--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    let fields = text_fields(&nodes).unwrap();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(fields["title"], "Holiday");
+    assert_eq!(fields["caption"], "A day at the beach");
+
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"tag\"\r\n\
+                 \r\n\
+                 one\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"tag\"\r\n\
+                 \r\n\
+                 two\r\n\
+                 --AaB03x--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    assert_eq!(text_fields_with(&nodes, Duplicates::LastWins).unwrap()["tag"], "two");
+    match text_fields_with(&nodes, Duplicates::Error) {
+        Err(Error::DuplicateField(name)) => assert_eq!(name, "tag"),
+        other => panic!("expected a duplicate field, got {:?}", other),
+    }
+
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"bad\"\r\n\
+                 \r\n\
+                 \xff\xfe\r\n\
+                 --AaB03x--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    match text_fields(&nodes) {
+        Err(Error::Utf8(_)) => {},
+        other => panic!("expected a UTF-8 error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_read_multipart_parts() {
+    let mut headers = Headers::new();
+    headers.set(ContentType("multipart/form-data; boundary=AaB03x".parse().unwrap()));
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x--";
+    let mut reader: Box<dyn Read> = Box::new(&body[..]);
+    let nodes = read_multipart_parts(&headers, &mut *reader, false).unwrap();
+    assert_eq!(nodes.len(), 2);
+    match nodes[0] {
+        Node::Part(ref part) => assert_eq!(part.body, b"value"),
+        _ => panic!("node of wrong type"),
+    }
+    match nodes[1] {
+        Node::File(ref filepart) => assert_eq!(filepart.read_to_vec().unwrap(), b"file contents"),
+        _ => panic!("node of wrong type"),
+    }
+
+    match read_multipart_parts(&Headers::new(), &mut &body[..], false) {
+        Err(Error::NoRequestContentType) => {},
+        other => panic!("expected NoRequestContentType, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_total_size() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"album\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.txt\"\r\n\
+                  \r\n\
+                  sand and sea\r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  note\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    assert_eq!(total_size(&nodes), 7 + 12 + 4);
+    assert_eq!(total_size(&[]), 0);
+
+    // A file part without a recorded size is measured on disk
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    ::std::fs::write(&path, b"0123456789").unwrap();
+    assert_eq!(total_size(&[Node::File(FilePart::new(Headers::new(), &path))]), 10);
+}
+
+#[test]
+fn test_has_files() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"album\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.txt\"\r\n\
+                  \r\n\
+                  sand\r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"sea.txt\"\r\n\
+                  \r\n\
+                  water\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    assert!(has_files(&nodes));
+    let files = file_parts(&nodes);
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].filename().unwrap().as_deref(), Some("beach.txt"));
+    assert_eq!(files[1].read_to_vec().unwrap(), b"water");
+
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    assert!(!has_files(&nodes));
+    assert!(file_parts(&nodes).is_empty());
+    assert!(!has_files(&[]));
+}
+
+#[test]
+fn test_body_consumed() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x--\r\n\
+                  epilogue";
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+
+    let mut body = &input[..];
+    let nodes = read_multipart_body(&mut body, &headers, false, false).unwrap();
+    assert_eq!(nodes.len(), 1);
+    match read_multipart_body(&mut body, &headers, false, false) {
+        Err(Error::EofBeforeFirstBoundary) => {},
+        other => panic!("expected EofBeforeFirstBoundary, got {:?}", other),
+    }
+
+    // The same goes for a body read a byte at a time, with nothing read ahead
+    let mut body = OneByteReader(input);
+    read_multipart_body(&mut body, &headers, false, false).unwrap();
+    match read_multipart_body(&mut body, &headers, false, false) {
+        Err(Error::EofBeforeFirstBoundary) => {},
+        other => panic!("expected EofBeforeFirstBoundary, got {:?}", other),
+    }
+
+    let mut input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\r\n".to_vec();
+    input.extend(&b"--AaB03x\r\nContent-Type: text/plain\r\n\r\nvalue\r\n--AaB03x--"[..]);
+    let mut stream = &input[..];
+    read_multipart(&mut stream, false).unwrap();
+    match read_multipart(&mut stream, false) {
+        Err(Error::EofInMainHeaders) => {},
+        other => panic!("expected EofInMainHeaders, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_content_type_param() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![
+        b"application/pkcs7-mime; smime-type=enveloped-data; \
+          Name=\"smime; \\\"signed\\\".p7m\"".to_vec()
+    ]);
+    let part = Part { headers, body: Vec::new() };
+    assert_eq!(part.content_type_param("smime-type").as_deref(), Some("enveloped-data"));
+    assert_eq!(part.content_type_param("name").as_deref(), Some("smime; \"signed\".p7m"));
+    assert_eq!(part.content_type_param("charset"), None);
+
+    let filepart = FilePart::new(part.headers.clone(), Path::new("unused"));
+    assert_eq!(filepart.content_type_param("NAME"), part.content_type_param("name"));
+    assert_eq!(Part { headers: Headers::new(), body: Vec::new() }.content_type_param("name"),
+               None);
+}
+
+#[test]
+fn test_pusher() {
+    let input = b"preamble\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x  \r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  almost --AaB03 but not\r\n-- quite\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  \r\n\
+                  \r\n\
+                  --AaB03x--\r\n\
+                  epilogue --AaB03x\r\n";
+
+    // The events, with the data of each part joined up
+    let events = |chunk_size: usize| -> Vec<Event> {
+        let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+        let mut events: Vec<Event> = Vec::new();
+        for chunk in input.chunks(chunk_size) {
+            for event in pusher.push(chunk).unwrap() {
+                match (events.last_mut(), event) {
+                    (Some(Event::PartData(data)), Event::PartData(more)) => data.extend(more),
+                    (_, event) => events.push(event),
+                }
+            }
+        }
+        pusher.finish().unwrap();
+        events
+    };
+
+    let expected = events(input.len());
+    assert_eq!(expected.len(), 10);
+    match expected[0] {
+        Event::PartStart(ref headers) => {
+            assert_eq!(super::get_content_disposition_name(headers).as_deref(), Some("field"));
+        },
+        ref other => panic!("expected PartStart, got {:?}", other),
+    }
+    assert_eq!(expected[1], Event::PartData(b"value".to_vec()));
+    assert_eq!(expected[2], Event::PartEnd);
+    assert!(matches!(expected[3], Event::PartStart(_)));
+    assert_eq!(expected[4], Event::PartData(b"almost --AaB03 but not\r\n-- quite".to_vec()));
+    assert_eq!(expected[5], Event::PartEnd);
+    assert!(matches!(expected[6], Event::PartStart(_)));
+    assert_eq!(expected[7], Event::PartData(b"\r\n".to_vec()));
+    assert_eq!(expected[8], Event::PartEnd);
+    assert_eq!(expected[9], Event::Done);
+
+    for chunk_size in 1..input.len() {
+        assert_eq!(events(chunk_size), expected, "chunks of {} bytes", chunk_size);
+    }
+
+    // A body which ends early is reported by finish()
+    let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+    let end = find_bytes(input, b"almost").unwrap() + 3;
+    let events = pusher.push(&input[..end]).unwrap();
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[4], Event::PartData(b"alm".to_vec()));
+    match pusher.finish() {
+        Err(Error::Truncated { expected_boundary, bytes_read }) => {
+            assert_eq!(expected_boundary, b"\r\n--AaB03x");
+            assert_eq!(bytes_read, 3);
+        },
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+
+    let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+    assert!(pusher.push(b"no delimiter here").unwrap().is_empty());
+    assert!(matches!(pusher.finish(), Err(Error::EofBeforeFirstBoundary)));
+
+    let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+    pusher.push(b"--AaB03x\nContent-Type: text/plain\n\nbody\n--AaB03x\n").unwrap();
+    assert!(matches!(pusher.finish(), Err(Error::MissingClosingBoundary)));
+
+    let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+    assert!(matches!(pusher.push(b"--AaB03x junk"), Err(Error::NoCrLfAfterBoundary)));
+
+    // Headers which never end are not buffered without limit, nor is what follows a
+    // delimiter before its line terminator
+    let limits = Limits { max_header_size: 64, ..Limits::default() };
+    for start in &[&b"--AaB03x\r\nContent-Type: "[..], b"--AaB03x\r\n--AaB03x "] {
+        let mut pusher = Pusher::new(b"AaB03x", limits.clone());
+        pusher.push(start).unwrap();
+        let mut result = Ok(Vec::new());
+        for _ in 0..100 {
+            result = pusher.push(b"xxxx");
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(Error::LimitExceeded(LimitKind::HeaderSize))),
+                "got {:?}", result);
+    }
+
+    // Headers within the limit are accepted
+    let mut pusher = Pusher::new(b"AaB03x", limits);
+    let events = pusher.push(b"--AaB03x\r\nContent-Type: text/plain\r\n\r\nbody\r\n--AaB03x--")
+        .unwrap();
+    assert_eq!(events.len(), 4);
+}
+
+#[test]
+fn test_require_disposition() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  no disposition\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+
+    // Off by default, as MIME messages need not give one
+    let nodes = read_multipart_body_with_options(&mut &input[..], &headers,
+                                                 &ReadOptions::default()).unwrap();
+    assert_eq!(nodes.len(), 2);
+
+    let options = ReadOptions { require_disposition: true, ..ReadOptions::default() };
+    match read_multipart_body_with_options(&mut &input[..], &headers, &options) {
+        Err(Error::MissingContentDisposition(1)) => {},
+        other => panic!("expected MissingContentDisposition(1), got {:?}", other),
+    }
+
+    // The parts within a nested multipart are not checked
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"files\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  nested\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap();
+    assert_eq!(nodes.len(), 1);
+}
+
+#[test]
+fn test_multipart_to_vec() {
+    let nodes = MultipartBuilder::new()
+        .add_text("first_name", "Michael")
+        .add_bytes("photo", "pixel.gif", mime!(Image/Gif), b"GIF89a".to_vec())
+        .build();
+    let body = multipart_to_vec(b"AaB03x", &nodes).unwrap();
+    let mut output: Vec<u8> = Vec::new();
+    write_multipart(&mut output, b"AaB03x", &nodes).unwrap();
+    assert_eq!(body, output);
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let options = ReadOptions { in_memory_only: true, ..ReadOptions::default() };
+    let parsed = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    assert_eq!(parsed, nodes);
+
+    assert!(matches!(multipart_to_vec(b"AaB03x ", &nodes), Err(Error::InvalidBoundary)));
+}
+
+#[test]
+fn test_reserialize() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    // A well-formed body comes back byte for byte
+    let input = b"--AaB03x\r\n\
+                  content-disposition: form-data;name=\"text\"\r\n\
+                  X-Trace: one\r\n\
+                  X-Trace: two\r\n\
+                  Content-Type: text/plain;charset=iso-8859-1\r\n\
+                  \r\n\
+                  caf\xe9\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  \r\n\
+                  file contents\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"nested\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  inner\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+    assert!(matches!(nodes[1], Node::File(_)));
+    let output = reserialize(&nodes, b"AaB03x").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output), String::from_utf8_lossy(input));
+    assert_eq!(output, &input[..]);
+
+    // Otherwise it is normalized, but parses back to the same parts
+    let input = b"preamble\r\n\
+                  --AaB03x\r\n\
+                  X-Trace: one\r\n\
+                  Content-Disposition: form-data; name=\"text\"\r\n\
+                  X-Trace:   two  \r\n\
+                  \r\n\
+                  text\r\n\
+                  --AaB03x \t\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  \r\n\
+                  file contents\r\n\
+                  --AaB03x--\r\n\
+                  epilogue";
+    let options = ReadOptions { in_memory_only: true, ..ReadOptions::default() };
+    let nodes = read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap();
+    let output = reserialize(&nodes, b"AaB03x").unwrap();
+    assert_eq!(String::from_utf8_lossy(&output),
+               "--AaB03x\r\n\
+                X-Trace: one\r\n\
+                X-Trace: two\r\n\
+                Content-Disposition: form-data; name=\"text\"\r\n\
+                \r\n\
+                text\r\n\
+                --AaB03x\r\n\
+                Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                \r\n\
+                file contents\r\n\
+                --AaB03x--");
+    let reparsed = read_multipart_body_with_options(&mut &output[..], &headers, &options)
+        .unwrap();
+    assert_eq!(reparsed, nodes);
+    assert_eq!(reserialize(&reparsed, b"AaB03x").unwrap(), output);
+}
+
+#[test]
+fn test_write_multipart_buffered() {
+    // Records how much had been written at each flush, failing the flush if asked to
+    struct FlushCounter {
+        written: Vec<u8>,
+        flushes: Vec<usize>,
+        fail: bool,
+    }
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.written.write(buf)
+        }
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            if self.fail {
+                return Err(::std::io::Error::other("connection reset"));
+            }
+            self.flushes.push(self.written.len());
+            Ok(())
+        }
+    }
+
+    let mut builder = MultipartBuilder::new();
+    for i in 0..10 {
+        builder = builder.add_bytes(&format!("field{}", i), "data.bin",
+                                    "application/octet-stream".parse().unwrap(),
+                                    vec![b'x'; 1000]);
+    }
+    let nodes = builder.build();
+
+    let mut writer = FlushCounter { written: Vec::new(), flushes: Vec::new(), fail: false };
+    let count = write_multipart_buffered(&mut writer, b"AaB03x", &nodes, 4096).unwrap();
+    assert_eq!(count, writer.written.len());
+    let mut expected: Vec<u8> = Vec::new();
+    write_multipart(&mut expected, b"AaB03x", &nodes).unwrap();
+    assert_eq!(writer.written, expected);
+
+    // Roughly every 4096 bytes, and always at the end
+    assert!(writer.flushes.len() >= count / 4096);
+    assert!(writer.flushes.len() <= count / 4096 + 1);
+    assert_eq!(writer.flushes.last(), Some(&count));
+    for pair in writer.flushes.windows(2) {
+        assert!(pair[1] - pair[0] >= 4096 || pair[1] == count);
+    }
+
+    // A short body is flushed only at the end
+    let mut writer = FlushCounter { written: Vec::new(), flushes: Vec::new(), fail: false };
+    let count = write_multipart_buffered(&mut writer, b"AaB03x", &[], 4096).unwrap();
+    assert_eq!(writer.flushes, vec![count]);
+
+    let mut writer = FlushCounter { written: Vec::new(), flushes: Vec::new(), fail: true };
+    match write_multipart_buffered(&mut writer, b"AaB03x", &nodes, 4096) {
+        Err(Error::Io(ref e)) => assert_eq!(e.to_string(), "connection reset"),
+        other => panic!("expected Io, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn test_read_multipart_gzipped() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 Content-Type: text/plain\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x--";
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(body).unwrap();
+    let gzipped = gzip.finish().unwrap();
+
+    let nodes = read_multipart_gzipped(&mut &gzipped[..], b"AaB03x").unwrap();
+    assert_eq!(nodes.len(), 2);
+    assert!(matches!(nodes[0], Node::Part(ref part) if part.body == b"value"));
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.read_to_vec().unwrap(), b"file contents");
+        },
+        _ => panic!("2nd node of wrong type"),
+    }
+
+    // Read a byte at a time, the result is the same
+    let nodes = read_multipart_gzipped(&mut OneByteReader(&gzipped), b"AaB03x").unwrap();
+    assert_eq!(nodes.len(), 2);
+
+    // An uncompressed body is not accepted
+    assert!(matches!(read_multipart_gzipped(&mut &body[..], b"AaB03x"), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_into_bytes_and_path() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  \r\n\
+                  file contents\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+    let mut nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+
+    let filepart = match nodes.pop() {
+        Some(Node::File(filepart)) => filepart,
+        other => panic!("expected a file part, got {:?}", other),
+    };
+    let part = match nodes.pop() {
+        Some(Node::Part(part)) => part,
+        other => panic!("expected a part, got {:?}", other),
+    };
+
+    let pointer = part.body.as_ptr();
+    let bytes = part.into_bytes();
+    assert_eq!(bytes, b"value");
+    assert_eq!(bytes.as_ptr(), pointer);
+
+    // The file outlives the part
+    let path = filepart.into_path();
+    assert_eq!(::std::fs::read(&path).unwrap(), b"file contents");
+    ::std::fs::remove_file(&path).unwrap();
+    ::std::fs::remove_dir(path.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_dashed_boundary() {
+    // The extra dashes before the first delimiter and within the parts must not put the
+    // delimiter out of line
+    let boundary = b"----WebKitFormBoundary7MA4YWxkTrZu0gW";
+    let input = b"-------\r\n\
+                  -------WebKitFormBoundary7MA4YWxkTrZu0gW\r\n\
+                  Content-Disposition: form-data; name=\"dashes\"\r\n\
+                  \r\n\
+                  ---\r\n----WebKitFormBoundary7MA4YWxkTrZu0g-\r\n--\r\n\
+                  ------WebKitFormBoundary7MA4YWxkTrZu0gW\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  ----\r\n\
+                  ------WebKitFormBoundary7MA4YWxkTrZu0gW--\r\n";
+    let first = b"---\r\n----WebKitFormBoundary7MA4YWxkTrZu0g-\r\n--";
+    let check = |nodes: &[Node]| {
+        assert_eq!(nodes.len(), 2);
+        match nodes[0] {
+            Node::Part(ref part) => assert_eq!(part.body, &first[..]),
+            _ => panic!("1st node of wrong type"),
+        }
+        match nodes[1] {
+            Node::File(ref filepart) => assert_eq!(filepart.read_to_vec().unwrap(), b"----"),
+            _ => panic!("2nd node of wrong type"),
+        }
+    };
+
+    let content_type = format!("multipart/form-data; boundary={}",
+                               ::std::str::from_utf8(boundary).unwrap());
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![content_type.clone().into_bytes()]);
+    check(&read_multipart_body(&mut &input[..], &headers, false, false).unwrap());
+    check(&read_multipart_body(&mut OneByteReader(input), &headers, false, false).unwrap());
+    let options = ReadOptions::with_buffer_capacity(3);
+    check(&read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap());
+
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        check(&runtime.block_on(read_multipart_body_async(&input[..], boundary, false)).unwrap());
+    }
+
+    let borrowed = parse_multipart_borrowed(&content_type, input).unwrap();
+    assert!(matches!(borrowed[0], BorrowedNode::Part(ref part) if part.body == &first[..]));
+    assert!(matches!(borrowed[1], BorrowedNode::Part(ref part) if part.body == b"----"));
+
+    let mut parts = MultipartParts::new(OneByteReader(input), boundary);
+    let mut bodies: Vec<Vec<u8>> = Vec::new();
+    while let Some(part) = parts.next_part() {
+        let mut body = Vec::new();
+        part.unwrap().read_to_end(&mut body).unwrap();
+        bodies.push(body);
+    }
+    assert_eq!(bodies, vec![first.to_vec(), b"----".to_vec()]);
+
+    let mut pusher = Pusher::new(boundary, Limits::default());
+    let mut data: Vec<u8> = Vec::new();
+    for chunk in input.chunks(1) {
+        for event in pusher.push(chunk).unwrap() {
+            match event {
+                Event::PartData(more) => data.extend(more),
+                Event::PartEnd => data.push(b'|'),
+                _ => {},
+            }
+        }
+    }
+    pusher.finish().unwrap();
+    assert_eq!(data, [&first[..], b"|----|"].concat());
+
+    let mut finder = BoundaryFinder::new(boundary);
+    let mut matches: Vec<BoundaryMatch> = Vec::new();
+    for chunk in input.chunks(1) {
+        matches.extend(finder.push(chunk));
+    }
+    matches.extend(finder.finish());
+    assert_eq!(matches, vec![
+        BoundaryMatch { position: 10, closing: false },
+        BoundaryMatch { position: 148, closing: false },
+        BoundaryMatch { position: 288, closing: true },
+    ]);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_async_cancellation() {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+
+    // Hands over the body a few bytes at a time, setting `cancel` once `cancel_at` bytes
+    // have gone
+    struct CancellingReader {
+        data: Vec<u8>,
+        pos: usize,
+        cancel_at: usize,
+        cancel: Arc<AtomicBool>,
+    }
+    impl tokio::io::AsyncRead for CancellingReader {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>,
+                     buf: &mut tokio::io::ReadBuf<'_>) -> Poll<::std::io::Result<()>> {
+            if self.pos >= self.cancel_at {
+                self.cancel.store(true, Ordering::SeqCst);
+            }
+            let end = self.data.len().min(self.pos + 16).min(self.pos + buf.remaining());
+            buf.put_slice(&self.data[self.pos..end]);
+            self.pos = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Unique, so that the files this writes can be told from those of other tests
+    let marker = format!("cancelled upload {}", TextNonce::sized_urlsafe(32).unwrap());
+    let body = format!("--AaB03x\r\n\
+                        Content-Disposition: form-data; name=\"first\"; filename=\"a.txt\"\r\n\
+                        \r\n\
+                        {marker}\r\n\
+                        --AaB03x\r\n\
+                        Content-Disposition: form-data; name=\"second\"; filename=\"b.txt\"\r\n\
+                        \r\n\
+                        {marker} {filler}\r\n\
+                        --AaB03x--", marker = marker, filler = "x".repeat(1000)).into_bytes();
+    let cancel_at = find_bytes(&body, b"xxxxxxxxxx").unwrap() + 100;
+
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let reader = CancellingReader { data: body.clone(), pos: 0, cancel_at, cancel: cancel.clone() };
+    let result = runtime.block_on(
+        read_multipart_body_async_cancellable(reader, b"AaB03x", false, cancel));
+    match result {
+        Err(Error::Io(ref e)) => assert_eq!(e.kind(), ::std::io::ErrorKind::Interrupted),
+        other => panic!("expected an interrupted Io error, got {:?}", other),
+    }
+
+    // Neither the complete file part nor the partial one is left behind
+    let prefix = FilePartConfig::default().prefix;
+    for entry in ::std::fs::read_dir(::std::env::temp_dir()).unwrap() {
+        let entry = entry.unwrap();
+        if ! entry.file_name().to_string_lossy().starts_with(&prefix) {
+            continue;
+        }
+        for file in ::std::fs::read_dir(entry.path()).into_iter().flatten().flatten() {
+            let contents = ::std::fs::read(file.path()).unwrap_or_default();
+            assert!(find_bytes(&contents, marker.as_bytes()).is_none(),
+                    "{} was left behind", file.path().display());
+        }
+    }
+
+    // Without the flag being set, the same body parses
+    let cancel = Arc::new(AtomicBool::new(false));
+    let reader = CancellingReader { data: body, pos: 0, cancel_at: usize::MAX,
+                                    cancel: cancel.clone() };
+    let nodes = runtime.block_on(
+        read_multipart_body_async_cancellable(reader, b"AaB03x", false, cancel)).unwrap();
+    assert_eq!(nodes.len(), 2);
+}
+
+#[test]
+fn test_read_multipart_body_with_meta() {
+    let input = b"preamble\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"album\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.txt\"\r\n\
+                  \r\n\
+                  sand\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"encoded\"\r\n\
+                  Content-Transfer-Encoding: base64\r\n\
+                  \r\n\
+                  aGVsbG8=\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+    let options = ReadOptions {
+        decode_transfer_encoding: true,
+        buffer_capacity: Some(7),
+        ..ReadOptions::default()
+    };
+    let (nodes, meta) = read_multipart_body_with_meta(&mut &input[..], &headers, &options)
+        .unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(meta.len(), 4);
+
+    let body = |m: &PartMeta| &input[m.body_offset..m.body_offset + m.body_len];
+    let headers = |m: &PartMeta| &input[m.headers_offset..m.body_offset];
+    assert_eq!(meta.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+    assert_eq!(meta[0].headers_offset, 20);
+    assert_eq!(headers(&meta[0]), &b"Content-Disposition: form-data; name=\"title\"\r\n\r\n"[..]);
+    assert_eq!(body(&meta[0]), b"Holiday");
+
+    // The nested multipart comes before its part
+    assert!(headers(&meta[1]).starts_with(b"Content-Disposition: form-data; name=\"album\""));
+    assert_eq!(body(&meta[1]),
+               &b"--BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.txt\"\r\n\
+                  \r\n\
+                  sand\r\n\
+                  --BbC04y--"[..]);
+    assert!(headers(&meta[2]).starts_with(b"Content-Disposition: file;"));
+    assert_eq!(body(&meta[2]), b"sand");
+
+    // The body as received, before decoding
+    assert_eq!(body(&meta[3]), b"aGVsbG8=");
+    assert!(matches!(nodes[2], Node::Part(ref part) if part.body == b"hello"));
+}
+
+#[test]
+fn test_transfer_encoding_token_normalized() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upper\"\r\n\
+                  Content-Transfer-Encoding: BASE64\r\n\
+                  \r\n\
+                  aGVsbG8=\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"mixed\"\r\n\
+                  content-transfer-encoding: Quoted-Printable\r\n\
+                  \r\n\
+                  caf=C3=A9\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"padded\"\r\n\
+                  Content-Transfer-Encoding: \t Base64 \t\r\n\
+                  \r\n\
+                  d29ybGQ=\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+    let nodes = read_multipart_body(&mut &input[..], &headers, false, true).unwrap();
+    let bodies: Vec<&[u8]> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => &part.body[..],
+        _ => panic!("node of wrong type"),
+    }).collect();
+    assert_eq!(bodies, vec![&b"hello"[..], "caf\u{e9}".as_bytes(), b"world"]);
+
+    // However the value was stored in the headers
+    for value in [&b" base64 "[..], b"\tBase64", b"bAsE64\t "].iter() {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Transfer-Encoding", vec![value.to_vec()]);
+        let decoded = super::transfer_encoding::decode(&headers, b"aGVsbG8=".to_vec(), false);
+        assert_eq!(decoded.unwrap(), b"hello", "{:?}", String::from_utf8_lossy(value));
+    }
+}
+
+#[test]
+fn test_empty_multipart() {
+    let mut headers = Headers::new();
+    headers.set(ContentType(mime!(Multipart/FormData; ("boundary")=("AaB03x"))));
+    for input in [&b"--AaB03x--"[..], b"--AaB03x--\r\n", b"preamble\r\n--AaB03x--\r\n"].iter() {
+        let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+        assert!(nodes.is_empty());
+        let nodes = read_multipart_body(&mut OneByteReader(input), &headers, false, false)
+            .unwrap();
+        assert!(nodes.is_empty());
+        let with_headers = [&b"Content-Type: multipart/mixed; boundary=AaB03x\r\n\r\n"[..],
+                            input].concat();
+        let multipart = read_multipart_full(&mut &with_headers[..], &ReadOptions::default())
+            .unwrap();
+        assert!(multipart.nodes.is_empty());
+        assert!(multipart.epilogue.is_empty());
+
+        assert!(parse_multipart_borrowed("multipart/mixed; boundary=AaB03x", input)
+                .unwrap().is_empty());
+        assert!(MultipartParts::new(&input[..], b"AaB03x").next_part().is_none());
+        assert_eq!(validate_multipart(&input[..], b"AaB03x").unwrap().parts, 0);
+        let mut pusher = Pusher::new(b"AaB03x", Limits::default());
+        assert_eq!(pusher.push(input).unwrap(), vec![Event::Done]);
+        pusher.finish().unwrap();
+        #[cfg(feature = "tokio")]
+        {
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            assert!(runtime.block_on(read_multipart_body_async(*input, b"AaB03x", false))
+                    .unwrap().is_empty());
+        }
+    }
+
+    // An empty nested multipart
+    let input = b"--AaB03x\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--\r\n";
+    let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert!(matches!(nodes[0], Node::Multipart((_, ref subnodes)) if subnodes.is_empty()));
+}
+
+#[cfg(feature = "sha2")]
+#[test]
+fn test_hash_file_parts() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"file\"; filename=\"abc.txt\"\r\n\
+                 \r\n\
+                 abc\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"text\"\r\n\
+                 \r\n\
+                 hello world\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"short\"\r\n\
+                 \r\n\
+                 hi\r\n\
+                 --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    let hello = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    // A file part, and a part which spills to a file, are hashed; one kept in memory is not
+    let options = ReadOptions {
+        hash_file_parts: true,
+        spill_threshold: Some(4),
+        tee_file_parts: Some(1024),
+        ..ReadOptions::default()
+    };
+    let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    assert_eq!(nodes.len(), 3);
+    match nodes[0] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.hash.as_deref(), Some(abc));
+            assert_eq!(filepart.bytes.as_deref(), Some(&b"abc"[..]));
+        },
+        _ => panic!("node of wrong type"),
+    }
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.hash.as_deref(), Some(hello));
+            assert_eq!(filepart.read_to_vec().unwrap(), b"hello world");
+        },
+        _ => panic!("node of wrong type"),
+    }
+    match nodes[2] {
+        Node::Part(ref part) => assert_eq!(part.body, b"hi"),
+        _ => panic!("node of wrong type"),
+    }
+
+    // Through a one-byte reader the digest is the same
+    let nodes = read_multipart_body_with_options(&mut OneByteReader(&body[..]), &headers,
+                                                 &options).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => assert_eq!(filepart.hash.as_deref(), Some(abc)),
+        _ => panic!("node of wrong type"),
+    }
+
+    // Without the option nothing is hashed
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => assert_eq!(filepart.hash, None),
+        _ => panic!("node of wrong type"),
+    }
+}
+
+#[test]
+fn test_boundary_within_part_body() {
+    // Each of these holds the boundary, or most of the delimiter, without being one
+    let near_misses: &[&[u8]] = &[b"x--AaB03x", b"\r\n--AaB03", b"\n--AaB03x", b"\r--AaB03x",
+                                  b"\r\n-AaB03x", b"--AaB03x--", b"\r\r\n--AaB0"];
+    let mut content: Vec<u8> = Vec::new();
+    for i in 0..200 {
+        content.extend(near_misses[i % near_misses.len()]);
+        content.extend(format!(" {} ", i).bytes());
+    }
+    let mut input: Vec<u8> = Vec::new();
+    input.extend(b"--AaB03x\r\n\
+                   Content-Disposition: form-data; name=\"text\"\r\n\
+                   \r\n");
+    input.extend(&content);
+    input.extend(b"\r\n--AaB03x\r\n\
+                   Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                   \r\n");
+    input.extend(&content);
+    input.extend(b"\r\n--AaB03x--\r\n");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    let check = |nodes: &[Node]| {
+        assert_eq!(nodes.len(), 2);
+        match nodes[0] {
+            Node::Part(ref part) => assert!(part.body == content),
+            _ => panic!("1st node of wrong type"),
+        }
+        match nodes[1] {
+            Node::File(ref filepart) => assert!(filepart.read_to_vec().unwrap() == content),
+            _ => panic!("2nd node of wrong type"),
+        }
+    };
+
+    // Wherever the buffer happens to end, near misses straddling it included
+    for capacity in 1..=80 {
+        let options = ReadOptions::with_buffer_capacity(capacity);
+        check(&read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap());
+    }
+    check(&read_multipart_body(&mut &input[..], &headers, false, false).unwrap());
+    check(&read_multipart_body(&mut OneByteReader(&input), &headers, false, false).unwrap());
+
+    let borrowed = parse_multipart_borrowed("multipart/form-data; boundary=AaB03x", &input)
+        .unwrap();
+    assert_eq!(borrowed.len(), 2);
+    assert!(matches!(borrowed[0], BorrowedNode::Part(ref part) if part.body == &content[..]));
+    assert!(matches!(borrowed[1], BorrowedNode::Part(ref part) if part.body == &content[..]));
+}
+
+#[test]
+fn test_read_calls_buffered() {
+    // A reader which counts the calls made to it, each of which would be a syscall on an
+    // unbuffered socket or file
+    struct CountingReader<'a> {
+        inner: &'a [u8],
+        calls: usize,
+    }
+    impl<'a> Read for CountingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> ::std::io::Result<usize> {
+            self.calls += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    let mut body: Vec<u8> = Vec::new();
+    for i in 0..50 {
+        body.extend(format!("--AaB03x\r\n\
+                             Content-Disposition: form-data; name=\"field{}\"\r\n\
+                             \r\n\
+                             {}\r\n", i, "x".repeat(i * 40)).bytes());
+    }
+    body.extend(b"--AaB03x--\r\n");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    // With the smallest buffer, each read is no larger than the delimiter searched for
+    let mut reader = CountingReader { inner: &body, calls: 0 };
+    let options = ReadOptions::with_buffer_capacity(1);
+    let expected = read_multipart_body_with_options(&mut reader, &headers, &options).unwrap();
+    assert_eq!(expected.len(), 50);
+    assert!(reader.calls > body.len() / 20);
+
+    // By default the stream is read 4096 bytes at a time, with the same result
+    let mut reader = CountingReader { inner: &body, calls: 0 };
+    let nodes = read_multipart_body(&mut reader, &headers, false, false).unwrap();
+    assert_eq!(nodes, expected);
+    let full_reads = body.len() / 4096;
+    assert!(reader.calls <= full_reads + 2,
+            "{} calls for {} bytes", reader.calls, body.len());
+
+    // So a `BufReader` in front of the stream saves no further calls
+    let mut reader = CountingReader { inner: &body, calls: 0 };
+    let nodes = read_multipart_body(&mut ::std::io::BufReader::new(&mut reader), &headers,
+                                    false, false).unwrap();
+    assert_eq!(nodes, expected);
+    assert!(reader.calls <= full_reads + 2);
+
+    // And a larger buffer makes fewer
+    let mut reader = CountingReader { inner: &body, calls: 0 };
+    let options = ReadOptions::with_buffer_capacity(1 << 20);
+    let nodes = read_multipart_body_with_options(&mut reader, &headers, &options).unwrap();
+    assert_eq!(nodes, expected);
+    assert!(reader.calls <= 2);
+}
+
+#[test]
+fn test_empty_part_bodies() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"comment\"\r\n\
+                  \r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"empty.txt\"\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"after\"\r\n\
+                  \r\n\
+                  x\r\n\
+                  --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let check = |nodes: &[Node]| {
+        assert_eq!(nodes.len(), 3);
+        match nodes[0] {
+            Node::Part(ref part) => assert!(part.body.is_empty()),
+            _ => panic!("1st node of wrong type"),
+        }
+        match nodes[1] {
+            Node::File(ref filepart) => {
+                assert_eq!(filepart.size, Some(0));
+                assert_eq!(::std::fs::metadata(&filepart.path).unwrap().len(), 0);
+            },
+            _ => panic!("2nd node of wrong type"),
+        }
+        match nodes[2] {
+            Node::Part(ref part) => assert_eq!(part.body, b"x"),
+            _ => panic!("3rd node of wrong type"),
+        }
+    };
+    check(&read_multipart_body(&mut &input[..], &headers, false, false).unwrap());
+    check(&read_multipart_body(&mut OneByteReader(input), &headers, false, false).unwrap());
+    let options = ReadOptions { spill_threshold: Some(1), ..ReadOptions::default() };
+    check(&read_multipart_body_with_options(&mut &input[..], &headers, &options).unwrap());
+
+    #[cfg(feature = "tokio")]
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        check(&runtime.block_on(read_multipart_body_async(&input[..], b"AaB03x", false)).unwrap());
+    }
+
+    let borrowed = parse_multipart_borrowed("multipart/form-data; boundary=AaB03x", input)
+        .unwrap();
+    assert_eq!(borrowed.len(), 3);
+    assert!(matches!(borrowed[0], BorrowedNode::Part(ref part) if part.body.is_empty()));
+    assert!(matches!(borrowed[1], BorrowedNode::Part(ref part) if part.body.is_empty()));
+
+    // An empty form field's value is the empty string
+    let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+    let fields = text_fields(&nodes).unwrap();
+    assert_eq!(fields.get("comment").map(String::as_str), Some(""));
+}
+
+#[test]
+fn test_filename_length_and_normalization() {
+    // A 5000 character name is cut to 255 bytes, keeping its extension
+    let long = format!("{}.txt", "a".repeat(4996));
+    let name = options::sanitize_filename(&long).unwrap();
+    assert_eq!(name.len(), 255);
+    assert!(name.starts_with("aaaa") && name.ends_with("a.txt"));
+
+    // Multi-byte characters are not split, nor parted from their combining marks
+    let long = "\u{e9}".repeat(2500);
+    let name = options::sanitize_filename(&long).unwrap();
+    assert_eq!(name, "\u{e9}".repeat(127));
+    let long = format!("{}.{}", "e\u{301}\u{302}".repeat(1000), "x".repeat(40));
+    let name = options::sanitize_filename(&long).unwrap();
+    assert!(name.len() <= 255);
+    assert!(name.chars().last().is_some_and(|c| c == '\u{302}'));
+
+    // Decomposed characters are composed, as NFC
+    assert_eq!(options::sanitize_filename("re\u{301}sume\u{301}.pdf").as_deref(),
+               Some("r\u{e9}sum\u{e9}.pdf"));
+    assert_eq!(options::sanitize_filename("r\u{e9}sum\u{e9}.pdf").as_deref(),
+               Some("r\u{e9}sum\u{e9}.pdf"));
+    assert_eq!(options::sanitize_filename("a\u{1b}[31mb\u{7f}.txt").as_deref(),
+               Some("a[31mb.txt"));
+
+    let mut input: Vec<u8> = Vec::new();
+    input.extend(b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                   \r\n\
+                   --AaB03x\r\n");
+    input.extend(format!("Content-Disposition: form-data; name=\"upload\"; \
+                          filename=\"{}.txt\"\r\n\r\n", "e\u{301}".repeat(2500)).bytes());
+    input.extend(b"contents\r\n--AaB03x--");
+    let target = tempfile::tempdir().unwrap();
+    let options = ReadOptions {
+        file_parts: FilePartConfig {
+            directory: Some(target.path().to_owned()),
+            preserve_filename: true,
+            ..FilePartConfig::default()
+        },
+        ..ReadOptions::default()
+    };
+    let nodes = read_multipart_with_options(&mut &input[..], &options).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => {
+            let name = filepart.path.file_name().unwrap().to_str().unwrap();
+            assert_eq!(name, format!("{}.txt", "\u{e9}".repeat(125)));
+            assert_eq!(filepart.read_to_vec().unwrap(), b"contents");
+        },
+        _ => panic!("node of wrong type"),
+    }
+}
+
+#[test]
+fn test_not_multipart_stream_untouched() {
+    let body = br#"{"name": "value", "list": [1, 2, 3]}"#;
+    type Check = fn(&Error) -> bool;
+    let cases: &[(Option<&[u8]>, Check)] = &[
+        (Some(b"application/json"), |e| matches!(*e, Error::NotMultipart)),
+        (None, |e| matches!(*e, Error::NoRequestContentType)),
+        (Some(b"multipart/form-data"), |e| matches!(*e, Error::BoundaryNotSpecified)),
+    ];
+    for &(content_type, expected) in cases {
+        let mut headers = Headers::new();
+        if let Some(content_type) = content_type {
+            headers.set_raw("Content-Type", vec![content_type.to_vec()]);
+        }
+
+        let mut stream = &body[..];
+        assert!(expected(&read_multipart_body(&mut stream, &headers, false, false).unwrap_err()));
+        assert_eq!(stream.len(), body.len());
+
+        let options = ReadOptions::with_buffer_capacity(1 << 20);
+        let err = read_multipart_body_with_options(&mut stream, &headers, &options).unwrap_err();
+        assert!(expected(&err));
+        let (nodes, err) = read_multipart_body_partial(&mut stream, &headers, &options);
+        assert!(nodes.is_empty() && expected(&err.unwrap()));
+        assert!(expected(&read_multipart_body_with_meta(&mut stream, &headers, &options)
+                         .unwrap_err()));
+        assert!(MultipartParts::from_headers(&mut stream, &headers).is_err());
+        assert!(expected(&Pusher::from_headers(&headers, Limits::default()).unwrap_err()));
+        assert_eq!(stream.len(), body.len());
+    }
+}
+
+#[test]
+fn test_disposition_backslashes() {
+    let disposition = |value: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Disposition", vec![value.as_bytes().to_vec()]);
+        disposition::ContentDisposition::from_headers(&headers).unwrap()
+    };
+
+    // Properly escaped quotes and backslashes are unescaped
+    let cd = disposition(r#"form-data; name="say \"hi\""; filename="back\\slash \"q\".txt""#);
+    assert_eq!(cd.name.as_deref(), Some(r#"say "hi""#));
+    assert_eq!(cd.filename.as_deref(), Some(r#"back\slash "q".txt"#));
+    assert_eq!(cd.params["filename"], r#"back\slash "q".txt"#);
+    let cd = disposition(r#"attachment; filename="C:\\Users\\me\\file.txt""#);
+    assert_eq!(cd.filename.as_deref(), Some(r"C:\Users\me\file.txt"));
+
+    // A raw Windows path keeps its backslashes as they were sent
+    let cd = disposition(r#"form-data; name="upload"; filename="C:\Users\me\file.txt""#);
+    assert_eq!(cd.filename.as_deref(), Some(r"C:\Users\me\file.txt"));
+    assert_eq!(options::sanitize_filename(cd.filename.as_deref().unwrap()).as_deref(),
+               Some("file.txt"));
+
+    // Unquoted values are taken as they are
+    let cd = disposition(r"form-data; name=a\\b");
+    assert_eq!(cd.name.as_deref(), Some(r"a\\b"));
+
+    // A name written with escapes parses back to itself
+    let nodes = MultipartBuilder::new().add_text(r#"a "quoted" \name"#, "x").build();
+    let mut output: Vec<u8> = Vec::new();
+    write_multipart(&mut output, b"AaB03x", &nodes).unwrap();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let parsed = read_multipart_body(&mut &output[..], &headers, false, false).unwrap();
+    match parsed[0] {
+        Node::Part(ref part) => {
+            assert_eq!(part.content_disposition().unwrap().name.as_deref(),
+                       Some(r#"a "quoted" \name"#));
+        },
+        _ => panic!("node of wrong type"),
+    }
+}
+
+#[test]
+fn test_read_first_part() {
+    let mut input: Vec<u8> = Vec::new();
+    input.extend(b"--AaB03x\r\n\
+                   Content-Disposition: form-data; name=\"signature\"\r\n\
+                   \r\n\
+                   t=1492774577,v1=5257a869\r\n\
+                   --AaB03x\r\n\
+                   Content-Disposition: form-data; name=\"second\"\r\n\
+                   \r\n\
+                   second\r\n\
+                   --AaB03x\r\n\
+                   Content-Disposition: form-data; name=\"third\"; filename=\"third.bin\"\r\n\
+                   \r\n");
+    // The third part is never closed, so the body as a whole does not parse
+    input.extend(vec![b'z'; 10_000]);
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    assert!(matches!(read_multipart_body(&mut &input[..], &headers, false, false),
+                     Err(Error::Truncated { .. })));
+
+    let mut stream = &input[..];
+    match read_first_part(&mut stream, b"AaB03x").unwrap() {
+        Some(Node::Part(ref part)) => {
+            assert_eq!(part.name().as_deref(), Some("signature"));
+            assert_eq!(part.body, b"t=1492774577,v1=5257a869");
+        },
+        other => panic!("wrong node: {:?}", other),
+    }
+    // No more was read than the parser buffers
+    assert_eq!(stream.len(), input.len() - 4096);
+
+    assert!(read_first_part(&mut OneByteReader(&input), b"AaB03x").unwrap().is_some());
+    assert!(read_first_part(&mut &b"--AaB03x--\r\n"[..], b"AaB03x").unwrap().is_none());
+    assert!(matches!(read_first_part(&mut &b"no delimiter"[..], b"AaB03x"),
+                     Err(Error::EofBeforeFirstBoundary)));
+}
+
+#[test]
+fn test_content_type_whitespace() {
+    let input = b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"text\"\r\n\
+                  Content-Type:  text/plain; charset=iso-8859-1 \t\r\n\
+                  \r\n\
+                  caf\xe9\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"nested\"\r\n\
+                  Content-Type: \tmultipart/mixed; boundary=BbC04y \r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Type: text/plain\r\n\
+                  \r\n\
+                  inner\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    for value in &[&b" multipart/form-data; boundary=AaB03x "[..],
+                   b"\tmultipart/form-data; boundary=\"AaB03x\"\t",
+                   b"  multipart/form-data; boundary=AaB03x"] {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![value.to_vec()]);
+        assert_eq!(get_multipart_boundary_from_headers(&headers).unwrap(), b"AaB03x");
+        let nodes = read_multipart_body(&mut &input[..], &headers, false, false).unwrap();
+        assert_eq!(nodes.len(), 2);
+        match nodes[0] {
+            Node::Part(ref part) => {
+                assert_eq!(part.content_type(), Some(mime!(Text/Plain; Charset=("iso-8859-1"))));
+                assert_eq!(part.text().unwrap(), "café");
+            },
+            _ => panic!("1st node of wrong type"),
+        }
+        match nodes[1] {
+            Node::Multipart((_, ref subnodes)) => assert_eq!(subnodes.len(), 1),
+            _ => panic!("2nd node of wrong type"),
+        }
+    }
+
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b" application/json ".to_vec()]);
+    assert!(matches!(get_multipart_boundary_from_headers(&headers), Err(Error::NotMultipart)));
+}
+
+#[cfg(feature = "flate2")]
+#[test]
+fn test_content_encoding() {
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    let text = b"Some text which has been compressed, and compressed text.";
+    let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+    gzip.write_all(text).unwrap();
+    let gzipped = gzip.finish().unwrap();
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+    zlib.write_all(text).unwrap();
+    let deflated = zlib.finish().unwrap();
+
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(b"--AaB03x\r\nContent-Type: text/plain\r\nContent-Encoding: gzip\r\n\r\n");
+    body.extend(&gzipped);
+    body.extend(b"\r\n--AaB03x\r\nContent-Type: text/plain\r\nContent-Encoding: deflate\r\n\r\n");
+    body.extend(&deflated);
+    body.extend(b"\r\n--AaB03x\r\nContent-Type: text/plain\r\n\r\nplain\r\n--AaB03x--");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=AaB03x".to_vec()]);
+
+    let options = ReadOptions { decode_content_encoding: true, ..ReadOptions::default() };
+    let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    let bodies: Vec<&[u8]> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => &part.body[..],
+        _ => panic!("node of wrong type"),
+    }).collect();
+    assert_eq!(bodies, vec![&text[..], &text[..], &b"plain"[..]]);
+
+    // Left alone unless asked for
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    match nodes[0] {
+        Node::Part(ref part) => assert_eq!(part.body, gzipped),
+        _ => panic!("node of wrong type"),
+    }
+
+    let corrupt = b"--AaB03x\r\nContent-Encoding: gzip\r\n\r\nnot gzip\r\n--AaB03x--";
+    match read_multipart_body_with_options(&mut &corrupt[..], &headers, &options) {
+        Err(Error::Decoding(_)) => {},
+        other => panic!("expected a decoding error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_strict_crlf() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let options = ReadOptions { strict_crlf: true, lenient: true, ..ReadOptions::default() };
+
+    let good = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 a\nb\rc\r\n\
+                 --AaB03x \t\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x--";
+    let nodes = read_multipart_body_with_options(&mut &good[..], &headers, &options).unwrap();
+    assert_eq!(nodes.len(), 2);
+
+    let bad: Vec<&[u8]> = vec![
+        // Bare LF line terminators throughout
+        b"--AaB03x\n\
+          Content-Disposition: form-data; name=\"field\"\n\
+          \n\
+          value\n\
+          --AaB03x--",
+        // A bare LF after a later delimiter
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"one\"\r\n\
+          \r\n\
+          1\r\n\
+          --AaB03x\n\
+          Content-Disposition: form-data; name=\"two\"\r\n\
+          \r\n\
+          2\r\n\
+          --AaB03x--",
+        // A bare CR after a delimiter
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"one\"\r\n\
+          \r\n\
+          1\r\n\
+          --AaB03x\rContent-Disposition: form-data; name=\"two\"\r\n\
+          \r\n\
+          2\r\n\
+          --AaB03x--",
+        // A delimiter after a bare LF within a part
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"field\"\r\n\
+          \r\n\
+          value\n\
+          --AaB03x\r\n\
+          smuggled\r\n\
+          --AaB03x--",
+        // A delimiter after a bare CR within a file part
+        b"--AaB03x\r\n\
+          Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+          \r\n\
+          file contents\r\
+          --AaB03x\r\n\
+          --AaB03x--",
+    ];
+    for body in &bad {
+        match read_multipart_body_with_options(&mut &body[..], &headers, &options) {
+            Err(Error::NoCrLfAfterBoundary) => {},
+            other => panic!("expected NoCrLfAfterBoundary for {:?}, got {:?}",
+                            String::from_utf8_lossy(body), other),
+        }
+    }
+
+    // The check is made as the body is streamed, wherever the buffer ends and whether the
+    // part is kept in memory, spilled or written to a file, and leaves no file behind
+    let dir = tempfile::tempdir().unwrap();
+    for &capacity in &[1, 3, 7, 4096] {
+        for &spill_threshold in &[None, Some(4)] {
+            let mut options = ReadOptions::with_buffer_capacity(capacity);
+            options.strict_crlf = true;
+            options.spill_threshold = spill_threshold;
+            options.file_parts.directory = Some(dir.path().to_owned());
+            for body in &bad[3..] {
+                assert!(matches!(read_multipart_body_with_options(&mut &body[..], &headers,
+                                                                  &options),
+                                 Err(Error::NoCrLfAfterBoundary)));
+            }
+            let nodes = read_multipart_body_with_options(&mut &good[..], &headers, &options)
+                .unwrap();
+            assert_eq!(nodes.len(), 2);
+        }
+    }
+    assert_eq!(::std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_read_multipart_body_with_progress() {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"upload\"; filename=\"big.bin\"\r\n\
+                  \r\n");
+    body.extend(vec![b'x'; 50_000]);
+    body.extend(b"\r\n--AaB03x--");
+
+    let mut reports: Vec<u64> = Vec::new();
+    let nodes = read_multipart_body_with_progress(&mut &body[..], b"AaB03x", false,
+                                                  |read| reports.push(read))
+        .unwrap();
+    assert_eq!(nodes.len(), 1);
+    assert!(reports.len() > 1 && reports.len() < 100, "{} reports", reports.len());
+    assert!(reports.windows(2).all(|w| w[0] < w[1]));
+    assert_eq!(*reports.last().unwrap(), body.len() as u64);
+}
+
+#[test]
+fn test_boundary_param_order() {
+    let boundary = |content_type: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        get_multipart_boundary_from_headers(&headers)
+    };
+
+    for content_type in &["multipart/form-data; boundary=xyz; charset=utf-8; x=y",
+                          "multipart/form-data; charset=utf-8; boundary=xyz; x=y",
+                          "multipart/form-data; charset=utf-8; x=y; boundary=xyz",
+                          "multipart/form-data;charset=utf-8;BOUNDARY=xyz",
+                          "multipart/form-data; Boundary = xyz ;charset=utf-8",
+                          "multipart/form-data; x=\"boundary=abc\"; boundary=xyz"] {
+        assert_eq!(boundary(content_type).unwrap(), b"xyz", "{}", content_type);
+    }
+    for content_type in &["multipart/form-data", "multipart/form-data; charset=utf-8",
+                          "multipart/form-data; x=\"boundary=abc\""] {
+        match boundary(content_type) {
+            Err(Error::BoundaryNotSpecified) => {},
+            other => panic!("expected BoundaryNotSpecified for {}, got {:?}", content_type, other),
+        }
+    }
+}
+
+#[test]
+fn test_tee_file_parts() {
+    let mut body: Vec<u8> = Vec::new();
+    body.extend(b"--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"small\"; filename=\"small.txt\"\r\n\
+                  \r\n\
+                  file contents\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"large\"; filename=\"large.bin\"\r\n\
+                  \r\n");
+    body.extend(vec![b'x'; 10_000]);
+    body.extend(b"\r\n--AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"spilled\"\r\n\
+                  \r\n\
+                  a field too long to keep\r\n\
+                  --AaB03x--");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    let options = ReadOptions {
+        tee_file_parts: Some(1024),
+        spill_threshold: Some(8),
+        ..ReadOptions::default()
+    };
+    let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.bytes.as_deref(), Some(&b"file contents"[..]));
+            assert_eq!(filepart.read_to_vec().unwrap(), b"file contents");
+        },
+        _ => panic!("node of wrong type"),
+    }
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.bytes, None);
+            assert_eq!(filepart.read_to_vec().unwrap(), vec![b'x'; 10_000]);
+        },
+        _ => panic!("node of wrong type"),
+    }
+    match nodes[2] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.bytes.as_deref(), Some(&b"a field too long to keep"[..]));
+            assert_eq!(filepart.read_to_vec().unwrap(), b"a field too long to keep");
+        },
+        _ => panic!("node of wrong type"),
+    }
+
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    match nodes[0] {
+        Node::File(ref filepart) => assert_eq!(filepart.bytes, None),
+        _ => panic!("node of wrong type"),
+    }
+}
+
+#[test]
+fn test_summarize() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"title\"\r\n\
+                 \r\n\
+                 Holiday\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"photo\"; filename=\"beach.jpg\"\r\n\
+                 Content-Type: image/jpeg\r\n\
+                 \r\n\
+                 not really a jpeg\r\n\
+                 --AaB03x--";
+    let nodes = parse_multipart_bytes("multipart/form-data; boundary=AaB03x", body, false)
+        .unwrap();
+    let summary = summarize(&nodes);
+    assert_eq!(summary, MultipartSummary { parts: vec![
+        PartSummary {
+            name: Some("title".to_owned()),
+            size: 7,
+            ..PartSummary::default()
+        },
+        PartSummary {
+            name: Some("photo".to_owned()),
+            filename: Some("beach.jpg".to_owned()),
+            content_type: Some("image/jpeg".to_owned()),
+            size: 17,
+            file: true,
+            parts: vec![],
+        },
+    ]});
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "parts": [
+                {
+                    "name": "title",
+                    "filename": null,
+                    "content_type": null,
+                    "size": 7,
+                    "file": false,
+                    "parts": [],
+                },
+                {
+                    "name": "photo",
+                    "filename": "beach.jpg",
+                    "content_type": "image/jpeg",
+                    "size": 17,
+                    "file": true,
+                    "parts": [],
+                },
+            ],
+        }));
+        let back: MultipartSummary = serde_json::from_value(json).unwrap();
+        assert_eq!(back, summary);
+    }
+}
+
+#[test]
+fn test_empty_boundary() {
+    let body = b"--\r\n\r\nvalue\r\n----";
+    for content_type in &["multipart/form-data; boundary=", "multipart/form-data; boundary=\"\"",
+                          "multipart/form-data; boundary=\"  \"; charset=utf-8",
+                          "multipart/form-data; boundary=;"] {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+        match get_multipart_boundary(&headers) {
+            Err(Error::BoundaryNotSpecified) => {},
+            other => panic!("expected BoundaryNotSpecified for {}, got {:?}", content_type, other),
+        }
+        match read_multipart_body(&mut &body[..], &headers, false, false) {
+            Err(Error::BoundaryNotSpecified) => {},
+            other => panic!("expected BoundaryNotSpecified for {}, got {:?}", content_type, other),
+        }
+    }
+}
+
+#[test]
+fn test_fuzz_parse() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"upload\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file contents\r\n\
+                 --AaB03x--";
+    let nodes = fuzz_parse("multipart/form-data; boundary=AaB03x", body).unwrap();
+    match nodes[0] {
+        Node::Part(ref part) => assert_eq!(part.body, b"file contents"),
+        _ => panic!("file parts should be kept in memory"),
+    }
+
+    let long_line = format!("--AaB03x\r\nX-Long: {}\r\n\r\nvalue\r\n--AaB03x--",
+                            "x".repeat(100_000));
+    let long_boundary = format!("multipart/mixed; boundary={}", "b".repeat(10_000));
+    let cases: Vec<(&str, &[u8])> = vec![
+        ("multipart/form-data; boundary=", b"--\r\n\r\nvalue\r\n----"),
+        ("multipart/form-data; boundary=\"\"", b"----"),
+        ("multipart/form-data; boundary=AaB03x", b""),
+        ("multipart/form-data; boundary=AaB03x", b"--AaB03x"),
+        ("multipart/form-data; boundary=AaB03x", b"--AaB03x\r"),
+        ("multipart/form-data; boundary=AaB03x", b"--AaB03x\r\nContent-Type: text/pl"),
+        ("multipart/form-data; boundary=AaB03x",
+         b"--AaB03x\r\nContent-Type\r\n\r\nx\r\n--AaB03x--"),
+        ("multipart/form-data; boundary=AaB03x", b"--AaB03x\r\n\r\n\r\n--AaB03x-"),
+        ("multipart/form-data; boundary=AaB03x", long_line.as_bytes()),
+        ("multipart/mixed; boundary=AaB03x",
+         b"--AaB03x\r\nContent-Type: multipart/mixed\r\n\r\n--AaB03x--"),
+        ("multipart/mixed; boundary=AaB03x",
+         b"--AaB03x\r\nContent-Transfer-Encoding: base64\r\n\r\n!!!\r\n--AaB03x--"),
+        (&long_boundary, b"--bbb"),
+        ("text/plain", b"--AaB03x--"),
+        ("not a mime type", b""),
+        ("", b""),
+    ];
+    for (content_type, body) in cases {
+        assert!(fuzz_parse(content_type, body).is_err(), "{:?} {:?}", content_type,
+                String::from_utf8_lossy(&body[..body.len().min(64)]));
+    }
+}
+
+#[test]
+fn test_disposition_type() {
+    let part = |disposition: Option<&str>| {
+        let mut headers = Headers::new();
+        if let Some(disposition) = disposition {
+            headers.set_raw("Content-Disposition", vec![disposition.as_bytes().to_vec()]);
+        }
+        Part { headers, body: Vec::new() }
+    };
+    let cases = vec![
+        (Some("form-data; name=\"field\""), Some(crate::DispositionType::FormData)),
+        (Some("Form-Data; name=\"field\""), Some(crate::DispositionType::FormData)),
+        (Some("attachment; filename=\"a.txt\""), Some(crate::DispositionType::Attachment)),
+        (Some("inline"), Some(crate::DispositionType::Inline)),
+        (Some("X-Custom; a=b"), Some(crate::DispositionType::Other("x-custom".to_owned()))),
+        (None, None),
+    ];
+    for (disposition, expected) in cases {
+        assert_eq!(part(disposition).disposition_type(), expected, "{:?}", disposition);
+    }
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![b"attachment".to_vec()]);
+    let filepart = FilePart::new(headers, &tempdir.path().join("file"));
+    assert_eq!(filepart.disposition_type(), Some(crate::DispositionType::Attachment));
+}
+
+#[test]
+fn test_buffer_capacity() {
+    let mut body: Vec<u8> = Vec::new();
+    for i in 0..200 {
+        let content = format!("value {} {}", i, "x".repeat(i * 7 % 300));
+        body.extend(b"--AaB03x\r\n");
+        body.extend(format!("Content-Disposition: form-data; name=\"field{}\"\r\n", i).bytes());
+        if i % 2 == 0 {
+            body.extend(format!("Content-Length: {}\r\n", content.len()).bytes());
+        }
+        if i % 5 == 0 {
+            // A Content-Length which is wrong is only a hint, and must not alter the parse
+            body.extend(b"Content-Length: 99999999999\r\n");
+        }
+        body.extend(b"\r\n");
+        body.extend(content.bytes());
+        body.extend(b"\r\n");
+    }
+    body.extend(b"--AaB03x\r\nContent-Type: multipart/mixed; boundary=BbC04y\r\n\r\n\
+                  --BbC04y\r\nContent-Type: text/plain\r\n\r\nnested\r\n--BbC04y--\r\n\
+                  --AaB03x--\r\n");
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    let expected = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    assert_eq!(expected.len(), 201);
+    for capacity in [0, 1, 2, 3, 7, 64, 4096, 1 << 20] {
+        let options = ReadOptions::with_buffer_capacity(capacity);
+        let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options)
+            .map_err(|e| format!("capacity {}: {}", capacity, e)).unwrap();
+        assert_eq!(nodes, expected, "capacity {}", capacity);
+    }
+}
+
+#[test]
+fn test_persist_all() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"field\"\r\n\
+                  \r\n\
+                  value\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"one\"; filename=\"photo.jpg\"\r\n\
+                  \r\n\
+                  first\r\n\
+                  --AaB03x\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: attachment; filename=\"photo.jpg\"\r\n\
+                  \r\n\
+                  second\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let options = ReadOptions {
+        file_parts: FilePartConfig { preserve_filename: true, ..FilePartConfig::default() },
+        ..ReadOptions::default()
+    };
+
+    // Both files are moved, the second under a new name as the first took its own
+    let target = tempfile::tempdir().unwrap();
+    let mut nodes = read_multipart_with_options(&mut &input[..], &options).unwrap();
+    let paths = persist_all(&mut nodes, target.path()).unwrap();
+    assert_eq!(paths, vec![target.path().join("photo.jpg"), target.path().join("photo-1.jpg")]);
+    let fileparts: Vec<&FilePart> = leaves(&nodes).filter_map(|(_, leaf)| match leaf {
+        Leaf::File(filepart) => Some(filepart),
+        Leaf::Part(_) => None,
+    }).collect();
+    assert_eq!(fileparts.iter().map(|f| f.path.clone()).collect::<Vec<_>>(), paths);
+    assert!(fileparts.iter().all(|f| ! f.delete_on_drop()));
+    drop(nodes);
+    assert_eq!(::std::fs::read(&paths[0]).unwrap(), b"first");
+    assert_eq!(::std::fs::read(&paths[1]).unwrap(), b"second");
+
+    // If a file cannot be moved, the files moved before it are put back
+    let target = tempfile::tempdir().unwrap();
+    let mut nodes = read_multipart_with_options(&mut &input[..], &options).unwrap();
+    let original: Vec<PathBuf> = leaves(&nodes).filter_map(|(_, leaf)| match leaf {
+        Leaf::File(filepart) => Some(filepart.path.clone()),
+        Leaf::Part(_) => None,
+    }).collect();
+    ::std::fs::remove_file(&original[1]).unwrap();
+    assert!(persist_all(&mut nodes, target.path()).is_err());
+    assert_eq!(::std::fs::read(&original[0]).unwrap(), b"first");
+    assert_eq!(::std::fs::read_dir(target.path()).unwrap().count(), 0);
+    match nodes[1] {
+        Node::File(ref filepart) => {
+            assert_eq!(filepart.path, original[0]);
+            assert!(filepart.delete_on_drop());
+        },
+        ref other => panic!("expected a file part, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_read_multipart_body_partial() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"first\"\r\n\
+                 \r\n\
+                 one\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"second\"\r\n\
+                 \r\n\
+                 two\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"third\"\r\n\
+                 \r\n\
+                 three\r\n\
+                 --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let options = ReadOptions::default();
+
+    // Truncated within the third part
+    let cut = find_bytes(body, b"thr").unwrap();
+    let (nodes, error) = read_multipart_body_partial(&mut &body[..cut], &headers, &options);
+    assert_eq!(nodes.len(), 2);
+    let names: Vec<Option<String>> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => part.name(),
+        ref other => panic!("expected a part, got {:?}", other),
+    }).collect();
+    assert_eq!(names, vec![Some("first".to_owned()), Some("second".to_owned())]);
+    match error {
+        Some(Error::Truncated { bytes_read: 0, .. }) => {},
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+
+    // Complete
+    let (nodes, error) = read_multipart_body_partial(&mut &body[..], &headers, &options);
+    assert_eq!(nodes.len(), 3);
+    assert!(error.is_none());
+}
+
+#[test]
+fn test_duplicate_content_type() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 Content-Type: text/plain\r\n\
+                 Content-Type: application/json\r\n\
+                 \r\n\
+                 {}\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"same\"\r\n\
+                 Content-Type: text/plain; charset=utf-8\r\n\
+                 Content-Type: text/plain;charset=utf-8\r\n\
+                 \r\n\
+                 text\r\n\
+                 --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    // The first is used
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    let types: Vec<Option<Mime>> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => part.content_type(),
+        ref other => panic!("expected a part, got {:?}", other),
+    }).collect();
+    assert_eq!(types, vec![Some(mime!(Text/Plain)), Some(mime!(Text/Plain; Charset=Utf8))]);
+    let mut parts = MultipartParts::from_headers(&body[..], &headers).unwrap();
+    let streamed = parts.next_part().unwrap().unwrap().headers;
+    assert_eq!(streamed.get::<ContentType>(), Some(&ContentType(mime!(Text/Plain))));
+
+    // Or, if they differ, the part is rejected
+    let options = ReadOptions { reject_conflicting_content_type: true, ..ReadOptions::default() };
+    match read_multipart_body_with_options(&mut &body[..], &headers, &options) {
+        Err(Error::Decoding(message)) => assert!(message.contains("application/json")),
+        other => panic!("expected a decoding error, got {:?}", other),
+    }
+    let same = &body[find_bytes(body, b"--AaB03x\r\nContent-Disposition: form-data; name=\"same\"")
+        .unwrap()..];
+    let nodes = read_multipart_body_with_options(&mut &same[..], &headers, &options).unwrap();
+    assert_eq!(nodes.len(), 1);
+}
+
+#[test]
+fn test_part_reader() {
+    let body = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"skipped\"\r\n\
+                 \r\n\
+                 first part\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"wanted\"\r\n\
+                 \r\n\
+                 second part\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"ignored\"\r\n\
+                 \r\n\
+                 third part --AaB03 almost a delimiter\r\n\
+                 --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+
+    for whole in [true, false] {
+        let stream: Box<dyn Read> = if whole {
+            Box::new(&body[..])
+        } else {
+            Box::new(OneByteReader(&body[..]))
+        };
+        let mut reader = PartReader::from_headers(stream, &headers).unwrap();
+        let mut names: Vec<Option<String>> = Vec::new();
+        let mut wanted: Vec<u8> = Vec::new();
+        while let Some(part_headers) = reader.next_headers() {
+            let name = super::get_content_disposition_name(&part_headers.unwrap());
+            match name.as_deref() {
+                Some("skipped") => assert_eq!(reader.skip().unwrap(), 10),
+                Some("wanted") => { reader.read_to_end(&mut wanted).unwrap(); },
+                _ => {},
+            }
+            names.push(name);
+        }
+        assert_eq!(names, vec![Some("skipped".to_owned()), Some("wanted".to_owned()),
+                               Some("ignored".to_owned())]);
+        assert_eq!(wanted, b"second part");
+        assert_eq!(reader.skip().unwrap(), 0);
+    }
+
+    // A body ending within a part
+    let mut reader = PartReader::new(&body[..body.len() - 20], b"AaB03x");
+    while let Some(part_headers) = reader.next_headers() {
+        part_headers.unwrap();
+        match reader.skip() {
+            Ok(_) => {},
+            Err(Error::Truncated { .. }) => return,
+            Err(e) => panic!("expected Truncated, got {:?}", e),
+        }
+    }
+    panic!("expected the last part to be truncated");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_reserved_filename() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition",
+                    vec![b"form-data; name=\"file\"; filename=\"NUL.txt\"".to_vec()]);
+    let config = FilePartConfig { preserve_filename: true, ..FilePartConfig::default() };
+    let filepart = FilePart::create_with_config(headers, &config).unwrap();
+    assert_eq!(filepart.path.file_name().unwrap(), "_NUL.txt");
+    ::std::fs::write(&filepart.path, b"data").unwrap();
+    assert_eq!(filepart.read_to_vec().unwrap(), b"data");
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_long_path() {
+    let target = tempfile::tempdir().unwrap();
+    let mut directory = target.path().to_owned();
+    while directory.as_os_str().len() < 300 {
+        directory.push("a-rather-long-directory-name");
+    }
+    ::std::fs::create_dir_all(options::long_path(directory.clone())).unwrap();
+    let config = FilePartConfig { directory: Some(directory), ..FilePartConfig::default() };
+    let filepart = FilePart::create_with_config(Headers::new(), &config).unwrap();
+    assert!(filepart.path.to_string_lossy().starts_with(r"\\?\"));
+    ::std::fs::write(&filepart.path, b"data").unwrap();
+    assert_eq!(filepart.read_to_vec().unwrap(), b"data");
+}
+
+#[test]
+fn test_parse_multipart_borrowed() {
+    let body = b"preamble\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\
+                 \r\n\
+                 file content\r\n\
+                 --AaB03x\r\n\
+                 Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                 \r\n\
+                 --BbC04y\r\n\
+                 Content-Type: text/plain\r\n\
+                 \r\n\
+                 nested\r\n\
+                 --BbC04y--\r\n\
+                 --AaB03x--\r\n";
+    let content_type = "multipart/form-data; boundary=AaB03x";
+    let nodes = parse_multipart_borrowed(content_type, body).unwrap();
+
+    // The same as the streaming parser, keeping everything in memory
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+    let options = ReadOptions { in_memory_only: true, ..ReadOptions::default() };
+    let expected = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    assert_eq!(nodes.iter().map(|n| n.to_node()).collect::<Vec<_>>(), expected);
+
+    // Every body is a slice of the original buffer
+    fn check(nodes: &[BorrowedNode], buffer: &[u8], count: &mut usize) {
+        let range = buffer.as_ptr() as usize..buffer.as_ptr() as usize + buffer.len();
+        for node in nodes {
+            match *node {
+                BorrowedNode::Part(ref part) => {
+                    let start = part.body.as_ptr() as usize;
+                    assert!(range.contains(&start) && start + part.body.len() <= range.end);
+                    let offset = start - range.start;
+                    assert_eq!(&buffer[offset..offset + part.body.len()], part.body);
+                    *count += 1;
+                },
+                BorrowedNode::Multipart((_, ref subnodes)) => check(subnodes, buffer, count),
+            }
+        }
+    }
+    let mut count = 0;
+    check(&nodes, body, &mut count);
+    assert_eq!(count, 3);
+
+    assert!(matches!(parse_multipart_borrowed("text/plain", body), Err(Error::NotMultipart)));
+    assert!(matches!(parse_multipart_borrowed(content_type, &body[..body.len() - 16]),
+                     Err(Error::Truncated { .. })));
+}
+
+#[test]
+fn test_header_case_insensitivity() {
+    let body = b"--AaB03x\r\n\
+                 content-disposition: form-data; name=\"lower\"; filename=\"lower.txt\"\r\n\
+                 content-type: text/plain\r\n\
+                 content-transfer-encoding: base64\r\n\
+                 \r\n\
+                 bG93ZXI=\r\n\
+                 --AaB03x\r\n\
+                 CONTENT-DISPOSITION: FORM-DATA; NAME=\"upper\"; FILENAME=\"UPPER.TXT\"\r\n\
+                 CONTENT-TYPE: TEXT/PLAIN\r\n\
+                 CONTENT-TRANSFER-ENCODING: BASE64\r\n\
+                 \r\n\
+                 VVBQRVI=\r\n\
+                 --AaB03x\r\n\
+                 CONTENT-TYPE: MULTIPART/MIXED; BOUNDARY=BbC04y\r\n\
+                 \r\n\
+                 --BbC04y\r\n\
+                 CONTENT-DISPOSITION: ATTACHMENT; FILENAME*=UTF-8''%C3%A9.txt\r\n\
+                 \r\n\
+                 nested\r\n\
+                 --BbC04y--\r\n\
+                 --AaB03x--";
+    let mut headers = Headers::new();
+    headers.set_raw("CONTENT-TYPE", vec![b"MULTIPART/FORM-DATA; BOUNDARY=AaB03x".to_vec()]);
+    let options = ReadOptions {
+        in_memory_only: true,
+        decode_transfer_encoding: true,
+        ..ReadOptions::default()
+    };
+    let nodes = read_multipart_body_with_options(&mut &body[..], &headers, &options).unwrap();
+    assert_eq!(nodes.len(), 3);
+    let expected = [("lower", "lower.txt", b"lower"), ("upper", "UPPER.TXT", b"UPPER")];
+    for (node, &(name, filename, content)) in nodes.iter().zip(expected.iter()) {
+        match *node {
+            Node::Part(ref part) => {
+                assert_eq!(part.name().as_deref(), Some(name));
+                assert_eq!(part.filename().unwrap().as_deref(), Some(filename));
+                assert_eq!(part.disposition_type(), Some(crate::DispositionType::FormData));
+                assert_eq!(part.content_type(), Some(mime!(Text/Plain)));
+                assert_eq!(part.body, content);
+            },
+            ref other => panic!("expected a part, got {:?}", other),
+        }
+    }
+    match nodes[2] {
+        Node::Multipart((_, ref subnodes)) => match subnodes[0] {
+            Node::Part(ref part) => {
+                assert_eq!(part.filename().unwrap().as_deref(), Some("\u{e9}.txt"));
+                assert_eq!(part.disposition_type(), Some(crate::DispositionType::Attachment));
+            },
+            ref other => panic!("expected a part, got {:?}", other),
+        },
+        ref other => panic!("expected a multipart, got {:?}", other),
+    }
+
+    // File parts are recognised whatever the case of their headers
+    let nodes = read_multipart_body(&mut &body[..], &headers, false, false).unwrap();
+    assert!(matches!(nodes[0], Node::File(_)));
+    assert!(matches!(nodes[1], Node::File(_)));
+}
+
+#[test]
+fn test_missing_closing_boundary() {
+    let complete = b"--AaB03x\r\n\
+                     Content-Disposition: form-data; name=\"first\"\r\n\
+                     \r\n\
+                     one\r\n\
+                     --AaB03x\r\n\
+                     Content-Disposition: form-data; name=\"second\"\r\n\
+                     \r\n\
+                     two\r\n\
+                     --AaB03x";
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let content_type = "multipart/form-data; boundary=AaB03x";
+
+    // Every part is complete, but the final delimiter lacks its `--`
+    for ending in [&b""[..], b"\r", b"\r\n", b"  \r\n", b"\n"] {
+        let body = [&complete[..], ending].concat();
+        let options = ReadOptions::default();
+        let (nodes, error) = read_multipart_body_partial(&mut &body[..], &headers, &options);
+        assert_eq!(nodes.len(), 2, "{:?}", ending);
+        assert!(matches!(error, Some(Error::MissingClosingBoundary)), "{:?}: {:?}", ending, error);
+
+        let mut parts = MultipartParts::new(&body[..], b"AaB03x");
+        let mut count = 0;
+        let error = loop {
+            match parts.next_part() {
+                Some(Ok(_)) => count += 1,
+                Some(Err(e)) => break Some(e),
+                None => break None,
+            }
+        };
+        assert_eq!(count, 2);
+        assert!(matches!(error, Some(Error::MissingClosingBoundary)), "{:?}: {:?}", ending, error);
+
+        assert!(matches!(parse_multipart_borrowed(content_type, &body),
+                         Err(Error::MissingClosingBoundary)), "{:?}", ending);
+    }
+
+    // Which is distinct from a body cut off within a part
+    let truncated = &complete[..complete.len() - 12];
+    assert!(matches!(read_multipart_body(&mut &truncated[..], &headers, false, false),
+                     Err(Error::Truncated { .. })));
+    // or after a delimiter which is followed by something other than a line terminator
+    let garbled = [&complete[..], b"xx"].concat();
+    assert!(matches!(read_multipart_body(&mut &garbled[..], &headers, false, false),
+                     Err(Error::NoCrLfAfterBoundary)));
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_missing_closing_boundary_async() {
+    let body: &[u8] = b"--AaB03x\r\n\
+                        Content-Disposition: form-data; name=\"first\"\r\n\
+                        \r\n\
+                        one\r\n\
+                        --AaB03x\r\n";
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let result = runtime.block_on(read_multipart_body_async(body, b"AaB03x", false));
+    assert!(matches!(result, Err(Error::MissingClosingBoundary)));
+}
+
+#[test]
+fn test_into_fields() {
+    let input = b"Content-Type: multipart/form-data; boundary=AaB03x\r\n\
+                  \r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"title\"\r\n\
+                  \r\n\
+                  Holiday\r\n\
+                  --AaB03x\r\n\
+                  Content-Disposition: form-data; name=\"photos\"\r\n\
+                  Content-Type: multipart/mixed; boundary=BbC04y\r\n\
+                  \r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"beach.jpg\"\r\n\
+                  Content-Type: image/jpeg\r\n\
+                  \r\n\
+                  beach\r\n\
+                  --BbC04y\r\n\
+                  Content-Disposition: file; filename=\"hills.png\"\r\n\
+                  Content-Type: image/png\r\n\
+                  \r\n\
+                  hills\r\n\
+                  --BbC04y--\r\n\
+                  --AaB03x--";
+    let nodes = read_multipart(&mut &input[..], false).unwrap();
+    let fields = into_fields(nodes);
+    assert_eq!(fields.len(), 3);
+
+    let summary: Vec<(Option<&str>, Option<&str>, Option<Mime>)> = fields.iter()
+        .map(|f| (f.name.as_deref(), f.filename.as_deref(), f.content_type.clone()))
+        .collect();
+    assert_eq!(summary, vec![
+        (Some("title"), None, None),
+        (Some("photos"), Some("beach.jpg"), Some(mime!(Image/Jpeg))),
+        (Some("photos"), Some("hills.png"), Some(mime!(Image/Png))),
+    ]);
+    assert_eq!(fields[0].data, FieldData::Bytes(b"Holiday".to_vec()));
+    let contents: Vec<Vec<u8>> = fields[1..].iter().map(|field| match field.data {
+        FieldData::File(ref filepart) => ::std::fs::read(&filepart.path).unwrap(),
+        ref other => panic!("expected a file, got {:?}", other),
+    }).collect();
+    assert_eq!(contents, vec![b"beach".to_vec(), b"hills".to_vec()]);
+    assert!(fields[2].headers.get_raw("Content-Disposition").is_some());
+}
+
+#[test]
+fn test_write_multipart_with_options() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Disposition", vec![b"form-data; name=\"field\"".to_vec()]);
+    let mut nested_headers = Headers::new();
+    nested_headers.set_raw("Content-Type", vec![b"multipart/mixed; boundary=BbC04y".to_vec()]);
+    let nodes = vec![
+        Node::Part(Part { headers: headers.clone(), body: b"value".to_vec() }),
+        Node::Multipart((nested_headers, vec![
+            Node::Part(Part { headers, body: b"nested".to_vec() }),
+        ])),
+    ];
+
+    let options = WriteOptions {
+        line_ending: LineEnding::Lf,
+        preamble: Some(b"This is a multi-part message in MIME format.".to_vec()),
+    };
+    let mut output: Vec<u8> = Vec::new();
+    let count = write_multipart_with_options(&mut output, b"AaB03x", &nodes, &options).unwrap();
+    assert_eq!(count, output.len());
+    let expected = "This is a multi-part message in MIME format.\n\
+                    --AaB03x\n\
+                    Content-Disposition: form-data; name=\"field\"\n\
+                    \n\
+                    value\n\
+                    --AaB03x\n\
+                    Content-Type: multipart/mixed; boundary=BbC04y\n\
+                    \n\
+                    --BbC04y\n\
+                    Content-Disposition: form-data; name=\"field\"\n\
+                    \n\
+                    nested\n\
+                    --BbC04y--\n\
+                    --AaB03x--";
+    assert_eq!(String::from_utf8(output.clone()).unwrap(), expected);
+    assert!(!output.contains(&b'\r'));
+
+    // It parses back into the same nodes
+    let mut content_type = Headers::new();
+    content_type.set_raw("Content-Type", vec![b"multipart/mixed; boundary=AaB03x".to_vec()]);
+    let parsed = read_multipart_body(&mut &output[..], &content_type, false, false).unwrap();
+    assert_eq!(parsed, nodes);
+
+    // The defaults are those of write_multipart()
+    let mut default: Vec<u8> = Vec::new();
+    write_multipart_with_options(&mut default, b"AaB03x", &nodes, &WriteOptions::default())
+        .unwrap();
+    let mut plain: Vec<u8> = Vec::new();
+    write_multipart(&mut plain, b"AaB03x", &nodes).unwrap();
+    assert_eq!(default, plain);
+
+    let options = WriteOptions { preamble: Some(b"--AaB03x".to_vec()), ..WriteOptions::default() };
+    assert!(matches!(write_multipart_with_options(&mut Vec::new(), b"AaB03x", &nodes, &options),
+                     Err(Error::BoundaryCollision)));
+}
+
+#[test]
+fn test_file_part_from_path() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("Report.PDF");
+    ::std::fs::write(&path, b"%PDF-1.4 content").unwrap();
+
+    let filepart = FilePart::from_path("upload", &path).unwrap();
+    assert_eq!(filepart.path, path);
+    assert_eq!(filepart.size, Some(16));
+    assert_eq!(filepart.name().as_deref(), Some("upload"));
+    assert_eq!(filepart.filename().unwrap().as_deref(), Some("Report.PDF"));
+    assert_eq!(filepart.content_type(), Some("application/pdf".parse().unwrap()));
+    assert!(! filepart.delete_on_drop());
+
+    let unknown = tempdir.path().join("data.bin");
+    ::std::fs::write(&unknown, b"").unwrap();
+    let filepart = FilePart::from_path("data", &unknown).unwrap();
+    assert_eq!(filepart.size, Some(0));
+    assert_eq!(filepart.content_type(), Some(mime!(Application/OctetStream)));
+
+    // It can be written straight away
+    let mut output: Vec<u8> = Vec::new();
+    write_multipart(&mut output, b"AaB03x", &[Node::File(filepart)]).unwrap();
+
+    match FilePart::from_path("missing", tempdir.path().join("missing.txt")) {
+        Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::NotFound => {},
+        other => panic!("expected a NotFound error, got {:?}", other),
+    }
+    assert!(matches!(FilePart::from_path("dir", tempdir.path()), Err(Error::Io(_))));
+}
+
+#[test]
+fn test_part_header_errors() {
+    let mut headers = Headers::new();
+    headers.set_raw("Content-Type", vec![b"multipart/form-data; boundary=AaB03x".to_vec()]);
+    let good = b"--AaB03x\r\n\
+                 Content-Disposition: form-data; name=\"field\"\r\n\
+                 Content-Type: text/plain\r\n\
+                 \r\n\
+                 value\r\n\
+                 --AaB03x--";
+
+    // Headers arriving a byte at a time are read in full
+    for lenient in [false, true] {
+        let options = ReadOptions { lenient, ..ReadOptions::default() };
+        let nodes = read_multipart_body_with_options(&mut OneByteReader(&good[..]), &headers,
+                                                     &options).unwrap();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].headers().len(), 2);
+    }
+    let mut parts = MultipartParts::new(OneByteReader(&good[..]), b"AaB03x");
+    assert_eq!(parts.next_part().unwrap().unwrap().headers.len(), 2);
+
+    // A malformed header line is an error as soon as it is found
+    let bad = b"--AaB03x\r\n\
+                Content-Disposition: form-data; name=\"field\"\r\n\
+                Not a header line\r\n\
+                \r\n\
+                value\r\n\
+                --AaB03x--";
+    assert!(matches!(read_multipart_body(&mut OneByteReader(&bad[..]), &headers, false, false),
+                     Err(Error::Httparse(httparse::Error::HeaderName))));
+    let mut parts = MultipartParts::new(&bad[..], b"AaB03x");
+    assert!(matches!(parts.next_part(), Some(Err(Error::Httparse(_)))));
+    assert!(matches!(parse_multipart_borrowed("multipart/form-data; boundary=AaB03x", bad),
+                     Err(Error::Httparse(_))));
+
+    // And a body ending within the headers is reported as such
+    let cut = &good[..30];
+    assert!(matches!(read_multipart_body(&mut OneByteReader(cut), &headers, false, false),
+                     Err(Error::EofInPartHeaders)));
+    let mut parts = MultipartParts::new(cut, b"AaB03x");
+    assert!(matches!(parts.next_part(), Some(Err(Error::EofInPartHeaders))));
+}
+
+#[test]
+fn test_content_range() {
+    let input = b"HTTP/1.1 206 Partial Content\r\n\
+                  Content-Type: multipart/byteranges; boundary=THIS_STRING_SEPARATES\r\n\
+                  \r\n\
+                  --THIS_STRING_SEPARATES\r\n\
+                  Content-Type: application/pdf\r\n\
+                  Content-Range: bytes 500-509/8000\r\n\
+                  \r\n\
+                  0123456789\r\n\
+                  --THIS_STRING_SEPARATES\r\n\
+                  Content-Type: application/pdf\r\n\
+                  Content-Range: bytes 7000-7004/*\r\n\
+                  \r\n\
+                  abcde\r\n\
+                  --THIS_STRING_SEPARATES--\r\n";
+    let nodes = read_multipart_from_http(&mut &input[..]).unwrap();
+    let ranges: Vec<_> = nodes.iter().map(|node| match *node {
+        Node::Part(ref part) => (part.content_range(), part.body.len()),
+        ref other => panic!("expected a part, got {:?}", other),
+    }).collect();
+    assert_eq!(ranges, vec![(Some((500, 509, Some(8000))), 10),
+                            (Some((7000, 7004, None)), 5)]);
+
+    let range = |value: &str| {
+        let mut headers = Headers::new();
+        headers.set_raw("Content-Range", vec![value.as_bytes().to_vec()]);
+        Part { headers, body: Vec::new() }.content_range()
+    };
+    assert_eq!(range("Bytes 0-0/1"), Some((0, 0, Some(1))));
+    for bad in ["bytes 5-4/10", "bytes 0-10/10", "bytes */10", "items 0-1/2", "bytes +1-2/3",
+                "bytes 0-1", ""] {
+        assert_eq!(range(bad), None, "{:?}", bad);
+    }
+    assert_eq!(Part { headers: Headers::new(), body: Vec::new() }.content_range(), None);
+}
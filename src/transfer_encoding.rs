@@ -0,0 +1,91 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Decoding of part bodies according to their `Content-Transfer-Encoding` header
+//! (RFC 2045 section 6).
+
+use std::borrow::Cow;
+
+use base64::Engine;
+use hyper::header::Headers;
+
+/// Decode `body` according to the `Content-Transfer-Encoding` header found in `headers`.
+/// Bodies without the header, or with an encoding that needs no transformation (`7bit`,
+/// `8bit`, `binary`, or one which is not recognised, which RFC 2045 says to treat as
+/// binary) are returned unchanged.  If `validate_7bit` is set, a `7bit` body must contain
+/// only 7-bit bytes.
+pub(crate) fn decode(headers: &Headers, body: Vec<u8>, validate_7bit: bool)
+                     -> Result<Vec<u8>, Cow<'static, str>>
+{
+    let encoding = match headers.get_raw("Content-Transfer-Encoding") {
+        Some(raw) if !raw.is_empty() => String::from_utf8_lossy(&raw[0]).trim().to_lowercase(),
+        _ => return Ok(body),
+    };
+
+    match &*encoding {
+        "base64" => decode_base64(&body),
+        "quoted-printable" => decode_quoted_printable(&body),
+        "7bit" if validate_7bit => match body.iter().position(|&b| b > 0x7f) {
+            Some(at) => Err(format!("8-bit byte 0x{:02x} at offset {} of a 7bit body",
+                                    body[at], at).into()),
+            None => Ok(body),
+        },
+        // "7bit" (unvalidated), "8bit" and "binary" need no decoding, and RFC 2045
+        // section 6.4 says to treat an unrecognised encoding as binary
+        _ => Ok(body),
+    }
+}
+
+fn decode_base64(body: &[u8]) -> Result<Vec<u8>, Cow<'static, str>> {
+    // Encoded lines are limited to 76 characters, so the body is broken up with line
+    // terminators (and possibly other whitespace) which the decoder does not accept.
+    let stripped: Vec<u8> = body.iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .cloned()
+        .collect();
+    base64::engine::general_purpose::STANDARD.decode(&stripped)
+        .map_err(|e| format!("invalid base64: {}", e).into())
+}
+
+fn decode_quoted_printable(body: &[u8]) -> Result<Vec<u8>, Cow<'static, str>> {
+    let mut output: Vec<u8> = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        if body[i] != b'=' {
+            output.push(body[i]);
+            i += 1;
+            continue;
+        }
+
+        // A soft line break is an '=' at the end of an encoded line, possibly followed by
+        // transport padding.
+        let mut j = i + 1;
+        while j < body.len() && (body[j] == b' ' || body[j] == b'\t') {
+            j += 1;
+        }
+        if body[j..].starts_with(b"\r\n") {
+            i = j + 2;
+            continue;
+        }
+        if body[j..].starts_with(b"\n") {
+            i = j + 1;
+            continue;
+        }
+
+        // Otherwise it must be an escaped octet, '=' followed by two hex digits.
+        let octet = body.get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| ::std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match octet {
+            Some(octet) => output.push(octet),
+            None => return Err(format!("invalid quoted-printable escape at byte {}", i).into()),
+        }
+        i += 3;
+    }
+    Ok(output)
+}
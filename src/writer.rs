@@ -0,0 +1,145 @@
+// Copyright 2016-2020 mime-multipart Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Writing a `multipart/*` body one part at a time, for when the parts are not all
+//! known up front.
+
+use std::io::{self, Read, Write};
+
+use hyper::header::{ContentLength, ContentType, Headers};
+use mime::Mime;
+
+use super::{check_headers, generate_boundary, validate_boundary, write_headers, Error};
+
+/// Writes a `multipart/*` body part by part, as the parts become available.
+///
+/// Each call to `write_part()` writes a delimiter, the part's headers and its body; the
+/// closing delimiter is written by `finish()`.  The output is the same as
+/// `write_multipart()` produces for the same parts, except that bodies streamed from a
+/// reader cannot be checked beforehand for the boundary, so use a generated boundary
+/// (`MultipartWriter::generate()`) unless the content is known.
+pub struct MultipartWriter<W: Write> {
+    inner: W,
+    boundary: Vec<u8>,
+    count: usize,
+}
+
+impl<W: Write> MultipartWriter<W> {
+    /// Create a writer into `inner`, using `boundary` (given without the leading `--`).
+    /// Returns `Error::InvalidBoundary` if the boundary is not valid.
+    pub fn new(inner: W, boundary: &[u8]) -> Result<MultipartWriter<W>, Error> {
+        validate_boundary(boundary)?;
+        Ok(MultipartWriter { inner, boundary: boundary.to_vec(), count: 0 })
+    }
+
+    /// Create a writer into `inner`, using a newly generated boundary.
+    pub fn generate(inner: W) -> MultipartWriter<W> {
+        MultipartWriter { inner, boundary: generate_boundary(), count: 0 }
+    }
+
+    /// The boundary in use, for the caller's Content-Type header.
+    pub fn boundary(&self) -> &[u8] {
+        &self.boundary
+    }
+
+    /// The number of bytes written so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Write a part with the given headers, streaming its body from `body`.  Returns the
+    /// number of bytes written for the part, or `Error::InvalidHeaderValue` (with nothing
+    /// written) if a header has a CR or LF in its name or value.
+    pub fn write_part<R: Read>(&mut self, headers: &Headers, body: &mut R)
+                               -> Result<usize, Error>
+    {
+        check_headers(headers)?;
+        let start = self.count;
+        let boundary = self.boundary.clone();
+        self.write(b"--")?;
+        self.write(&boundary)?;
+        self.write(b"\r\n")?;
+        self.count += write_headers(&mut self.inner, headers, b"\r\n")?;
+        self.write(b"\r\n")?;
+        self.count += io::copy(body, &mut self.inner)? as usize;
+        self.write(b"\r\n")?;
+        self.inner.flush()?;
+        Ok(self.count - start)
+    }
+
+    /// Write the closing delimiter, returning the underlying writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        let boundary = self.boundary.clone();
+        self.write(b"--")?;
+        self.write(&boundary)?;
+        self.write(b"--")?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the underlying writer.  Writing to it directly will
+    /// corrupt the body.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.count += buf.len();
+        Ok(())
+    }
+}
+
+/// Writes a `multipart/x-mixed-replace` response body, as used for MJPEG camera streams,
+/// where each part (frame) replaces the one before it.
+///
+/// Every frame is written with its delimiter before it, so that the browser can display
+/// it as soon as it has arrived, and with Content-Type and Content-Length headers.
+pub struct XMixedReplaceWriter<W: Write> {
+    writer: MultipartWriter<W>,
+}
+
+impl<W: Write> XMixedReplaceWriter<W> {
+    /// Create a writer into `inner`, using a newly generated boundary.
+    pub fn new(inner: W) -> XMixedReplaceWriter<W> {
+        XMixedReplaceWriter { writer: MultipartWriter::generate(inner) }
+    }
+
+    /// Create a writer into `inner`, using `boundary` (given without the leading `--`).
+    pub fn with_boundary(inner: W, boundary: &[u8]) -> Result<XMixedReplaceWriter<W>, Error> {
+        Ok(XMixedReplaceWriter { writer: MultipartWriter::new(inner, boundary)? })
+    }
+
+    /// The value for the response's Content-Type header.
+    pub fn content_type(&self) -> String {
+        format!("multipart/x-mixed-replace; boundary={}",
+                String::from_utf8_lossy(self.writer.boundary()))
+    }
+
+    /// Write a frame of the given content type.  Returns the number of bytes written.
+    pub fn push_frame(&mut self, content_type: Mime, bytes: &[u8]) -> Result<usize, Error> {
+        let mut headers = Headers::new();
+        headers.set(ContentType(content_type));
+        headers.set(ContentLength(bytes.len() as u64));
+        self.writer.write_part(&headers, &mut &bytes[..])
+    }
+
+    /// Write the closing delimiter, ending the stream, and return the underlying writer.
+    pub fn finish(self) -> Result<W, Error> {
+        self.writer.finish()
+    }
+
+    /// Get a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+}